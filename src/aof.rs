@@ -0,0 +1,792 @@
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use tokio::sync::{futures::Notified, Notify};
+
+use crate::{
+    config::AofConfig,
+    db::DataValue,
+    resp::{bulk_string::BulkString, frame, rdb::writer, RedisData},
+    state::State,
+};
+
+/// When appended writes are flushed to disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// after every write, before replying
+    Always,
+    /// at most once per second
+    Everysec,
+    /// left to the operating system
+    No,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "always" => Ok(Self::Always),
+            "everysec" => Ok(Self::Everysec),
+            "no" => Ok(Self::No),
+            value => anyhow::bail!("Invalid appendfsync value '{value}'"),
+        }
+    }
+}
+
+impl fmt::Display for FsyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = match self {
+            Self::Always => "always",
+            Self::Everysec => "everysec",
+            Self::No => "no",
+        };
+        f.write_str(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    Base,
+    History,
+    Incr,
+}
+
+impl FileKind {
+    fn as_char(&self) -> char {
+        match self {
+            Self::Base => 'b',
+            Self::History => 'h',
+            Self::Incr => 'i',
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub name: String,
+    pub seq: u64,
+    pub kind: FileKind,
+}
+
+/// The files making up the AOF, in the Redis 7 manifest format:
+/// `file appendonly.aof.1.base.rdb seq 1 type b`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn parse(data: &str) -> anyhow::Result<Self> {
+        let mut entries = Vec::new();
+        for line in data.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            anyhow::ensure!(
+                parts.len() >= 2 && parts.len().is_multiple_of(2),
+                "Invalid AOF manifest line: {line}"
+            );
+            let (mut name, mut seq, mut kind) = (None, None, None);
+            for pair in parts.chunks(2) {
+                match pair[0] {
+                    "file" => name = Some(pair[1].to_owned()),
+                    "seq" => seq = Some(pair[1].parse()?),
+                    "type" => {
+                        kind = Some(match pair[1] {
+                            "b" => FileKind::Base,
+                            "h" => FileKind::History,
+                            "i" => FileKind::Incr,
+                            kind => anyhow::bail!("Unknown AOF file type {kind}"),
+                        })
+                    }
+                    // unknown keys are ignored for forward compatibility
+                    _ => (),
+                }
+            }
+            match (name, seq, kind) {
+                (Some(name), Some(seq), Some(kind)) => {
+                    entries.push(ManifestEntry { name, seq, kind })
+                }
+                _ => anyhow::bail!("Invalid AOF manifest line: {line}"),
+            }
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn base(&self) -> Option<&ManifestEntry> {
+        self.entries.iter().find(|e| e.kind == FileKind::Base)
+    }
+
+    pub fn incrs(&self) -> impl Iterator<Item = &ManifestEntry> {
+        self.entries.iter().filter(|e| e.kind == FileKind::Incr)
+    }
+
+    /// The next sequence number for a file of the given kind
+    fn next_seq(&self, kind: FileKind) -> u64 {
+        self.entries
+            .iter()
+            .filter(|e| e.kind == kind)
+            .map(|e| e.seq)
+            .max()
+            .unwrap_or(0)
+            + 1
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in &self.entries {
+            writeln!(
+                f,
+                "file {} seq {} type {}",
+                entry.name,
+                entry.seq,
+                entry.kind.as_char()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Append-only file persistence, made of a base snapshot and incremental files listing every
+/// write applied since, tracked by a manifest in `appenddirname`
+#[derive(Debug)]
+pub struct Aof {
+    config: AofConfig,
+    /// `appenddirname` inside `dir`
+    dir: PathBuf,
+    manifest: Mutex<Manifest>,
+    /// `appendfsync`, which can be changed at runtime
+    fsync: Mutex<FsyncPolicy>,
+    /// the incremental file writes are appended to, `None` while the AOF is off
+    file: Mutex<Option<File>>,
+    fsync_pending: AtomicBool,
+    last_fsync: Mutex<Instant>,
+    /// the replication offset up to which the writes are on disk
+    fsynced_offset: AtomicUsize,
+    /// notified when `fsynced_offset` advances
+    fsynced: Notify,
+    /// `auto-aof-rewrite-percentage`
+    rewrite_percentage: AtomicU64,
+    /// `auto-aof-rewrite-min-size`
+    rewrite_min_size: AtomicU64,
+    rewrite_in_progress: AtomicBool,
+    last_rewrite_ok: AtomicBool,
+    /// duration of the last rewrite in seconds, -1 before the first one
+    last_rewrite_time: AtomicI64,
+    /// size of all the AOF files
+    current_size: AtomicU64,
+    /// size of all the AOF files after startup or the last rewrite
+    base_size: AtomicU64,
+}
+
+/// Statistics reported by `INFO persistence`
+#[derive(Debug)]
+pub struct RewriteInfo {
+    pub in_progress: bool,
+    pub last_ok: bool,
+    pub last_time: i64,
+    pub current_size: u64,
+    pub base_size: u64,
+}
+
+impl Aof {
+    /// Location of the manifest, used to tell whether the dataset has to be loaded from the AOF
+    pub fn manifest_path(dir: &str, config: &AofConfig) -> PathBuf {
+        Path::new(dir)
+            .join(&config.dirname)
+            .join(format!("{}.manifest", config.filename))
+    }
+
+    /// Loads the AOF into `state`, or creates it from the current dataset when there is none
+    /// yet. Runs before clients are accepted.
+    pub fn open(dir: &str, config: &AofConfig, state: &mut State) -> anyhow::Result<Self> {
+        let aof_dir = Path::new(dir).join(&config.dirname);
+        let manifest_path = Self::manifest_path(dir, config);
+        let mut manifest = if manifest_path.exists() {
+            let manifest = Manifest::parse(&fs::read_to_string(&manifest_path)?)?;
+            load(&aof_dir, &manifest, config.load_truncated, state)?;
+            manifest
+        } else {
+            fs::create_dir_all(&aof_dir)
+                .with_context(|| format!("create AOF directory {aof_dir:?}"))?;
+            let base = format!("{}.1.base.rdb", config.filename);
+            writer::write_file(&aof_dir.join(&base), &state.snapshot())?;
+            Manifest {
+                entries: vec![ManifestEntry {
+                    name: base,
+                    seq: 1,
+                    kind: FileKind::Base,
+                }],
+            }
+        };
+        if manifest.incrs().next().is_none() {
+            let seq = manifest
+                .entries
+                .iter()
+                .map(|e| e.seq)
+                .max()
+                .unwrap_or(0)
+                .max(1);
+            manifest.entries.push(ManifestEntry {
+                name: format!("{}.{seq}.incr.aof", config.filename),
+                seq,
+                kind: FileKind::Incr,
+            });
+        }
+        write_manifest(&manifest_path, &manifest)?;
+        let incr = manifest
+            .incrs()
+            .last()
+            .expect("an incremental file")
+            .name
+            .clone();
+        let file = open_incr(&aof_dir.join(&incr))?;
+        let size = files_size(&aof_dir, &manifest);
+
+        let aof = Self::disabled(config.clone());
+        *aof.manifest.lock().unwrap() = manifest;
+        *aof.file.lock().unwrap() = Some(file);
+        aof.current_size.store(size, Ordering::Relaxed);
+        aof.base_size.store(size, Ordering::Relaxed);
+        Ok(Self {
+            dir: aof_dir,
+            ..aof
+        })
+    }
+
+    /// An AOF that is turned off, keeping its config for `CONFIG GET`
+    pub fn disabled(config: AofConfig) -> Self {
+        Self {
+            dir: PathBuf::new(),
+            manifest: Mutex::new(Manifest::default()),
+            fsync: Mutex::new(config.fsync),
+            file: Mutex::new(None),
+            fsync_pending: AtomicBool::new(false),
+            last_fsync: Mutex::new(Instant::now()),
+            fsynced_offset: AtomicUsize::new(0),
+            fsynced: Notify::new(),
+            rewrite_percentage: AtomicU64::new(config.rewrite_percentage),
+            rewrite_min_size: AtomicU64::new(config.rewrite_min_size),
+            rewrite_in_progress: AtomicBool::new(false),
+            last_rewrite_ok: AtomicBool::new(true),
+            last_rewrite_time: AtomicI64::new(-1),
+            current_size: AtomicU64::new(0),
+            base_size: AtomicU64::new(0),
+            config,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.file.lock().unwrap().is_some()
+    }
+
+    pub fn config(&self) -> &AofConfig {
+        &self.config
+    }
+
+    pub fn fsync_policy(&self) -> FsyncPolicy {
+        *self.fsync.lock().unwrap()
+    }
+
+    pub fn set_fsync_policy(&self, policy: FsyncPolicy) {
+        *self.fsync.lock().unwrap() = policy;
+    }
+
+    pub fn rewrite_percentage(&self) -> u64 {
+        self.rewrite_percentage.load(Ordering::Relaxed)
+    }
+
+    pub fn set_rewrite_percentage(&self, percentage: u64) {
+        self.rewrite_percentage.store(percentage, Ordering::Relaxed);
+    }
+
+    pub fn rewrite_min_size(&self) -> u64 {
+        self.rewrite_min_size.load(Ordering::Relaxed)
+    }
+
+    pub fn set_rewrite_min_size(&self, size: u64) {
+        self.rewrite_min_size.store(size, Ordering::Relaxed);
+    }
+
+    pub fn rewrite_info(&self) -> RewriteInfo {
+        RewriteInfo {
+            in_progress: self.rewrite_in_progress.load(Ordering::Relaxed),
+            last_ok: self.last_rewrite_ok.load(Ordering::Relaxed),
+            last_time: self.last_rewrite_time.load(Ordering::Relaxed),
+            current_size: self.current_size.load(Ordering::Relaxed),
+            base_size: self.base_size.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Whether the AOF grew past `auto-aof-rewrite-min-size` and by `auto-aof-rewrite-percentage`
+    /// since the last rewrite
+    pub fn should_rewrite(&self) -> bool {
+        let percentage = self.rewrite_percentage();
+        if !self.is_enabled() || percentage == 0 || self.rewrite_in_progress.load(Ordering::Relaxed)
+        {
+            return false;
+        }
+        let current = self.current_size.load(Ordering::Relaxed);
+        let base = self.base_size.load(Ordering::Relaxed).max(1);
+        current >= self.rewrite_min_size()
+            && current.saturating_sub(base) * 100 / base >= percentage
+    }
+
+    /// Starts a rewrite by switching appends to a new incremental file, so that a snapshot taken
+    /// under the same command lock plus that file hold every write. Returns the sequence number
+    /// of the base file to write with [`Aof::write_base`].
+    pub fn start_rewrite(&self) -> anyhow::Result<u64> {
+        anyhow::ensure!(
+            self.is_enabled(),
+            "Background append only file rewriting requires appendonly yes"
+        );
+        anyhow::ensure!(
+            self.rewrite_in_progress
+                .compare_exchange(false, true, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok(),
+            "Background append only file rewriting already in progress"
+        );
+        let result = (|| {
+            let mut manifest = self.manifest.lock().unwrap();
+            let seq = manifest.next_seq(FileKind::Incr);
+            let name = format!("{}.{seq}.incr.aof", self.config.filename);
+            let file = open_incr(&self.dir.join(&name))?;
+            // until the rewrite is done the dataset is the old base plus every incremental file
+            let mut updated = manifest.clone();
+            updated.entries.push(ManifestEntry {
+                name,
+                seq,
+                kind: FileKind::Incr,
+            });
+            write_manifest(&self.manifest_file(), &updated)?;
+            *manifest = updated;
+
+            let mut current = self.file.lock().unwrap();
+            if let Some(previous) = current.as_ref() {
+                previous.sync_data()?;
+            }
+            *current = Some(file);
+            anyhow::Ok(manifest.next_seq(FileKind::Base))
+        })();
+        if result.is_err() {
+            self.rewrite_in_progress.store(false, Ordering::Release);
+        }
+        result
+    }
+
+    /// Writes the snapshot taken when the rewrite started as the new base file
+    pub(crate) fn write_base(
+        &self,
+        seq: u64,
+        entries: &[(BulkString, DataValue)],
+    ) -> anyhow::Result<ManifestEntry> {
+        let name = format!("{}.{seq}.base.rdb", self.config.filename);
+        writer::write_file(&self.dir.join(&name), entries)?;
+        Ok(ManifestEntry {
+            name,
+            seq,
+            kind: FileKind::Base,
+        })
+    }
+
+    /// Ends a rewrite. On success the manifest only lists the new base and the incremental file
+    /// opened by [`Aof::start_rewrite`], and the files it replaced are deleted.
+    pub fn finish_rewrite(&self, base: anyhow::Result<ManifestEntry>, started: Instant) {
+        let result = base.and_then(|base| {
+            let mut manifest = self.manifest.lock().unwrap();
+            let incr = manifest
+                .incrs()
+                .last()
+                .cloned()
+                .context("the AOF manifest has no incremental file")?;
+            let updated = Manifest {
+                entries: vec![base, incr],
+            };
+            write_manifest(&self.manifest_file(), &updated)?;
+            let replaced = std::mem::replace(&mut *manifest, updated);
+            for entry in replaced
+                .entries
+                .iter()
+                .filter(|e| !manifest.entries.contains(e))
+            {
+                if let Err(e) = fs::remove_file(self.dir.join(&entry.name)) {
+                    eprintln!("Failed to remove the AOF file {}: {e}", entry.name);
+                }
+            }
+            let size = files_size(&self.dir, &manifest);
+            self.current_size.store(size, Ordering::Relaxed);
+            self.base_size.store(size, Ordering::Relaxed);
+            anyhow::Ok(())
+        });
+        match &result {
+            Ok(()) => println!("Background AOF rewrite terminated with success"),
+            Err(e) => println!("Background AOF rewrite error: {e}"),
+        }
+        self.last_rewrite_ok
+            .store(result.is_ok(), Ordering::Relaxed);
+        self.last_rewrite_time
+            .store(started.elapsed().as_secs() as i64, Ordering::Relaxed);
+        self.rewrite_in_progress.store(false, Ordering::Release);
+    }
+
+    fn manifest_file(&self) -> PathBuf {
+        self.dir.join(format!("{}.manifest", self.config.filename))
+    }
+
+    /// Appends a write in its RESP form. With `appendfsync always` it is on disk on return.
+    /// Appends a write, where `offset` is the replication offset that covers it. Under
+    /// `appendfsync always` the write is on disk when this returns, and so is the offset.
+    pub fn append(&self, request: &[u8], offset: usize) -> std::io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        let Some(file) = file.as_mut() else {
+            return Ok(());
+        };
+        file.write_all(request)?;
+        self.current_size
+            .fetch_add(request.len() as u64, Ordering::Relaxed);
+        match self.fsync_policy() {
+            FsyncPolicy::Always => {
+                file.sync_data()?;
+                self.record_fsynced(offset);
+            }
+            FsyncPolicy::Everysec => self.fsync_pending.store(true, Ordering::Relaxed),
+            FsyncPolicy::No => (),
+        }
+        Ok(())
+    }
+
+    /// Flushes pending writes once a second under `appendfsync everysec`, then records that the
+    /// writes up to the replication `offset`, read before the call, are on disk
+    pub fn cron(&self, offset: usize) {
+        // the OS decides when the writes reach the disk under `appendfsync no`
+        if !self.is_enabled() || self.fsync_policy() == FsyncPolicy::No {
+            return;
+        }
+        if self.fsync_policy() == FsyncPolicy::Everysec
+            && self.fsync_pending.load(Ordering::Relaxed)
+        {
+            let mut last_fsync = self.last_fsync.lock().unwrap();
+            if last_fsync.elapsed() < Duration::from_secs(1) {
+                return;
+            }
+            self.fsync_pending.store(false, Ordering::Relaxed);
+            let file = self.file.lock().unwrap();
+            *last_fsync = Instant::now();
+            if let Err(e) = file.as_ref().map_or(Ok(()), |file| file.sync_data()) {
+                eprintln!("Failed to fsync the AOF: {e}");
+                self.fsync_pending.store(true, Ordering::Relaxed);
+                return;
+            }
+        }
+        // `always` synced every write as it was appended
+        self.record_fsynced(offset);
+    }
+
+    /// Records that the writes up to the replication `offset` are on disk, when every write was
+    /// synced as it was appended
+    pub fn synced_on_append(&self, offset: usize) {
+        if self.is_enabled() && self.fsync_policy() == FsyncPolicy::Always {
+            self.record_fsynced(offset);
+        }
+    }
+
+    fn record_fsynced(&self, offset: usize) {
+        if self.fsynced_offset.fetch_max(offset, Ordering::AcqRel) < offset {
+            self.fsynced.notify_waiters();
+        }
+    }
+
+    /// The replication offset up to which the writes are on disk, 0 while the AOF is off or
+    /// left to the OS
+    pub fn fsynced_offset(&self) -> usize {
+        self.fsynced_offset.load(Ordering::Acquire)
+    }
+
+    /// Resolves when more writes are on disk. Like with `Notify`, the future catches the
+    /// fsyncs from the moment it is created.
+    pub fn fsynced(&self) -> Notified<'_> {
+        self.fsynced.notified()
+    }
+}
+
+fn open_incr(path: &Path) -> anyhow::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("open AOF file {path:?}"))
+}
+
+/// Total size of the files listed in the manifest
+fn files_size(aof_dir: &Path, manifest: &Manifest) -> u64 {
+    manifest
+        .entries
+        .iter()
+        .filter_map(|e| fs::metadata(aof_dir.join(&e.name)).ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Writes the manifest to a temporary file and renames it into place
+pub fn write_manifest(path: &Path, manifest: &Manifest) -> anyhow::Result<()> {
+    let temp_path = path.with_extension("manifest.tmp");
+    let mut file = File::create(&temp_path)?;
+    file.write_all(manifest.to_string().as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// Replays the base and incremental files listed in the manifest
+fn load(
+    aof_dir: &Path,
+    manifest: &Manifest,
+    load_truncated: bool,
+    state: &mut State,
+) -> anyhow::Result<()> {
+    if let Some(base) = manifest.base() {
+        let path = aof_dir.join(&base.name);
+        let data = fs::read(&path).with_context(|| format!("read AOF base {path:?}"))?;
+        if data.starts_with(b"REDIS") {
+            state
+                .load_rdb(data.as_slice())
+                .with_context(|| format!("load AOF base {path:?}"))?;
+        } else {
+            replay(&path, state, false)?;
+        }
+    }
+    let incrs: Vec<_> = manifest.incrs().collect();
+    for (i, incr) in incrs.iter().enumerate() {
+        let path = aof_dir.join(&incr.name);
+        if !path.exists() {
+            continue;
+        }
+        // only the file that was being appended to when the server stopped can be truncated
+        let is_last = i + 1 == incrs.len();
+        let commands = replay(&path, state, load_truncated && is_last)?;
+        println!("Loaded {commands} commands from {}", incr.name);
+    }
+    Ok(())
+}
+
+/// A command of an AOF file, or a committed `MULTI` ... `EXEC` block, as RESP requests
+#[derive(Debug)]
+pub enum AofUnit<'a> {
+    Command(&'a [u8]),
+    Transaction(Vec<&'a [u8]>),
+}
+
+/// How much of an AOF file is readable
+#[derive(Debug)]
+pub struct AofScan {
+    /// length of the prefix made of complete commands and committed transactions
+    pub valid_len: usize,
+    /// number of commands in that prefix, the `MULTI` and `EXEC` markers excluded
+    pub commands: usize,
+    /// what made the file unreadable at `valid_len`, `None` when it is only truncated there
+    pub error: Option<anyhow::Error>,
+}
+
+/// Splits an AOF file into commands and transactions, calling `on_unit` with the offset of each.
+/// Only the RESP framing is checked, so commands this server does not know, like the `SELECT`
+/// a Redis AOF starts with, are valid. Scanning stops at the first malformed command, and a
+/// transaction that was never committed is left out of the valid prefix. Errors of `on_unit`
+/// are returned as is.
+pub fn scan<'a>(
+    data: &'a [u8],
+    mut on_unit: impl FnMut(usize, AofUnit<'a>) -> anyhow::Result<()>,
+) -> anyhow::Result<AofScan> {
+    let mut pos = 0;
+    let mut commands = 0;
+    let mut error = None;
+    // start of the open MULTI block and the commands queued since
+    let mut transaction: Option<(usize, Vec<&[u8]>)> = None;
+    while pos < data.len() {
+        let len = match frame::command_len(&data[pos..]) {
+            Ok(Some(len)) => len,
+            Ok(None) => break,
+            Err(e) => {
+                error = Some(anyhow::anyhow!("Bad file format at offset {pos}: {e}"));
+                break;
+            }
+        };
+        let request = &data[pos..pos + len];
+        let name = match frame::args(request) {
+            Ok(args) if !args.is_empty() => args[0].to_ascii_lowercase(),
+            Ok(_) => {
+                error = Some(anyhow::anyhow!(
+                    "Bad file format at offset {pos}: empty command"
+                ));
+                break;
+            }
+            Err(e) => {
+                error = Some(anyhow::anyhow!("Bad file format at offset {pos}: {e}"));
+                break;
+            }
+        };
+        match (name.as_slice(), &mut transaction) {
+            (b"multi", None) => transaction = Some((pos, Vec::new())),
+            (b"exec", Some(_)) => {
+                let (start, queued) = transaction.take().unwrap();
+                commands += queued.len();
+                on_unit(start, AofUnit::Transaction(queued))?;
+            }
+            (_, Some((_, queued))) => queued.push(request),
+            (_, None) => {
+                commands += 1;
+                on_unit(pos, AofUnit::Command(request))?;
+            }
+        }
+        pos += len;
+    }
+
+    let valid_len = match &transaction {
+        Some((multi_start, _)) => *multi_start,
+        None => pos,
+    };
+    Ok(AofScan {
+        valid_len,
+        commands,
+        error,
+    })
+}
+
+/// Applies every command of an AOF file, returning how many were applied. A truncated tail, or a
+/// transaction that was never committed, is cut off when `allow_truncated` is set.
+pub fn replay(path: &Path, state: &mut State, allow_truncated: bool) -> anyhow::Result<usize> {
+    let data = fs::read(path).with_context(|| format!("read AOF file {path:?}"))?;
+    let scan = scan(&data, |pos, unit| {
+        let context = || format!("replay command in {path:?} at offset {pos}");
+        match unit {
+            AofUnit::Command(request) => {
+                let redis_data = RedisData::parse_bytes(request).with_context(context)?;
+                state.handle_response(&redis_data).with_context(context)?;
+            }
+            AofUnit::Transaction(requests) => {
+                let commands = requests
+                    .into_iter()
+                    .map(RedisData::parse_bytes)
+                    .collect::<anyhow::Result<Vec<_>>>()
+                    .with_context(context)?;
+                state.exec(&commands, &[]);
+            }
+        }
+        Ok(())
+    })?;
+    if let Some(e) = scan.error {
+        anyhow::bail!(
+            "Bad file format reading the append only file {path:?}: {e}. Repair it with \
+             redis-check-aof --fix"
+        );
+    }
+
+    if scan.valid_len < data.len() {
+        anyhow::ensure!(
+            allow_truncated,
+            "Unexpected end of file reading the append only file {path:?}. You can set \
+             aof-load-truncated to yes or repair the file with redis-check-aof"
+        );
+        println!(
+            "!!! Warning: short read while loading the AOF file {path:?}, truncating it to \
+             {} bytes !!!",
+            scan.valid_len
+        );
+        truncate(path, scan.valid_len as u64)?;
+    }
+    Ok(scan.commands)
+}
+
+/// Cuts a damaged AOF file down to its valid prefix
+pub fn truncate(path: &Path, len: u64) -> anyhow::Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .open(path)?
+        .set_len(len)
+        .with_context(|| format!("truncate {path:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_round_trip() {
+        let data = "file appendonly.aof.1.base.rdb seq 1 type b\n\
+                    file appendonly.aof.1.incr.aof seq 1 type i\n\
+                    file appendonly.aof.2.incr.aof seq 2 type i\n";
+        let manifest = Manifest::parse(data).unwrap();
+        assert_eq!(manifest.base().unwrap().name, "appendonly.aof.1.base.rdb");
+        assert_eq!(manifest.incrs().count(), 2);
+        assert_eq!(manifest.to_string(), data);
+        assert!(Manifest::parse("file appendonly.aof seq x type i").is_err());
+    }
+
+    #[test]
+    fn test_replay_truncated() {
+        let path = std::env::temp_dir().join(format!("aof-test-{}.aof", std::process::id()));
+        let set = b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n";
+        let mut data = set.to_vec();
+        data.extend(
+            b"*1\r\n$5\r\nMULTI\r\n*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbaz\r\n*1\r\n$4\r\nEX",
+        );
+        fs::write(&path, &data).unwrap();
+
+        let mut state = State::default();
+        assert!(replay(&path, &mut state, false).is_err());
+        assert_eq!(replay(&path, &mut state, true).unwrap(), 1);
+        assert_eq!(fs::read(&path).unwrap(), set);
+        let get = RedisData::Get(crate::resp::bulk_string::BulkString::encode("foo"));
+        assert_eq!(state.handle_response(&get).unwrap(), "$3\r\nbar\r\n");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let dir = std::env::temp_dir().join(format!("aof-rewrite-test-{}", std::process::id()));
+        let config = AofConfig {
+            enabled: true,
+            ..AofConfig::default()
+        };
+        let mut state = State::default();
+        let aof = Aof::open(dir.to_str().unwrap(), &config, &mut state).unwrap();
+        aof.append(b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n", 0)
+            .unwrap();
+
+        let seq = aof.start_rewrite().unwrap();
+        assert!(aof.start_rewrite().is_err());
+        assert_eq!(aof.manifest.lock().unwrap().incrs().count(), 2);
+        aof.append(b"*3\r\n$3\r\nSET\r\n$3\r\nbaz\r\n$3\r\nqux\r\n", 0)
+            .unwrap();
+        let base = aof.write_base(seq, &state.snapshot());
+        aof.finish_rewrite(base, Instant::now());
+
+        let manifest = Manifest::parse(
+            &fs::read_to_string(Aof::manifest_path(dir.to_str().unwrap(), &config)).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(manifest.base().unwrap().name, "appendonly.aof.2.base.rdb");
+        let incrs: Vec<_> = manifest.incrs().map(|e| e.name.as_str()).collect();
+        assert_eq!(incrs, ["appendonly.aof.2.incr.aof"]);
+        assert!(!dir
+            .join(&config.dirname)
+            .join("appendonly.aof.1.incr.aof")
+            .exists());
+        let info = aof.rewrite_info();
+        assert!(!info.in_progress && info.last_ok);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::VecDeque;
+
+/// The replication backlog: the last bytes of the replication stream, kept so that a replica
+/// that lost its connection can continue from its offset instead of loading a new snapshot
+#[derive(Debug)]
+pub struct Backlog {
+    data: VecDeque<u8>,
+    size: usize,
+    /// replication offset of the first byte held
+    start: usize,
+}
+
+impl Backlog {
+    /// An empty backlog holding at most `size` bytes, continuing from `offset`
+    pub fn new(size: usize, offset: usize) -> Self {
+        Self {
+            data: VecDeque::new(),
+            size,
+            start: offset,
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.data.extend(data);
+        self.trim();
+    }
+
+    /// Replication offset right after the last byte held
+    pub fn end(&self) -> usize {
+        self.start + self.data.len()
+    }
+
+    /// Offset of the first byte held, counting from 1 like `INFO` reports it
+    pub fn first_byte_offset(&self) -> usize {
+        self.start + 1
+    }
+
+    pub fn histlen(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn resize(&mut self, size: usize) {
+        self.size = size;
+        self.trim();
+    }
+
+    /// The bytes following `offset`, if the backlog still holds all of them
+    pub fn since(&self, offset: usize) -> Option<Vec<u8>> {
+        let skip = offset.checked_sub(self.start)?;
+        (offset <= self.end()).then(|| self.data.range(skip..).copied().collect())
+    }
+
+    fn trim(&mut self) {
+        let excess = self.data.len().saturating_sub(self.size);
+        self.data.drain(..excess);
+        self.start += excess;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backlog() {
+        let mut backlog = Backlog::new(8, 100);
+        assert_eq!(backlog.since(100), Some(Vec::new()));
+        backlog.feed(b"abcde");
+        assert_eq!(backlog.end(), 105);
+        assert_eq!(backlog.since(102), Some(b"cde".to_vec()));
+        backlog.feed(b"fghij");
+        assert_eq!(backlog.first_byte_offset(), 103);
+        assert_eq!(backlog.histlen(), 8);
+        assert_eq!(backlog.since(101), None);
+        assert_eq!(backlog.since(106), Some(b"ghij".to_vec()));
+        assert_eq!(backlog.since(111), None);
+        backlog.resize(2);
+        assert_eq!(backlog.since(108), Some(b"ij".to_vec()));
+    }
+}
@@ -0,0 +1,5 @@
+use redis_starter_rust::check;
+
+fn main() -> anyhow::Result<()> {
+    std::process::exit(if check::redis_check_aof()? { 0 } else { 1 });
+}
@@ -0,0 +1,5 @@
+use redis_starter_rust::convert;
+
+fn main() -> anyhow::Result<()> {
+    convert::redis_rdb_convert()
+}
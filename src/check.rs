@@ -0,0 +1,323 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use clap::Parser;
+
+use crate::{
+    aof::{self, FileKind, Manifest},
+    db::{DataType, DataValue},
+    resp::{bulk_string::BulkString, rdb::Rdb},
+};
+
+/// Allocation and bookkeeping bytes added to every string by the memory estimates
+const STRING_OVERHEAD: usize = 16;
+
+/// Bytes added to every key for its entry in the keyspace
+const KEY_OVERHEAD: usize = 48;
+
+#[derive(Debug, Default)]
+pub struct TypeStats {
+    pub keys: u64,
+    /// rough memory used by the keys and their values
+    pub memory: u64,
+}
+
+/// What was read from an RDB file
+#[derive(Debug, Default)]
+pub struct RdbReport {
+    pub version: u32,
+    pub aux: Vec<(String, String)>,
+    /// key counts and memory estimates by type name
+    pub types: BTreeMap<&'static str, TypeStats>,
+    pub expires: u64,
+    pub already_expired: u64,
+    /// offset of the first record that could not be read, and why
+    pub corruption: Option<(u64, anyhow::Error)>,
+}
+
+impl RdbReport {
+    pub fn keys(&self) -> u64 {
+        self.types.values().map(|stats| stats.keys).sum()
+    }
+
+    fn record(&mut self, key: &BulkString, value: &DataValue) {
+        let stats = self.types.entry(value.value.name()).or_default();
+        stats.keys += 1;
+        stats.memory += estimate_memory(key, value);
+        if let Some(expiry) = &value.expiry {
+            self.expires += 1;
+            if expiry.has_expired() {
+                self.already_expired += 1;
+            }
+        }
+    }
+}
+
+impl fmt::Display for RdbReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "RDB version {}", self.version)?;
+        for (key, value) in &self.aux {
+            writeln!(f, "AUX FIELD {key} = '{value}'")?;
+        }
+        writeln!(f, "{:<10} {:>12} {:>16}", "type", "keys", "memory (bytes)")?;
+        for (name, stats) in &self.types {
+            writeln!(f, "{name:<10} {:>12} {:>16}", stats.keys, stats.memory)?;
+        }
+        writeln!(
+            f,
+            "{} keys read, {} expires, {} already expired",
+            self.keys(),
+            self.expires,
+            self.already_expired
+        )
+    }
+}
+
+/// How much of an AOF file is readable
+#[derive(Debug)]
+pub struct AofReport {
+    pub size: u64,
+    pub commands: usize,
+    /// length of the prefix made of complete commands and committed transactions
+    pub valid_len: u64,
+    /// why the file stops being readable at `valid_len`, `None` when it is only truncated
+    pub error: Option<anyhow::Error>,
+}
+
+impl AofReport {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none() && self.valid_len == self.size
+    }
+}
+
+/// An AOF base can be a snapshot in the RDB format or a list of commands
+#[derive(Debug)]
+pub enum FileReport {
+    Rdb(RdbReport),
+    Aof(AofReport),
+}
+
+/// Reads a whole RDB file, collecting statistics until the first corrupt record
+pub fn check_rdb(path: &Path) -> anyhow::Result<RdbReport> {
+    let file = File::open(path).with_context(|| format!("open {path:?}"))?;
+    let mut rdb = Rdb::new(file);
+    let mut report = RdbReport::default();
+    let result = rdb.read_entries(|entry| {
+        report.record(&entry.key, &entry.value);
+        Ok(())
+    });
+    report.version = rdb.version();
+    report.aux = rdb.aux().to_vec();
+    if let Err(e) = result {
+        report.corruption = Some((rdb.record_start(), e));
+    }
+    Ok(report)
+}
+
+/// Parses every command of an AOF file without applying them
+pub fn check_aof(path: &Path) -> anyhow::Result<AofReport> {
+    let data = fs::read(path).with_context(|| format!("read {path:?}"))?;
+    let scan = aof::scan(&data, |_, _| Ok(()))?;
+    Ok(AofReport {
+        size: data.len() as u64,
+        commands: scan.commands,
+        valid_len: scan.valid_len as u64,
+        error: scan.error,
+    })
+}
+
+/// Checks a file of either format, telling them apart by the RDB magic string
+pub fn check_file(path: &Path) -> anyhow::Result<FileReport> {
+    let mut magic = Vec::new();
+    File::open(path)
+        .with_context(|| format!("open {path:?}"))?
+        .take(5)
+        .read_to_end(&mut magic)?;
+    match magic.as_slice() {
+        b"REDIS" => Ok(FileReport::Rdb(check_rdb(path)?)),
+        _ => Ok(FileReport::Aof(check_aof(path)?)),
+    }
+}
+
+/// The files of a multi-part AOF in the order they are loaded
+pub fn manifest_files(path: &Path) -> anyhow::Result<Vec<(PathBuf, FileKind)>> {
+    let data = fs::read_to_string(path).with_context(|| format!("read {path:?}"))?;
+    let manifest = Manifest::parse(&data)?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    Ok(manifest
+        .base()
+        .into_iter()
+        .chain(manifest.incrs())
+        .map(|entry| (dir.join(&entry.name), entry.kind))
+        .collect())
+}
+
+/// Rough size of a key and its value in memory
+fn estimate_memory(key: &BulkString, value: &DataValue) -> u64 {
+    let string = |s: &BulkString| s.data.len() + STRING_OVERHEAD;
+    let value_size: usize = match &value.value {
+        DataType::String(s) => string(s),
+        DataType::List(list) => list.iter().map(string).sum(),
+        DataType::Set(set) => set.iter().map(string).sum(),
+        DataType::ZSet(zset) => zset.keys().map(|member| string(member) + 8).sum(),
+        DataType::Hash(hash) => hash.iter().map(|(f, v)| string(f) + string(v)).sum(),
+        DataType::Stream(entries) => entries
+            .iter()
+            .map(|entry| {
+                string(&entry.id)
+                    + entry
+                        .map
+                        .iter()
+                        .map(|(f, v)| string(f) + string(v))
+                        .sum::<usize>()
+            })
+            .sum(),
+    };
+    (KEY_OVERHEAD + string(key) + value_size) as u64
+}
+
+/// Validates an RDB dump offline, printing what it holds and where it is corrupted
+#[derive(Parser, Debug)]
+#[command(name = "redis-check-rdb", version, about, long_about = None)]
+struct CheckRdbArgs {
+    /// The RDB file to check
+    file: PathBuf,
+}
+
+/// Entry point of `redis-check-rdb`, returning whether the file is valid
+pub fn redis_check_rdb() -> anyhow::Result<bool> {
+    let args = CheckRdbArgs::parse();
+    println!("[offset 0] Checking RDB file {}", args.file.display());
+    let report = check_rdb(&args.file)?;
+    print!("{report}");
+    match &report.corruption {
+        Some((offset, e)) => {
+            println!("--- RDB ERROR DETECTED ---");
+            println!("[offset {offset}] {e:#}");
+            Ok(false)
+        }
+        None => {
+            println!("\\o/ RDB looks OK! \\o/");
+            Ok(true)
+        }
+    }
+}
+
+/// Validates an append only file offline, either a single file or every file listed in a
+/// manifest, and can cut a damaged file down to its last valid command
+#[derive(Parser, Debug)]
+#[command(name = "redis-check-aof", version, about, long_about = None)]
+struct CheckAofArgs {
+    /// Truncate the last file to its last valid command
+    #[clap(long)]
+    fix: bool,
+
+    /// An AOF file, or the manifest of a multi-part AOF
+    file: PathBuf,
+}
+
+/// Entry point of `redis-check-aof`, returning whether every file is valid or was repaired
+pub fn redis_check_aof() -> anyhow::Result<bool> {
+    let args = CheckAofArgs::parse();
+    let files = match args.file.extension().is_some_and(|ext| ext == "manifest") {
+        true => manifest_files(&args.file)?,
+        false => vec![(args.file.clone(), FileKind::Incr)],
+    };
+
+    let mut ok = true;
+    for (i, (path, kind)) in files.iter().enumerate() {
+        let kind = match kind {
+            FileKind::Base => "base",
+            FileKind::History => "history",
+            FileKind::Incr => "incr",
+        };
+        println!("Checking {kind} file {}", path.display());
+        let report = match check_file(path) {
+            Ok(report) => report,
+            Err(e) => {
+                println!("{e:#}");
+                ok = false;
+                continue;
+            }
+        };
+        match report {
+            FileReport::Rdb(report) => {
+                print!("{report}");
+                if let Some((offset, e)) = &report.corruption {
+                    println!("[offset {offset}] RDB preamble is corrupted: {e:#}");
+                    ok = false;
+                } else {
+                    println!("RDB preamble is OK");
+                }
+            }
+            FileReport::Aof(report) => {
+                println!(
+                    "AOF analyzed: filename={}, size={}, ok_up_to={}, diff={}, commands={}",
+                    path.display(),
+                    report.size,
+                    report.valid_len,
+                    report.size - report.valid_len,
+                    report.commands
+                );
+                if report.is_ok() {
+                    println!("AOF {} is valid", path.display());
+                    continue;
+                }
+                match &report.error {
+                    Some(e) => println!("{e:#}"),
+                    None => println!("AOF {} is truncated", path.display()),
+                }
+                if !args.fix {
+                    ok = false;
+                } else if i + 1 != files.len() {
+                    // commands after the damage in earlier files would be lost with the rest
+                    println!("Only the last file can be truncated, repair this one by hand");
+                    ok = false;
+                } else {
+                    aof::truncate(path, report.valid_len)?;
+                    println!("Successfully truncated AOF {}", path.display());
+                }
+            }
+        }
+    }
+    Ok(ok)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_aof() {
+        let path = std::env::temp_dir().join(format!("check-aof-test-{}.aof", std::process::id()));
+        let set = b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n";
+        let get = b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n";
+        let mut data = [set.as_slice(), get].concat();
+        data.extend(b"*3\r\n$3\r\nSET\r\n$1\r\nx");
+        fs::write(&path, &data).unwrap();
+        let report = check_aof(&path).unwrap();
+        assert!(!report.is_ok());
+        assert!(report.error.is_none());
+        assert_eq!(report.commands, 2);
+        assert_eq!(report.valid_len as usize, set.len() + get.len());
+
+        // only the framing is checked, not whether this server knows the command
+        let select = b"*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n";
+        fs::write(&path, [select.as_slice(), set].concat()).unwrap();
+        let report = check_aof(&path).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.commands, 2);
+
+        fs::write(&path, b"*1\r\n+PING\r\n").unwrap();
+        let report = check_aof(&path).unwrap();
+        assert_eq!(report.valid_len, 0);
+        assert!(report.error.is_some());
+        fs::remove_file(&path).unwrap();
+    }
+}
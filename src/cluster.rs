@@ -0,0 +1,46 @@
+/// Number of hash slots keys and shard channels are distributed over
+pub const CLUSTER_SLOTS: u16 = 16384;
+
+/// CRC16 (XMODEM) as used by Redis Cluster to hash keys to slots
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |crc, byte| {
+        let mut crc = crc ^ ((*byte as u16) << 8);
+        for _ in 0..8 {
+            crc = match crc & 0x8000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x1021,
+            };
+        }
+        crc
+    })
+}
+
+/// Maps a key to its hash slot. When the key contains a non-empty `{hashtag}`, only the tag is
+/// hashed so that related keys land on the same slot.
+pub fn key_hash_slot(key: &[u8]) -> u16 {
+    let hashed = match key.iter().position(|c| *c == b'{') {
+        Some(start) => match key[start + 1..].iter().position(|c| *c == b'}') {
+            Some(0) | None => key,
+            Some(len) => &key[start + 1..start + 1 + len],
+        },
+        None => key,
+    };
+    crc16(hashed) & (CLUSTER_SLOTS - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_hash_slot() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(key_hash_slot(b"foo"), 12182);
+        assert_eq!(
+            key_hash_slot(b"{user1000}.following"),
+            key_hash_slot(b"{user1000}.followers")
+        );
+        assert_eq!(key_hash_slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") % 16384);
+        assert_eq!(key_hash_slot(b"foo{{bar}}zap"), crc16(b"{bar") % 16384);
+    }
+}
@@ -1,6 +1,14 @@
-use clap::{command, Parser};
+use clap::Parser;
 
-use crate::state::MasterConfig;
+use crate::{
+    aof::FsyncPolicy,
+    persistence::DEFAULT_SAVE_RULES,
+    replica::{
+        DEFAULT_BACKLOG_TTL, DEFAULT_MIN_REPLICAS_MAX_LAG, DEFAULT_PING_PERIOD,
+        DEFAULT_REPL_TIMEOUT,
+    },
+    state::MasterConfig,
+};
 
 #[derive(Debug, Clone)]
 pub struct DatabaseConfig {
@@ -8,11 +16,52 @@ pub struct DatabaseConfig {
     pub dbfilename: String,
 }
 
+#[derive(Debug, Clone)]
+pub struct AofConfig {
+    pub enabled: bool,
+    pub fsync: FsyncPolicy,
+    /// directory holding the AOF files, inside `dir`
+    pub dirname: String,
+    /// prefix of the AOF file names
+    pub filename: String,
+    /// whether a truncated AOF tail is cut off at startup instead of refusing to start
+    pub load_truncated: bool,
+    /// growth over the size after the last rewrite, in percent, that triggers a rewrite; 0
+    /// disables automatic rewrites
+    pub rewrite_percentage: u64,
+    /// size in bytes below which the AOF is never rewritten automatically
+    pub rewrite_min_size: u64,
+}
+
+impl Default for AofConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            fsync: FsyncPolicy::Everysec,
+            dirname: "appendonlydir".to_owned(),
+            filename: "appendonly.aof".to_owned(),
+            load_truncated: true,
+            rewrite_percentage: 100,
+            rewrite_min_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
 pub struct Config {
     pub master_config: Option<MasterConfig>,
-    pub replicaof: bool,
     pub port: u16,
-    pub db_config: Option<DatabaseConfig>,
+    pub db_config: DatabaseConfig,
+    pub notify_keyspace_events: String,
+    pub save: String,
+    pub aof_config: AofConfig,
+    pub repl_diskless_sync: bool,
+    pub repl_backlog_size: usize,
+    pub repl_backlog_ttl: u64,
+    pub repl_timeout: u64,
+    pub repl_ping_replica_period: u64,
+    pub replica_read_only: bool,
+    pub min_replicas_to_write: usize,
+    pub min_replicas_max_lag: u64,
 }
 
 #[derive(Parser, Debug)]
@@ -28,12 +77,113 @@ struct Args {
     replicaof: Option<Vec<String>>,
 
     /// The path to the directory where the RDB file is stored
-    #[clap(long)]
-    dir: Option<String>,
+    #[clap(long, default_value = ".")]
+    dir: String,
 
     /// The name of the RDB file (example: rdbfile)
-    #[clap(long)]
-    dbfilename: Option<String>,
+    #[clap(long, default_value = "dump.rdb")]
+    dbfilename: String,
+
+    /// The keyspace event classes to publish notifications for (example: KEA)
+    #[clap(long, default_value = "")]
+    notify_keyspace_events: String,
+
+    /// Snapshot rules as `<seconds> <changes>` pairs, an empty value disables snapshots
+    #[clap(long, default_value = DEFAULT_SAVE_RULES)]
+    save: String,
+
+    /// Whether writes are logged to the append only file (yes or no)
+    #[clap(long, default_value = "no")]
+    appendonly: String,
+
+    /// When the append only file is flushed to disk: always, everysec or no
+    #[clap(long, default_value = "everysec")]
+    appendfsync: String,
+
+    /// The directory holding the append only files, relative to dir
+    #[clap(long, default_value = "appendonlydir")]
+    appenddirname: String,
+
+    /// The base name of the append only files
+    #[clap(long, default_value = "appendonly.aof")]
+    appendfilename: String,
+
+    /// Whether a truncated append only file is loaded anyway (yes or no)
+    #[clap(long, default_value = "yes")]
+    aof_load_truncated: String,
+
+    /// Growth of the append only file, in percent, that triggers a rewrite (0 disables it)
+    #[clap(long, default_value_t = 100)]
+    auto_aof_rewrite_percentage: u64,
+
+    /// The minimum size of the append only file before it is rewritten (example: 64mb)
+    #[clap(long, default_value = "64mb")]
+    auto_aof_rewrite_min_size: String,
+
+    /// Whether snapshots are streamed to replicas instead of written to the RDB file first (yes or no)
+    #[clap(long, default_value = "yes")]
+    repl_diskless_sync: String,
+
+    /// The size of the replication backlog that lets replicas continue after a disconnection (example: 1mb)
+    #[clap(long, default_value = "1mb")]
+    repl_backlog_size: String,
+
+    /// Seconds without replicas after which the replication backlog is freed (0 keeps it forever)
+    #[clap(long, default_value_t = DEFAULT_BACKLOG_TTL)]
+    repl_backlog_ttl: u64,
+
+    /// Seconds without data from the primary after which a replica reconnects
+    #[clap(long, default_value_t = DEFAULT_REPL_TIMEOUT)]
+    repl_timeout: u64,
+
+    /// Seconds between the PINGs a primary sends to its replicas
+    #[clap(long, default_value_t = DEFAULT_PING_PERIOD)]
+    repl_ping_replica_period: u64,
+
+    /// Whether a replica refuses writes from its clients (yes or no)
+    #[clap(long, default_value = "yes")]
+    replica_read_only: String,
+
+    /// The number of replicas with a lag of at most min-replicas-max-lag below which writes are refused (0 disables the check)
+    #[clap(long, default_value_t = 0)]
+    min_replicas_to_write: usize,
+
+    /// Seconds since its last acknowledgement after which a replica no longer counts for min-replicas-to-write
+    #[clap(long, default_value_t = DEFAULT_MIN_REPLICAS_MAX_LAG)]
+    min_replicas_max_lag: u64,
+}
+
+/// Parses a `yes`/`no` config value
+pub fn parse_yes_no(value: &str) -> anyhow::Result<bool> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        value => anyhow::bail!("argument must be 'yes' or 'no', got '{value}'"),
+    }
+}
+
+/// Parses a memory config value, a byte count with an optional unit (`1k` is 1000 bytes, `1kb`
+/// is 1024 bytes)
+pub fn parse_memory(value: &str) -> anyhow::Result<u64> {
+    let value = value.to_lowercase();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => anyhow::bail!("argument must be a memory value, got '{value}'"),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| anyhow::anyhow!("argument must be a memory value, got '{value}'"))?;
+    Ok(number * multiplier)
 }
 
 pub fn load_config() -> anyhow::Result<Config> {
@@ -46,16 +196,31 @@ pub fn load_config() -> anyhow::Result<Config> {
         None
     };
 
-    let db_config = if let (Some(dir), Some(dbfilename)) = (args.dir, args.dbfilename) {
-        Some(DatabaseConfig { dir, dbfilename })
-    } else {
-        None
-    };
-
     Ok(Config {
         master_config,
-        replicaof: args.replicaof.is_some(),
         port: args.port,
-        db_config,
+        db_config: DatabaseConfig {
+            dir: args.dir,
+            dbfilename: args.dbfilename,
+        },
+        notify_keyspace_events: args.notify_keyspace_events,
+        save: args.save,
+        aof_config: AofConfig {
+            enabled: parse_yes_no(&args.appendonly)?,
+            fsync: args.appendfsync.parse()?,
+            dirname: args.appenddirname,
+            filename: args.appendfilename,
+            load_truncated: parse_yes_no(&args.aof_load_truncated)?,
+            rewrite_percentage: args.auto_aof_rewrite_percentage,
+            rewrite_min_size: parse_memory(&args.auto_aof_rewrite_min_size)?,
+        },
+        repl_diskless_sync: parse_yes_no(&args.repl_diskless_sync)?,
+        repl_backlog_size: parse_memory(&args.repl_backlog_size)? as usize,
+        repl_backlog_ttl: args.repl_backlog_ttl,
+        repl_timeout: args.repl_timeout,
+        repl_ping_replica_period: args.repl_ping_replica_period,
+        replica_read_only: parse_yes_no(&args.replica_read_only)?,
+        min_replicas_to_write: args.min_replicas_to_write,
+        min_replicas_max_lag: args.min_replicas_max_lag,
     })
 }
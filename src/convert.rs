@@ -0,0 +1,490 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use clap::{Args, Parser, Subcommand};
+use indexmap::IndexMap;
+
+use crate::{
+    db::{DataType, DataValue, SetConfig, StreamData},
+    glob::glob_match,
+    json::Json,
+    resp::{
+        bulk_string::BulkString,
+        frame,
+        rdb::{writer::RdbWriter, Rdb},
+    },
+};
+
+/// Elements per command when a collection is exported as RESP, so that big keys don't turn into
+/// huge commands
+const RESP_CHUNK: usize = 128;
+
+/// Output formats of [`export`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// one JSON object per key and line, with its db, key, type, TTL and value
+    Json,
+    /// the commands recreating the keys, to pipe into another server
+    Resp,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "resp" => Ok(Self::Resp),
+            value => anyhow::bail!("Unknown format '{value}', expected json or resp"),
+        }
+    }
+}
+
+/// Which keys are exported or imported, every key by default
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    /// glob-style key pattern
+    pub pattern: Option<String>,
+    /// type names as reported by `TYPE`, any type when empty
+    pub types: Vec<String>,
+    pub db: Option<u64>,
+}
+
+impl Filter {
+    fn matches(&self, db: u64, key: &BulkString, value: &DataType) -> bool {
+        self.db.is_none_or(|filter| filter == db)
+            && self
+                .pattern
+                .as_ref()
+                .is_none_or(|pattern| glob_match(pattern.as_bytes(), key.data.as_bytes()))
+            && (self.types.is_empty()
+                || self
+                    .types
+                    .iter()
+                    .any(|ty| ty.eq_ignore_ascii_case(value.name())))
+    }
+}
+
+/// Streams the keys of an RDB file to `output`, skipping the ones that have expired. Returns the
+/// number of keys written.
+pub fn export(
+    input: impl Read,
+    mut output: impl Write,
+    format: Format,
+    filter: &Filter,
+) -> anyhow::Result<usize> {
+    let mut count = 0;
+    // a connection starts on database 0
+    let mut selected = 0;
+    Rdb::new(input).read_entries(|entry| {
+        let expired = entry.value.expiry.as_ref().is_some_and(|e| e.has_expired());
+        if expired || !filter.matches(entry.db, &entry.key, &entry.value.value) {
+            return Ok(());
+        }
+        match format {
+            Format::Json => writeln!(output, "{}", to_json(entry.db, &entry.key, &entry.value))?,
+            Format::Resp => {
+                if entry.db != selected {
+                    output
+                        .write_all(&frame::encode(&["SELECT".to_owned(), entry.db.to_string()]))?;
+                    selected = entry.db;
+                }
+                output.write_all(&to_resp(&entry.key, &entry.value)?)?;
+            }
+        }
+        count += 1;
+        Ok(())
+    })?;
+    output.flush()?;
+    Ok(count)
+}
+
+/// Builds an RDB file from JSON lines in the format written by [`export`]. Returns the number of
+/// keys written.
+pub fn import(input: impl BufRead, output: impl Write, filter: &Filter) -> anyhow::Result<usize> {
+    let mut databases: BTreeMap<u64, Vec<(BulkString, DataValue)>> = BTreeMap::new();
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (db, key, value) = Json::parse(&line)
+            .and_then(|json| from_json(&json))
+            .with_context(|| format!("Invalid key on line {}", i + 1))?;
+        if filter.matches(db, &key, &value.value) {
+            databases.entry(db).or_default().push((key, value));
+        }
+    }
+    let count = databases.values().map(Vec::len).sum();
+    RdbWriter::new(output).write_databases(
+        databases
+            .iter()
+            .map(|(db, entries)| (*db, entries.as_slice())),
+    )?;
+    Ok(count)
+}
+
+fn to_json(db: u64, key: &BulkString, value: &DataValue) -> Json {
+    let string = |s: &BulkString| Json::String(s.data.to_string());
+    let ttl = match value.expiry.as_ref().and_then(|e| e.expiration()) {
+        Some(expiration) => {
+            let ttl = expiration
+                .duration_since(SystemTime::now())
+                .unwrap_or_default();
+            Json::Number(ttl.as_millis() as f64)
+        }
+        None => Json::Null,
+    };
+    let json = match &value.value {
+        DataType::String(value) => string(value),
+        DataType::List(list) => Json::Array(list.iter().map(string).collect()),
+        DataType::Set(set) => Json::Array(set.iter().map(string).collect()),
+        DataType::ZSet(zset) => Json::Object(
+            zset.iter()
+                .map(|(member, score)| {
+                    // infinite scores are written as strings, JSON numbers can't hold them
+                    let score = match score.is_finite() {
+                        true => Json::Number(*score),
+                        false => Json::String(score.to_string()),
+                    };
+                    (member.data.to_string(), score)
+                })
+                .collect(),
+        ),
+        DataType::Hash(hash) => Json::Object(
+            hash.iter()
+                .map(|(field, value)| (field.data.to_string(), string(value)))
+                .collect(),
+        ),
+        DataType::Stream(entries) => Json::Array(
+            entries
+                .iter()
+                .map(|entry| {
+                    let fields = entry
+                        .map
+                        .iter()
+                        .map(|(field, value)| (field.data.to_string(), string(value)))
+                        .collect();
+                    Json::Object(vec![
+                        ("id".to_owned(), string(&entry.id)),
+                        ("fields".to_owned(), Json::Object(fields)),
+                    ])
+                })
+                .collect(),
+        ),
+    };
+    Json::Object(vec![
+        ("db".to_owned(), Json::Number(db as f64)),
+        ("key".to_owned(), string(key)),
+        (
+            "type".to_owned(),
+            Json::String(value.value.name().to_owned()),
+        ),
+        ("ttl".to_owned(), ttl),
+        ("value".to_owned(), json),
+    ])
+}
+
+fn from_json(json: &Json) -> anyhow::Result<(u64, BulkString, DataValue)> {
+    let field = |name: &str| {
+        json.get(name)
+            .ok_or_else(|| anyhow::anyhow!("Missing \"{name}\""))
+    };
+    let string = |json: &Json| {
+        json.as_str()
+            .map(BulkString::encode)
+            .context("Expected a string")
+    };
+
+    let db = match json.get("db") {
+        Some(db) => db.as_f64().context("\"db\" must be a number")? as u64,
+        None => 0,
+    };
+    let key = field("key")?.as_str().context("\"key\" must be a string")?;
+    let expiry = match json.get("ttl") {
+        None | Some(Json::Null) => None,
+        Some(ttl) => {
+            let ttl = ttl.as_f64().context("\"ttl\" must be a number")?;
+            let expiration = SystemTime::now() + Duration::from_millis(ttl.max(0.0) as u64);
+            Some(SetConfig::from_expiration(expiration))
+        }
+    };
+    let value = field("value")?;
+    let value = match field("type")?
+        .as_str()
+        .context("\"type\" must be a string")?
+    {
+        "string" => DataType::String(string(value)?),
+        "list" => DataType::List(array(value)?.iter().map(string).collect::<Result<_, _>>()?),
+        "set" => DataType::Set(array(value)?.iter().map(string).collect::<Result<_, _>>()?),
+        "zset" => {
+            let mut zset = object(value)?
+                .iter()
+                .map(|(member, score)| {
+                    let score = match score {
+                        Json::Number(score) => *score,
+                        Json::String(score) => score.parse()?,
+                        _ => anyhow::bail!("Invalid score for member {member}"),
+                    };
+                    Ok((BulkString::encode(member), score))
+                })
+                .collect::<anyhow::Result<IndexMap<_, f64>>>()?;
+            zset.sort_by(|m1, s1, m2, s2| {
+                s1.total_cmp(s2)
+                    .then_with(|| m1.data.as_str().cmp(m2.data.as_str()))
+            });
+            DataType::ZSet(zset)
+        }
+        "hash" => DataType::Hash(
+            object(value)?
+                .iter()
+                .map(|(field, value)| Ok((BulkString::encode(field), string(value)?)))
+                .collect::<anyhow::Result<_>>()?,
+        ),
+        "stream" => DataType::Stream(
+            array(value)?
+                .iter()
+                .map(|entry| {
+                    let id = entry.get("id").context("Missing stream entry \"id\"")?;
+                    let fields = entry
+                        .get("fields")
+                        .context("Missing stream entry \"fields\"")?;
+                    Ok(StreamData {
+                        id: string(id)?,
+                        map: object(fields)?
+                            .iter()
+                            .map(|(field, value)| Ok((BulkString::encode(field), string(value)?)))
+                            .collect::<anyhow::Result<_>>()?,
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?,
+        ),
+        ty => anyhow::bail!("Unknown type '{ty}'"),
+    };
+    Ok((db, BulkString::encode(key), DataValue { value, expiry }))
+}
+
+fn array(json: &Json) -> anyhow::Result<&[Json]> {
+    json.as_array().context("Expected an array")
+}
+
+fn object(json: &Json) -> anyhow::Result<&[(String, Json)]> {
+    json.as_object().context("Expected an object")
+}
+
+/// The commands recreating a key. Collections are deleted first so that they replace the
+/// target's value instead of being merged into it.
+fn to_resp(key: &BulkString, value: &DataValue) -> anyhow::Result<Vec<u8>> {
+    let key = key.data.to_string();
+    let string = |s: &BulkString| s.data.to_string();
+    let mut out = Vec::new();
+    match &value.value {
+        DataType::String(value) => out.extend(frame::encode(&[
+            "SET".to_owned(),
+            key.clone(),
+            string(value),
+        ])),
+        DataType::List(list) => replace_with(
+            &mut out,
+            "RPUSH",
+            &key,
+            list.iter().map(string).collect(),
+            1,
+        ),
+        DataType::Set(set) => {
+            replace_with(&mut out, "SADD", &key, set.iter().map(string).collect(), 1)
+        }
+        DataType::ZSet(zset) => replace_with(
+            &mut out,
+            "ZADD",
+            &key,
+            zset.iter()
+                .flat_map(|(member, score)| [score.to_string(), string(member)])
+                .collect(),
+            2,
+        ),
+        DataType::Hash(hash) => replace_with(
+            &mut out,
+            "HSET",
+            &key,
+            hash.iter()
+                .flat_map(|(field, value)| [string(field), string(value)])
+                .collect(),
+            2,
+        ),
+        DataType::Stream(entries) => {
+            out.extend(frame::encode(&["DEL".to_owned(), key.clone()]));
+            for entry in entries {
+                let mut args = vec!["XADD".to_owned(), key.clone(), string(&entry.id)];
+                args.extend(
+                    entry
+                        .map
+                        .iter()
+                        .flat_map(|(field, value)| [string(field), string(value)]),
+                );
+                out.extend(frame::encode(&args));
+            }
+        }
+    }
+    if let Some(expiration) = value.expiry.as_ref().and_then(|e| e.expiration()) {
+        let ms = expiration.duration_since(UNIX_EPOCH)?.as_millis();
+        out.extend(frame::encode(&[
+            "PEXPIREAT".to_owned(),
+            key,
+            ms.to_string(),
+        ]));
+    }
+    Ok(out)
+}
+
+/// Deletes `key` and adds `args` back with the command `name`, `RESP_CHUNK` groups of `group`
+/// arguments at a time
+fn replace_with(out: &mut Vec<u8>, name: &str, key: &str, args: Vec<String>, group: usize) {
+    out.extend(frame::encode(&["DEL".to_owned(), key.to_owned()]));
+    for chunk in args.chunks(RESP_CHUNK * group) {
+        let mut args = vec![name.to_owned(), key.to_owned()];
+        args.extend_from_slice(chunk);
+        out.extend(frame::encode(&args));
+    }
+}
+
+/// Converts RDB files to JSON lines or RESP commands, and JSON lines back to RDB files
+#[derive(Parser, Debug)]
+#[command(name = "redis-rdb-convert", version, about, long_about = None)]
+struct ConvertArgs {
+    #[command(subcommand)]
+    command: ConvertCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum ConvertCommand {
+    /// Writes the keys of an RDB file to stdout
+    Export {
+        /// The output format: json or resp
+        #[clap(long, default_value = "json")]
+        format: String,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+
+        /// The RDB file to export
+        file: PathBuf,
+    },
+    /// Builds an RDB file from JSON lines
+    Import {
+        #[command(flatten)]
+        filter: FilterArgs,
+
+        /// The JSON lines to import, `-` for stdin
+        input: PathBuf,
+
+        /// The RDB file to write
+        output: PathBuf,
+    },
+}
+
+#[derive(Args, Debug)]
+struct FilterArgs {
+    /// Only keys matching this glob-style pattern
+    #[clap(long)]
+    pattern: Option<String>,
+
+    /// Only keys of this type, can be repeated (example: --type hash --type zset)
+    #[clap(long = "type")]
+    types: Vec<String>,
+
+    /// Only keys of this database
+    #[clap(long)]
+    db: Option<u64>,
+}
+
+impl From<FilterArgs> for Filter {
+    fn from(args: FilterArgs) -> Self {
+        Self {
+            pattern: args.pattern,
+            types: args.types,
+            db: args.db,
+        }
+    }
+}
+
+/// Entry point of `redis-rdb-convert`
+pub fn redis_rdb_convert() -> anyhow::Result<()> {
+    match ConvertArgs::parse().command {
+        ConvertCommand::Export {
+            format,
+            filter,
+            file,
+        } => {
+            let input = File::open(&file).with_context(|| format!("open {file:?}"))?;
+            let output = BufWriter::new(io::stdout().lock());
+            let count = export(input, output, format.parse()?, &filter.into())?;
+            eprintln!("Exported {count} keys");
+        }
+        ConvertCommand::Import {
+            filter,
+            input,
+            output,
+        } => {
+            let reader: Box<dyn BufRead> = match input == Path::new("-") {
+                true => Box::new(io::stdin().lock()),
+                false => Box::new(BufReader::new(
+                    File::open(&input).with_context(|| format!("open {input:?}"))?,
+                )),
+            };
+            let writer = BufWriter::new(
+                File::create(&output).with_context(|| format!("create {output:?}"))?,
+            );
+            let count = import(reader, writer, &filter.into())?;
+            eprintln!("Imported {count} keys into {}", output.display());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_round_trip() {
+        let lines = [
+            r#"{"db":0,"key":"s","type":"string","ttl":null,"value":"v"}"#,
+            r#"{"db":0,"key":"z","type":"zset","ttl":null,"value":{"a":1,"b":"inf"}}"#,
+            r#"{"db":0,"key":"x","type":"stream","ttl":null,"value":[{"id":"1-1","fields":{"f":"v"}}]}"#,
+            r#"{"db":1,"key":"l","type":"list","ttl":null,"value":["a","b"]}"#,
+        ];
+        let mut rdb = Vec::new();
+        assert_eq!(
+            import(lines.join("\n").as_bytes(), &mut rdb, &Filter::default()).unwrap(),
+            4
+        );
+
+        let mut json = Vec::new();
+        export(rdb.as_slice(), &mut json, Format::Json, &Filter::default()).unwrap();
+        assert_eq!(String::from_utf8(json).unwrap(), lines.join("\n") + "\n");
+
+        let filter = Filter {
+            pattern: Some("[sz]".to_owned()),
+            types: vec!["zset".to_owned()],
+            db: Some(0),
+        };
+        let mut resp = Vec::new();
+        assert_eq!(
+            export(rdb.as_slice(), &mut resp, Format::Resp, &filter).unwrap(),
+            1
+        );
+        assert_eq!(
+            resp,
+            b"*2\r\n$3\r\nDEL\r\n$1\r\nz\r\n\
+              *6\r\n$4\r\nZADD\r\n$1\r\nz\r\n$1\r\n1\r\n$1\r\na\r\n$3\r\ninf\r\n$1\r\nb\r\n"
+        );
+    }
+}
@@ -1,17 +1,28 @@
 use std::{
     collections::VecDeque,
     fs::File,
-    path::Path,
-    time::{Duration, SystemTime},
+    io::Read,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
     config::DatabaseConfig,
-    resp::{bulk_string::BulkString, rdb::Rdb},
+    notify::{class, Notifier},
+    persistence::Persistence,
+    resp::{bulk_string::BulkString, rdb::Rdb, XinfoArg},
 };
+use anyhow::Context;
 use dashmap::DashMap;
-use indexmap::IndexMap;
+use indexmap::{IndexMap, IndexSet};
 use thiserror::Error;
+use tokio::sync::watch;
+
+pub const WRONGTYPE_ERROR: &str =
+    "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n";
+
+/// The reply of `XREAD` when no stream has entries to return
+pub const NULL_ARRAY: &str = "*-1\r\n";
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct SetConfig {
@@ -19,14 +30,19 @@ pub struct SetConfig {
 }
 
 impl SetConfig {
-    pub fn new(px: Option<&BulkString>, time: Option<&BulkString>) -> anyhow::Result<Self> {
+    /// Reads the expiration option of `SET`: `EX` and `PX` are relative to now, `EXAT` and
+    /// `PXAT` are unix times
+    pub fn new(option: Option<&BulkString>, time: Option<&BulkString>) -> anyhow::Result<Self> {
         let mut config = SetConfig { expiration: None };
-        if let (Some(px), Some(time)) = (px, time) {
-            if px.data.to_lowercase().as_str() == "px" {
-                let expiry_duration: u64 = time.data.parse()?;
-                config.expiration =
-                    Some(SystemTime::now() + Duration::from_millis(expiry_duration));
-            }
+        if let (Some(option), Some(time)) = (option, time) {
+            let time: u64 = time.data.parse()?;
+            config.expiration = match option.data.to_lowercase().as_str() {
+                "ex" => Some(SystemTime::now() + Duration::from_secs(time)),
+                "px" => Some(SystemTime::now() + Duration::from_millis(time)),
+                "exat" => Some(UNIX_EPOCH + Duration::from_secs(time)),
+                "pxat" => Some(UNIX_EPOCH + Duration::from_millis(time)),
+                _ => None,
+            };
         };
         Ok(config)
     }
@@ -37,6 +53,10 @@ impl SetConfig {
         }
     }
 
+    pub fn expiration(&self) -> Option<SystemTime> {
+        self.expiration
+    }
+
     pub fn has_expired(&self) -> bool {
         match self.expiration {
             Some(expiration) => expiration <= SystemTime::now(),
@@ -51,12 +71,31 @@ pub struct StreamData {
     pub map: IndexMap<BulkString, BulkString>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum DataType {
     String(BulkString),
+    List(VecDeque<BulkString>),
+    Set(IndexSet<BulkString>),
+    /// members with their scores, in ascending score order
+    ZSet(IndexMap<BulkString, f64>),
+    Hash(IndexMap<BulkString, BulkString>),
     Stream(VecDeque<StreamData>),
 }
 
+impl DataType {
+    /// The name reported by `TYPE`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::String(_) => "string",
+            Self::List(_) => "list",
+            Self::Set(_) => "set",
+            Self::ZSet(_) => "zset",
+            Self::Hash(_) => "hash",
+            Self::Stream(_) => "stream",
+        }
+    }
+}
+
 enum SequencePosition {
     Start,
     End,
@@ -91,18 +130,11 @@ impl DataType {
         (start.0 <= time && time <= end.0) && (start.1 <= seq && seq <= end.1)
     }
 
-    fn within_exclusive_time_bounds(
-        &self,
-        value: &StreamData,
-        start: (i64, i64),
-        end: (i64, i64),
-    ) -> bool {
-        let (time, seq) = value
+    fn after_id(&self, value: &StreamData, start: (i64, i64)) -> bool {
+        let id = value
             .get_time_and_seq_num(&value.id.data, None)
             .expect("Invalid Id set for {value:?}");
-
-        ((start.0 < time && time <= end.0) && (start.1 <= seq && seq <= end.1))
-            || ((start.0 <= time && time <= end.0) && (start.1 < seq && seq <= end.1))
+        id > start
     }
 
     fn stream_value_to_resp(&self, value: &StreamData) -> String {
@@ -121,8 +153,8 @@ impl DataType {
 
     fn xrange(&self, start: &BulkString, end: &BulkString) -> anyhow::Result<String> {
         let stream_data = match self {
-            DataType::String(_) => anyhow::bail!("Only use for stream data"),
             DataType::Stream(val) => val,
+            _ => anyhow::bail!("Only use for stream data"),
         };
         let start = match start.data.as_str() {
             "-" => (0, 0),
@@ -142,10 +174,15 @@ impl DataType {
         Ok(resp_value)
     }
 
-    fn xread(&self, key: &BulkString, start: &BulkString) -> anyhow::Result<String> {
+    fn xread(
+        &self,
+        key: &BulkString,
+        start: &BulkString,
+        count: Option<usize>,
+    ) -> anyhow::Result<String> {
         let stream_data = match self {
-            DataType::String(_) => anyhow::bail!("Only use for stream data"),
             DataType::Stream(val) => val,
+            _ => anyhow::bail!("Only use for stream data"),
         };
 
         let start = match start.data.as_str() {
@@ -153,11 +190,10 @@ impl DataType {
             data => self.xrange_split_values(data, SequencePosition::Start)?,
         };
 
-        let end = (i64::MAX, i64::MAX);
-
         let result: Vec<String> = stream_data
             .iter()
-            .filter(|x| self.within_exclusive_time_bounds(x, start, end))
+            .filter(|x| self.after_id(x, start))
+            .take(count.unwrap_or(usize::MAX))
             .map(|x| self.stream_value_to_resp(x))
             .collect();
 
@@ -174,10 +210,69 @@ impl DataType {
         Ok(resp_value)
     }
 
+    /// Generates the `XINFO STREAM` reply. The stream is stored as a flat `VecDeque`, so every
+    /// entry is reported as its own radix tree key and the nodes are the allocated slots of the
+    /// deque. Entries are never deleted, so the entries added match the stream length.
+    fn xinfo_stream(&self, full: Option<usize>) -> anyhow::Result<String> {
+        let stream_data = match self {
+            DataType::Stream(val) => val,
+            _ => anyhow::bail!("Only use for stream data"),
+        };
+        let first_id = match stream_data.front() {
+            Some(v) => v.id.clone(),
+            None => BulkString::encode("0-0"),
+        };
+        let mut fields = vec![
+            ("length", format!(":{}\r\n", stream_data.len())),
+            ("radix-tree-keys", format!(":{}\r\n", stream_data.len())),
+            (
+                "radix-tree-nodes",
+                format!(":{}\r\n", stream_data.capacity()),
+            ),
+            ("last-generated-id", self.max_entry_timestamp()?.decode()),
+            ("max-deleted-entry-id", BulkString::encode("0-0").decode()),
+            ("entries-added", format!(":{}\r\n", stream_data.len())),
+            ("recorded-first-entry-id", first_id.decode()),
+        ];
+        match full {
+            Some(count) => {
+                let count = if count == 0 { usize::MAX } else { count };
+                let entries: Vec<String> = stream_data
+                    .iter()
+                    .take(count)
+                    .map(|x| self.stream_value_to_resp(x))
+                    .collect();
+                fields.push((
+                    "entries",
+                    format!("*{}\r\n{}", entries.len(), entries.join("")),
+                ));
+                fields.push(("groups", "*0\r\n".to_owned()));
+            }
+            None => {
+                let entry_to_resp = |entry: Option<&StreamData>| match entry {
+                    Some(v) => self.stream_value_to_resp(v),
+                    None => "$-1\r\n".to_owned(),
+                };
+                fields.push(("groups", ":0\r\n".to_owned()));
+                fields.push(("first-entry", entry_to_resp(stream_data.front())));
+                fields.push(("last-entry", entry_to_resp(stream_data.back())));
+            }
+        }
+        let resp_value = format!(
+            "*{}\r\n{}",
+            fields.len() * 2,
+            fields
+                .iter()
+                .map(|(k, v)| format!("{}{v}", BulkString::encode(k).decode()))
+                .collect::<String>()
+        );
+        Ok(resp_value)
+    }
+
     fn max_entry_timestamp(&self) -> anyhow::Result<BulkString> {
         let stream_data = match self {
-            DataType::String(_) => anyhow::bail!("Only use for stream data"),
             DataType::Stream(val) => val,
+            _ => anyhow::bail!("Only use for stream data"),
         };
 
         let res = match stream_data.back() {
@@ -203,8 +298,10 @@ pub enum EntryIdError {
     NotAscendingError,
     #[error("-ERR The ID specified in XADD must be greater than 0-0\r\n")]
     InvalidStartError,
-    #[error("Parsing stream data")]
+    #[error("-ERR Invalid stream ID specified as stream command argument\r\n")]
     ParsingError,
+    #[error("-WRONGTYPE Operation against a key holding the wrong kind of value\r\n")]
+    WrongTypeError,
 }
 
 impl StreamData {
@@ -241,7 +338,7 @@ impl StreamData {
         data: &str,
         other: Option<&StreamData>,
     ) -> Result<(i64, i64), EntryIdError> {
-        let (time, num) = data.split_once('-').expect("invalid explicit entry id");
+        let (time, num) = data.split_once('-').ok_or(EntryIdError::ParsingError)?;
         let time: i64 = time.parse().map_err(|_| EntryIdError::ParsingError)?;
         let num = match num {
             "*" => match other {
@@ -250,7 +347,7 @@ impl StreamData {
                         .id
                         .data
                         .split_once('-')
-                        .expect("invalid explicit entry id");
+                        .ok_or(EntryIdError::ParsingError)?;
                     let other_num: i64 =
                         other_num.parse().map_err(|_| EntryIdError::ParsingError)?;
                     let other_time: i64 =
@@ -273,34 +370,67 @@ impl StreamData {
 pub struct Database {
     config: Option<DatabaseConfig>,
     values: DashMap<BulkString, DataValue>,
+    stream_watchers: DashMap<BulkString, watch::Sender<()>>,
+    /// Keys under `WATCH`, with the number of watching connections and a modification count
+    watched_keys: DashMap<BulkString, (usize, u64)>,
+    notifier: Notifier,
+    persistence: Persistence,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct DataValue {
     pub(crate) value: DataType,
     pub(crate) expiry: Option<SetConfig>,
 }
 
 impl Database {
-    pub fn initialize(config: Option<DatabaseConfig>) -> Self {
-        let mut values = DashMap::new();
-        let Some(config) = config else {
-            return Self { config, values };
-        };
+    /// Creates an empty database
+    pub fn new(config: Option<DatabaseConfig>) -> Self {
+        Self {
+            config,
+            values: DashMap::new(),
+            stream_watchers: DashMap::new(),
+            watched_keys: DashMap::new(),
+            notifier: Notifier::default(),
+            persistence: Persistence::default(),
+        }
+    }
 
-        let path = Path::new(&config.dir).join(&config.dbfilename);
+    /// Creates the database and loads the configured RDB file, if there is one
+    pub fn initialize(config: Option<DatabaseConfig>) -> anyhow::Result<Self> {
+        let db = Self::new(config);
+        if db.config.is_none() {
+            return Ok(db);
+        }
+        let path = db.rdb_path();
         if let Ok(f) = File::open(&path) {
-            let mut rdb = Rdb::new(f);
-            rdb.read_rdb_to_map(&mut values)
-                .expect("Failed to read rdb dump");
+            db.load_rdb(f).with_context(|| {
+                format!("Failed to load the RDB file {path:?}, inspect it with redis-check-rdb")
+            })?;
         } else {
             println!("The {path:?} file was not found");
         }
+        Ok(db)
+    }
 
-        Self {
-            config: Some(config),
-            values,
+    /// Replaces every key with the keys of `other`, as if all of them were modified. The caller
+    /// has to keep other commands out while the keys are swapped.
+    pub fn replace_with(&self, other: Database) {
+        self.values.clear();
+        for (key, value) in other.values {
+            self.values.insert(key, value);
+        }
+        for mut watched in self.watched_keys.iter_mut() {
+            watched.1 += 1;
         }
+        for watcher in self.stream_watchers.iter() {
+            watcher.send_replace(());
+        }
+    }
+
+    /// Loads the keys of an RDB dump on top of the current ones
+    pub(crate) fn load_rdb(&self, reader: impl Read) -> anyhow::Result<()> {
+        Rdb::new(reader).read_rdb_to_map(&self.values)
     }
 
     pub fn set(
@@ -309,14 +439,15 @@ impl Database {
         value: InputData,
         config: Option<SetConfig>,
     ) -> Result<BulkString, EntryIdError> {
-        let val = match value {
+        let is_new = !self.values.contains_key(&key);
+        let (val, event, class) = match value {
             InputData::String(val) => {
                 let data_value = DataValue {
                     value: DataType::String(val.clone()),
                     expiry: config,
                 };
                 self.values.insert(key.clone(), data_value);
-                val
+                (val, "set", class::STRING)
             }
             InputData::Stream(mut val) => match self.values.get_mut(&key) {
                 Some(mut stored_data) => match &mut stored_data.value {
@@ -325,14 +456,14 @@ impl Database {
                         val.valid_entry_id(last_value)?;
                         let id = val.id.clone();
                         stream_data.push_back(val);
-                        id
+                        self.notify_stream(&key);
+                        (id, "xadd", class::STREAM)
                     }
-                    DataType::String(_) => panic!("previous stored value was a string"),
+                    _ => return Err(EntryIdError::WrongTypeError),
                 },
                 None => {
                     let mut v = VecDeque::new();
-                    let key_id = val.id.data.replace('*', "1");
-                    val.id = BulkString::encode(&key_id);
+                    val.valid_entry_id(None)?;
                     let id = val.id.clone();
                     v.push_back(val);
                     self.values.insert(
@@ -342,46 +473,184 @@ impl Database {
                             expiry: None,
                         },
                     );
-                    id
+                    self.notify_stream(&key);
+                    (id, "xadd", class::STREAM)
                 }
             },
         };
+        self.touch(&key);
+        if is_new {
+            self.notifier.notify(class::NEW, "new", &key);
+        }
+        self.notifier.notify(class, event, &key);
 
         Ok(val)
     }
 
     pub fn get(&self, key: &BulkString) -> String {
+        if self.expire_if_needed(key) {
+            return "$-1\r\n".to_owned();
+        }
         let Some(stored_val) = self.values.get(key) else {
             return "$-1\r\n".to_owned();
         };
-        let decoded_value = match &stored_val.value {
+        match &stored_val.value {
             DataType::String(v) => v.decode(),
-            DataType::Stream(_) => unimplemented!(), // TODO: better error handling here
-        };
-        let has_expired = stored_val.expiry.clone().is_some_and(|e| e.has_expired());
-        if has_expired {
-            self.values.remove(key);
-            "$-1\r\n".to_owned()
-        } else {
-            decoded_value
+            _ => WRONGTYPE_ERROR.to_owned(),
         }
     }
 
     pub fn ty(&self, key: &BulkString) -> String {
+        if self.expire_if_needed(key) {
+            return "+none\r\n".to_owned();
+        }
         let Some(stored_val) = self.values.get(key) else {
             return "+none\r\n".to_owned();
         };
-        let ty_value = match &stored_val.value {
-            DataType::String(_) => "+string\r\n".to_owned(),
-            DataType::Stream(_) => "+stream\r\n".to_owned(),
-        };
-        let has_expired = stored_val.expiry.clone().is_some_and(|e| e.has_expired());
-        if has_expired {
-            self.values.remove(key);
-            "+none\r\n".to_owned()
-        } else {
-            ty_value
+        format!("+{}\r\n", stored_val.value.name())
+    }
+
+    /// Removes the keys, returning how many existed
+    pub fn del(&self, keys: &[BulkString]) -> usize {
+        let mut deleted = 0;
+        for key in keys {
+            if self.expire_if_needed(key) || self.values.remove(key).is_none() {
+                continue;
+            }
+            deleted += 1;
+            self.touch(key);
+            self.notifier.notify(class::GENERIC, "del", key);
         }
+        deleted
+    }
+
+    /// A copy of the value stored at the key
+    pub(crate) fn get_value(&self, key: &BulkString) -> Option<DataValue> {
+        if self.expire_if_needed(key) {
+            return None;
+        }
+        self.values.get(key).map(|value| value.clone())
+    }
+
+    /// When the key expires, without checking whether it already did
+    pub(crate) fn expiration(&self, key: &BulkString) -> Option<SystemTime> {
+        self.values
+            .get(key)
+            .and_then(|value| value.expiry.as_ref().and_then(SetConfig::expiration))
+    }
+
+    /// Stores a deserialized value, returning false when the key exists and `replace` is not set
+    pub(crate) fn restore(&self, key: BulkString, value: DataValue, replace: bool) -> bool {
+        self.expire_if_needed(&key);
+        let is_new = !self.values.contains_key(&key);
+        if !is_new && !replace {
+            return false;
+        }
+        // a value that expired on the way is accepted and dropped right away
+        if value.expiry.as_ref().is_some_and(|e| e.has_expired()) {
+            if !is_new {
+                self.values.remove(&key);
+                self.touch(&key);
+                self.notifier.notify(class::GENERIC, "del", &key);
+            }
+            return true;
+        }
+        let is_stream = matches!(value.value, DataType::Stream(_));
+        self.values.insert(key.clone(), value);
+        self.touch(&key);
+        if is_new {
+            self.notifier.notify(class::NEW, "new", &key);
+        }
+        self.notifier.notify(class::GENERIC, "restore", &key);
+        if is_stream {
+            self.notify_stream(&key);
+        }
+        true
+    }
+
+    /// Removes the key if it has expired, returning whether it was removed
+    fn expire_if_needed(&self, key: &BulkString) -> bool {
+        let expired = self
+            .values
+            .remove_if(key, |_, v| {
+                v.expiry.as_ref().is_some_and(|e| e.has_expired())
+            })
+            .is_some();
+        if expired {
+            self.touch(key);
+            self.notifier.notify(class::EXPIRED, "expired", key);
+        }
+        expired
+    }
+
+    /// Removes every expired key, so that expirations are observed even for keys nobody reads
+    pub fn expire_keys(&self) {
+        let expired: Vec<BulkString> = self
+            .values
+            .iter()
+            .filter(|entry| entry.expiry.as_ref().is_some_and(|e| e.has_expired()))
+            .map(|entry| entry.key().clone())
+            .collect();
+        for key in expired {
+            self.expire_if_needed(&key);
+        }
+    }
+
+    /// Marks a key as modified for the connections watching it and for the next snapshot
+    fn touch(&self, key: &BulkString) {
+        self.persistence.mark_dirty();
+        if let Some(mut watched) = self.watched_keys.get_mut(key) {
+            watched.1 += 1;
+        }
+    }
+
+    /// Starts watching a key, returning its current modification count
+    pub fn watch(&self, key: &BulkString) -> u64 {
+        self.expire_if_needed(key);
+        let mut watched = self.watched_keys.entry(key.clone()).or_insert((0, 0));
+        watched.0 += 1;
+        watched.1
+    }
+
+    pub fn unwatch(&self, key: &BulkString) {
+        self.watched_keys.remove_if_mut(key, |_, watched| {
+            watched.0 -= 1;
+            watched.0 == 0
+        });
+    }
+
+    /// Returns whether the key was modified, expired or deleted since `watch` returned `version`
+    pub fn modified_since(&self, key: &BulkString, version: u64) -> bool {
+        self.expire_if_needed(key);
+        self.watched_keys
+            .get(key)
+            .is_none_or(|watched| watched.1 != version)
+    }
+
+    pub fn notifier(&self) -> &Notifier {
+        &self.notifier
+    }
+
+    pub fn persistence(&self) -> &Persistence {
+        &self.persistence
+    }
+
+    /// Location of the RDB file, `./dump.rdb` unless configured otherwise
+    pub fn rdb_path(&self) -> PathBuf {
+        match &self.config {
+            Some(config) => Path::new(&config.dir).join(&config.dbfilename),
+            None => PathBuf::from("dump.rdb"),
+        }
+    }
+
+    /// Copies every key that has not expired. The caller has to keep writers out while the copy is
+    /// taken for it to be a consistent point-in-time snapshot.
+    pub(crate) fn snapshot(&self) -> Vec<(BulkString, DataValue)> {
+        self.values
+            .iter()
+            .filter(|entry| !entry.expiry.as_ref().is_some_and(|e| e.has_expired()))
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect()
     }
 
     pub fn dir(&self) -> anyhow::Result<String> {
@@ -420,47 +689,110 @@ impl Database {
     ) -> anyhow::Result<String> {
         match self.values.get(key) {
             Some(stored_value) => stored_value.value.xrange(start, end),
-            None => anyhow::bail!("Stream value not set"),
+            None => Ok("*0\r\n".to_owned()),
+        }
+    }
+
+    pub fn xinfo(&self, arg: &XinfoArg) -> String {
+        let key = match arg {
+            XinfoArg::Stream(key, _) | XinfoArg::Groups(key) | XinfoArg::Consumers(key, _) => key,
+        };
+        let Some(stored_value) = self.values.get(key) else {
+            return "-ERR no such key\r\n".to_owned();
+        };
+        if !matches!(stored_value.value, DataType::Stream(_)) {
+            return WRONGTYPE_ERROR.to_owned();
+        }
+        match arg {
+            XinfoArg::Stream(_, full) => match stored_value.value.xinfo_stream(*full) {
+                Ok(res) => res,
+                Err(e) => format!("-ERR {e}\r\n"),
+            },
+            // consumer groups are not supported yet, so every stream has none
+            XinfoArg::Groups(_) => "*0\r\n".to_owned(),
+            XinfoArg::Consumers(key, group) => format!(
+                "-NOGROUP No such consumer group '{}' for key name '{}'\r\n",
+                group.data.as_str(),
+                key.data.as_str()
+            ),
         }
     }
 
+    /// Replaces the `$` IDs with the last ID of their stream, or `None` when one of those keys
+    /// holds another type
     pub fn swap_and_fetch_max_id(
         &self,
         key_id_pairs: &[(BulkString, BulkString)],
-    ) -> Vec<(BulkString, BulkString)> {
+    ) -> Option<Vec<(BulkString, BulkString)>> {
         key_id_pairs
             .iter()
-            .flat_map(|(key, start)| {
+            .map(|(key, start)| {
                 let start = if start.data.as_str() == "$" {
                     match self.values.get(key) {
-                        Some(v) => v
-                            .value
-                            .max_entry_timestamp()
-                            .expect("Failed to find last entry time stamp"),
+                        Some(v) => v.value.max_entry_timestamp().ok()?,
                         None => BulkString::encode("0-0"),
                     }
                 } else {
                     start.to_owned()
                 };
-                dbg!(&key, &start);
                 Some((key.clone(), start))
             })
             .collect()
     }
 
-    pub fn xread(&self, key_id_pairs: &[(BulkString, BulkString)]) -> String {
+    /// Returns a receiver that is notified whenever an entry is added to the stream stored at `key`
+    pub fn subscribe_stream(&self, key: &BulkString) -> watch::Receiver<()> {
+        self.stream_watchers
+            .entry(key.clone())
+            .or_insert_with(|| watch::channel(()).0)
+            .subscribe()
+    }
+
+    /// Drops the watcher of the stream stored at `key` once nobody is subscribed to it anymore
+    pub fn unsubscribe_stream(&self, key: &BulkString) {
+        self.stream_watchers
+            .remove_if(key, |_, tx| tx.receiver_count() == 0);
+    }
+
+    fn notify_stream(&self, key: &BulkString) {
+        if let Some(tx) = self.stream_watchers.get(key) {
+            tx.send_replace(());
+        }
+    }
+
+    pub fn xread(&self, count: Option<usize>, key_id_pairs: &[(BulkString, BulkString)]) -> String {
         let resp_values: Vec<String> = key_id_pairs
             .iter()
             .flat_map(|(key, start)| {
                 self.values
                     .get(key)
-                    .and_then(|v| v.value.xread(key, start).ok())
+                    .and_then(|v| v.value.xread(key, start, count).ok())
             })
             .collect();
 
         match resp_values.len() {
-            0 => "$-1\r\n".to_string(),
+            // the null array, which clients decoding an array reply expect
+            0 => NULL_ARRAY.to_string(),
             n => format!("*{n}\r\n{}", resp_values.join("")),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unsubscribe_stream() {
+        let db = Database::new(None);
+        let key = BulkString::encode("s");
+        let rx = db.subscribe_stream(&key);
+        let other = db.subscribe_stream(&key);
+        drop(rx);
+        db.unsubscribe_stream(&key);
+        assert_eq!(db.stream_watchers.len(), 1);
+        drop(other);
+        db.unsubscribe_stream(&key);
+        assert!(db.stream_watchers.is_empty());
+    }
+}
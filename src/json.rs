@@ -0,0 +1,319 @@
+use std::fmt;
+
+/// A JSON value. Object members keep their order, so that exported collections round trip in
+/// the order they were stored.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn parse(data: &str) -> anyhow::Result<Self> {
+        let mut parser = Parser {
+            data: data.as_bytes(),
+            pos: 0,
+        };
+        let value = parser.value()?;
+        parser.whitespace();
+        anyhow::ensure!(
+            parser.pos == data.len(),
+            "Unexpected data after the JSON value at offset {}",
+            parser.pos
+        );
+        Ok(value)
+    }
+
+    /// The member `name` of an object
+    pub fn get(&self, name: &str) -> Option<&Json> {
+        match self {
+            Self::Object(members) => members.iter().find(|(key, _)| key == name).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::Number(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Self::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&[(String, Json)]> {
+        match self {
+            Self::Object(members) => Some(members),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Null => f.write_str("null"),
+            Self::Bool(value) => write!(f, "{value}"),
+            // JSON has no representation for infinities and NaN
+            Self::Number(value) if !value.is_finite() => f.write_str("null"),
+            Self::Number(value) => write!(f, "{value}"),
+            Self::String(value) => write_string(f, value),
+            Self::Array(values) => {
+                f.write_str("[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                f.write_str("]")
+            }
+            Self::Object(members) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{value}")?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, value: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    f.write_str("\"")
+}
+
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn value(&mut self) -> anyhow::Result<Json> {
+        self.whitespace();
+        match self.peek() {
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut values = Vec::new();
+                if self.consume(b']') {
+                    return Ok(Json::Array(values));
+                }
+                loop {
+                    values.push(self.value()?);
+                    if self.consume(b']') {
+                        return Ok(Json::Array(values));
+                    }
+                    self.expect(b',')?;
+                }
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                if self.consume(b'}') {
+                    return Ok(Json::Object(members));
+                }
+                loop {
+                    self.whitespace();
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    members.push((key, self.value()?));
+                    if self.consume(b'}') {
+                        return Ok(Json::Object(members));
+                    }
+                    self.expect(b',')?;
+                }
+            }
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            Some(c) => anyhow::bail!(
+                "Unexpected character '{}' at offset {}",
+                c as char,
+                self.pos
+            ),
+            None => anyhow::bail!("Unexpected end of JSON data"),
+        }
+    }
+
+    fn whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).copied()
+    }
+
+    /// Skips whitespace and `c` if it comes next
+    fn consume(&mut self, c: u8) -> bool {
+        self.whitespace();
+        let found = self.peek() == Some(c);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, c: u8) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.consume(c),
+            "Expected '{}' at offset {}",
+            c as char,
+            self.pos
+        );
+        Ok(())
+    }
+
+    fn literal(&mut self, literal: &str, value: Json) -> anyhow::Result<Json> {
+        anyhow::ensure!(
+            self.data[self.pos..].starts_with(literal.as_bytes()),
+            "Invalid literal at offset {}",
+            self.pos
+        );
+        self.pos += literal.len();
+        Ok(value)
+    }
+
+    fn number(&mut self) -> anyhow::Result<Json> {
+        let start = self.pos;
+        while matches!(
+            self.peek(),
+            Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')
+        ) {
+            self.pos += 1;
+        }
+        let number = std::str::from_utf8(&self.data[start..self.pos])?;
+        let value = number
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid number '{number}' at offset {start}"))?;
+        Ok(Json::Number(value))
+    }
+
+    fn string(&mut self) -> anyhow::Result<String> {
+        self.expect(b'"')?;
+        let mut value = String::new();
+        loop {
+            let start = self.pos;
+            while !matches!(self.peek(), Some(b'"' | b'\\') | None) {
+                self.pos += 1;
+            }
+            value.push_str(std::str::from_utf8(&self.data[start..self.pos])?);
+            match self.peek() {
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(value);
+                }
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let escaped = self
+                        .peek()
+                        .ok_or_else(|| anyhow::anyhow!("Unexpected end of JSON string"))?;
+                    self.pos += 1;
+                    match escaped {
+                        b'"' => value.push('"'),
+                        b'\\' => value.push('\\'),
+                        b'/' => value.push('/'),
+                        b'b' => value.push('\u{8}'),
+                        b'f' => value.push('\u{c}'),
+                        b'n' => value.push('\n'),
+                        b'r' => value.push('\r'),
+                        b't' => value.push('\t'),
+                        b'u' => value.push(self.unicode_escape()?),
+                        c => anyhow::bail!("Invalid escape '\\{}' in JSON string", c as char),
+                    }
+                }
+                _ => anyhow::bail!("Unexpected end of JSON string"),
+            }
+        }
+    }
+
+    /// The code point of a `\uXXXX` escape, combining UTF-16 surrogate pairs
+    fn unicode_escape(&mut self) -> anyhow::Result<char> {
+        let high = self.hex4()?;
+        let code = if (0xd800..0xdc00).contains(&high) {
+            anyhow::ensure!(
+                self.data[self.pos..].starts_with(b"\\u"),
+                "Unpaired surrogate in JSON string"
+            );
+            self.pos += 2;
+            let low = self.hex4()?;
+            anyhow::ensure!(
+                (0xdc00..0xe000).contains(&low),
+                "Invalid surrogate pair in JSON string"
+            );
+            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or_else(|| anyhow::anyhow!("Invalid code point {code:#x}"))
+    }
+
+    fn hex4(&mut self) -> anyhow::Result<u32> {
+        let digits = self
+            .data
+            .get(self.pos..self.pos + 4)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| anyhow::anyhow!("Invalid \\u escape at offset {}", self.pos))?;
+        self.pos += 4;
+        Ok(digits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let data = r#"{"key":"a\"b\\c\n\u0001","n":[1,-2.5,1e3],"ok":true,"none":null,"o":{}}"#;
+        let json = Json::parse(data).unwrap();
+        assert_eq!(json.get("key").unwrap().as_str(), Some("a\"b\\c\n\u{1}"));
+        assert_eq!(
+            json.get("n").unwrap().as_array().unwrap()[2].as_f64(),
+            Some(1000.0)
+        );
+        assert_eq!(Json::parse(&json.to_string()).unwrap(), json);
+        assert_eq!(
+            Json::parse(r#""\ud83d\ude00""#).unwrap(),
+            Json::String("😀".to_owned())
+        );
+        assert!(Json::parse("[1,").is_err());
+        assert!(Json::parse("{} x").is_err());
+    }
+}
@@ -1,5 +1,16 @@
+pub mod aof;
+pub mod backlog;
+pub mod check;
+pub mod cluster;
 pub mod config;
+pub mod convert;
 pub mod db;
+pub mod glob;
+pub mod json;
+pub mod notify;
+pub mod persistence;
+pub mod pubsub;
 pub mod replica;
 pub mod resp;
 pub mod state;
+pub mod transaction;
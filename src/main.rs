@@ -1,150 +1,375 @@
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 
 use redis_starter_rust::{
+    aof::Aof,
     config::{load_config, Config},
-    db::Database,
-    replica::{initiate_replica_connection, send_write_to_client, send_write_to_replica},
-    resp::RedisData,
-    state::State,
+    db::{Database, WRONGTYPE_ERROR},
+    pubsub::Subscriptions,
+    replica::{replication_link, send_write_to_client, send_write_to_replica},
+    resp::{frame, RedisData},
+    state::{MasterConfig, State},
+    transaction::Transaction,
 };
+use tokio::net::TcpListener;
 use tokio::{
     io::AsyncReadExt,
-    sync::{mpsc, watch},
+    sync::{mpsc, Notify},
 };
-use tokio::{net::TcpListener, sync::broadcast};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let Config {
         master_config,
         port,
-        replicaof,
         db_config,
+        notify_keyspace_events,
+        save,
+        aof_config,
+        repl_diskless_sync,
+        repl_backlog_size,
+        repl_backlog_ttl,
+        repl_timeout,
+        repl_ping_replica_period,
+        replica_read_only,
+        min_replicas_to_write,
+        min_replicas_max_lag,
     } = load_config()?;
 
     let address = format!("127.0.0.1:{port}");
     println!("main address: {address}");
-    let db = Database::initialize(db_config);
-    let state = State::new(replicaof, db);
-    if let Some(config) = &master_config {
+    let dir = db_config.dir.clone();
+    // the AOF is more recent than the RDB file, which is only loaded when there is no AOF yet
+    let db = match aof_config.enabled && Aof::manifest_path(&dir, &aof_config).exists() {
+        true => Database::new(Some(db_config)),
+        false => Database::initialize(Some(db_config))?,
+    };
+    db.notifier().set_flags(&notify_keyspace_events)?;
+    db.persistence().set_rules(&save)?;
+    let mut state = State::new(master_config.clone(), db);
+    let aof = match aof_config.enabled {
+        true => Aof::open(&dir, &aof_config, &mut state)?,
+        false => Aof::disabled(aof_config),
+    };
+    let state = state.with_aof(aof);
+    state.replicas().set_diskless_sync(repl_diskless_sync);
+    state.replicas().set_backlog_size(repl_backlog_size);
+    state.replicas().set_backlog_ttl(repl_backlog_ttl);
+    state.replicas().set_ping_period(repl_ping_replica_period);
+    state.set_repl_timeout(Duration::from_secs(repl_timeout));
+    state.set_replica_read_only(replica_read_only);
+    state
+        .replicas()
+        .set_min_replicas_to_write(min_replicas_to_write);
+    state
+        .replicas()
+        .set_min_replicas_max_lag(min_replicas_max_lag);
+    if master_config.is_some() {
         let state = state.clone();
-        let config = config.clone();
-        tokio::spawn(async move { initiate_replica_connection(state, config).await });
+        let epoch = state.epoch();
+        tokio::spawn(async move { replication_link(state, port, epoch).await });
     };
 
-    let listener = TcpListener::bind(address).await?;
+    {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(100));
+            loop {
+                interval.tick().await;
+                state.expire_keys();
+                state.save_cron();
+                state.aof_cron();
+                state.replication_cron();
+            }
+        });
+    }
 
-    let (replica_tx, _rx) = broadcast::channel(100);
-    let (xadd_tx, _rx) = watch::channel("hello");
-    let (ack_tx, _rx) = broadcast::channel(100);
+    let listener = TcpListener::bind(address).await?;
 
     loop {
         let (socket, socket_addr) = listener.accept().await?;
         let (client_tx, client_rx) = mpsc::channel::<Vec<u8>>(100);
         let mut state = state.clone();
         let (mut reader, writer) = socket.into_split();
-        let replica_tx = replica_tx.clone();
-        let ack_tx = ack_tx.clone();
-        let xadd_tx = xadd_tx.clone();
-        let master_config = master_config.clone();
         println!("got connection from {socket_addr:?}");
         tokio::spawn(async move { send_write_to_client(client_rx, writer).await });
         tokio::spawn(async move {
             let mut buf = [0; 1024];
-            loop {
-                match reader.read(&mut buf).await {
-                    Ok(n) => {
-                        if n == 0 {
-                            // connection closed
-                            return;
+            // bytes read that don't form a complete command yet
+            let mut pending = Vec::new();
+            let mut transaction = Transaction::default();
+            // whether the connection is a replica that reads snapshots in the `$EOF:` format
+            let mut capa_eof = false;
+            // the port a replica announced that it listens on
+            let mut listening_port = socket_addr.port();
+            // the replication offset after the last write of the client, which WAIT waits for
+            let mut write_offset = 0;
+            let mut subscriptions = Subscriptions::new(state.pubsub(), client_tx.clone());
+            let disconnected = subscriptions.disconnected();
+            // notified when a replica was dropped from the registry, so that it syncs again
+            let replica_dropped = Arc::new(Notify::new());
+            'connection: loop {
+                let read = tokio::select! {
+                    read = reader.read(&mut buf) => read,
+                    // the connection fell behind on its published messages
+                    _ = disconnected.notified() => break,
+                    _ = replica_dropped.notified() => break,
+                };
+                let n = match read {
+                    // connection closed
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(e) => {
+                        eprintln!("failed to read from socket; err = {:?}", e);
+                        break;
+                    }
+                };
+                pending.extend_from_slice(&buf[..n]);
+                // a read can end in the middle of a command or hold several pipelined ones
+                loop {
+                    let len = match frame::command_len(&pending) {
+                        Ok(Some(len)) => len,
+                        Ok(None) => break,
+                        Err(e) => {
+                            let response = format!("-ERR Protocol error: {e}\r\n");
+                            let _ = client_tx.send(response.into_bytes()).await;
+                            break 'connection;
+                        }
+                    };
+                    let request: Vec<u8> = pending.drain(..len).collect();
+                    let redis_data = match RedisData::parse_bytes(&request) {
+                        Ok(redis_data) => redis_data,
+                        Err(e) => {
+                            // a command that fails to parse inside MULTI fails the EXEC
+                            transaction.abort();
+                            let response = format!("-ERR {e}\r\n");
+                            let _ = client_tx.send(response.into_bytes()).await;
+                            continue;
                         }
-                        let request = String::from_utf8_lossy(&buf[..n]);
-                        let redis_data =
-                            RedisData::parse(&request).expect("failed to parse request");
-                        println!("got data {redis_data:?}");
+                    };
+                    println!("got data {redis_data:?}");
 
-                        let redis_data =
-                            if let RedisData::Xread(stream, pairs, block_duration) = redis_data {
-                                // if the user requests blocking reads using $, we have to swap the
-                                // start time with the maximum ID
-                                RedisData::Xread(stream, state.swap_pairs(&pairs), block_duration)
-                            } else {
-                                redis_data
+                    if subscriptions.count() > 0
+                        && !matches!(
+                            redis_data,
+                            RedisData::Subscribe(_)
+                                | RedisData::Unsubscribe(_)
+                                | RedisData::Psubscribe(_)
+                                | RedisData::Punsubscribe(_)
+                                | RedisData::Ssubscribe(_)
+                                | RedisData::Sunsubscribe(_)
+                                | RedisData::Ping
+                                | RedisData::Quit
+                        )
+                    {
+                        let response = format!(
+                            "-ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context\r\n",
+                            RedisData::command_name(&String::from_utf8_lossy(&request))
+                        );
+                        let _ = client_tx.send(response.into_bytes()).await;
+                        continue;
+                    }
+
+                    // the writes of a replica only come from its primary, over the replication link
+                    if redis_data.is_write() && state.is_read_only() {
+                        transaction.abort();
+                        let response = "-READONLY You can't write against a read only replica.\r\n";
+                        let _ = client_tx.send(response.as_bytes().to_vec()).await;
+                        continue;
+                    }
+                    if redis_data.is_write() && state.lacks_good_replicas() {
+                        transaction.abort();
+                        let response = "-NOREPLICAS Not enough good replicas to write.\r\n";
+                        let _ = client_tx.send(response.as_bytes().to_vec()).await;
+                        continue;
+                    }
+
+                    if transaction.is_active() && matches!(redis_data, RedisData::Watch(_)) {
+                        transaction.abort();
+                        let response = "-ERR WATCH inside MULTI is not allowed\r\n";
+                        let _ = client_tx.send(response.as_bytes().to_vec()).await;
+                        continue;
+                    }
+                    if transaction.is_active()
+                        && !matches!(
+                            redis_data,
+                            RedisData::Multi | RedisData::Exec | RedisData::Discard
+                        )
+                    {
+                        let response = transaction.queue(redis_data, request.clone());
+                        let _ = client_tx.send(response.into_bytes()).await;
+                        continue;
+                    }
+
+                    let redis_data =
+                        if let RedisData::Xread(count, pairs, block_duration) = redis_data {
+                            // if the user requests blocking reads using $, we have to swap the
+                            // start time with the maximum ID
+                            let Some(pairs) = state.swap_pairs(&pairs) else {
+                                let _ = client_tx.send(WRONGTYPE_ERROR.as_bytes().to_vec()).await;
+                                continue;
                             };
+                            RedisData::Xread(count, pairs, block_duration)
+                        } else {
+                            redis_data
+                        };
 
-                        match &redis_data {
-                            RedisData::Set(_, _, _) => {
-                                let _ = replica_tx.send(buf[..n].to_vec());
-                                if master_config.is_none() {
-                                    println!("Incrementing primary by {n}");
-                                    state.increment_offset(n);
-                                };
+                    if let RedisData::ReplConf(cmd, arg) = &redis_data {
+                        match cmd.data.to_lowercase().as_str() {
+                            "ack" => match state.replicas().ack_request(socket_addr, &request) {
+                                Ok(offset) => println!("sending {offset} from {socket_addr}"),
+                                Err(e) => eprintln!("invalid ack from {socket_addr}: {e}"),
+                            },
+                            "listening-port" => {
+                                listening_port = arg.data.parse().unwrap_or(listening_port);
                             }
-                            RedisData::ReplConf(cmd, arg) => {
-                                if let "ack" = cmd.data.to_lowercase().as_str() {
-                                    let offset: usize =
-                                        arg.data.parse().expect("failed to parse ack offset");
-                                    println!("sending {offset} from {socket_addr}");
-                                    let _ = ack_tx.send((offset, socket_addr));
-                                }
+                            // REPLCONF capa can announce several capabilities at once
+                            "capa" => {
+                                capa_eof |= frame::args(&request)
+                                    .unwrap_or_default()
+                                    .iter()
+                                    .any(|arg| arg.eq_ignore_ascii_case(b"eof"));
                             }
-                            RedisData::Xread(_, _, Some(duration)) => {
-                                if *duration == 0 {
-                                    let mut xadd_rx = xadd_tx.subscribe();
-                                    match xadd_rx.changed().await {
-                                        Ok(_) => {
-                                            println!("data set in stream");
-                                        }
-                                        Err(_) => panic!("failed to send"),
+                            _ => {}
+                        }
+                    }
+
+                    if let RedisData::Wait(numreplicas, timeout) = &redis_data {
+                        let response = match state.primary() {
+                            Some(_) => "-ERR WAIT cannot be used with replica instances. Please also note that since Redis 4.0 if a replica is configured to be writable (which is not the default) writes to replicas are just local and are not propagated.\r\n".to_owned(),
+                            None => match state.wait(numreplicas, timeout, write_offset).await {
+                                Ok(count) => format!(":{count}\r\n"),
+                                Err(e) => format!("-ERR {e}\r\n"),
+                            },
+                        };
+                        let _ = client_tx.send(response.into_bytes()).await;
+                    } else if let RedisData::Waitaof(numlocal, numreplicas, timeout) = &redis_data {
+                        let response = match state.primary() {
+                            Some(_) => "-ERR WAITAOF cannot be used with replica instances. Please also note that writes to replicas are just local and are not propagated.\r\n".to_owned(),
+                            None => {
+                                let waitaof =
+                                    state.waitaof(numlocal, numreplicas, timeout, write_offset);
+                                match waitaof.await {
+                                    Ok((local, replicas)) => {
+                                        format!("*2\r\n:{local}\r\n:{replicas}\r\n")
                                     }
-                                } else {
-                                    let duration = Duration::from_millis(*duration);
-                                    tokio::time::sleep(duration).await;
+                                    Err(e) => format!("-ERR {e}\r\n"),
                                 }
                             }
-
-                            _ => (),
                         };
-
-                        if let RedisData::Wait(target_num_replicas, timeout) = redis_data {
-                            let getack = b"*3\r\n$8\r\nreplconf\r\n$6\r\nGETACK\r\n$1\r\n*\r\n";
-                            let _ = replica_tx.send(getack.to_vec());
-                            let rx = ack_tx.subscribe();
-                            let synced_replicas = state
-                                .count_synced_replicas(target_num_replicas, timeout, rx)
-                                .await
-                                .expect("failed get synced replica count");
-                            let response = format!(":{}\r\n", synced_replicas);
-                            let _ = client_tx.send(response.as_bytes().to_vec()).await;
-                        } else {
-                            // TODO: improve response handling
-                            let response = state
-                                .handle_response(&redis_data)
-                                .expect("failed to generate response");
-                            if !response.is_empty() {
-                                client_tx.send(response.as_bytes().to_vec()).await.unwrap();
-                            }
-                            if let RedisData::Xadd(_, _, _) = redis_data {
-                                xadd_tx.send("xadd").expect("failed to send xadd ack");
-                            }
-                            if let RedisData::Psync(_, _) = redis_data {
-                                let rdb = state.replica_request().unwrap();
-                                client_tx.send(rdb.to_vec()).await.unwrap();
-                                state.increment_num_replicas();
+                        let _ = client_tx.send(response.into_bytes()).await;
+                    } else if let RedisData::Xread(count, pairs, Some(block_duration)) = &redis_data
+                    {
+                        let response = state.xread_blocking(*count, pairs, *block_duration).await;
+                        let _ = client_tx.send(response.as_bytes().to_vec()).await;
+                    } else if let RedisData::Subscribe(channels) = &redis_data {
+                        subscriptions.subscribe(channels).await;
+                    } else if let RedisData::Unsubscribe(channels) = &redis_data {
+                        subscriptions.unsubscribe(channels).await;
+                    } else if let RedisData::Psubscribe(patterns) = &redis_data {
+                        subscriptions.psubscribe(patterns).await;
+                    } else if let RedisData::Punsubscribe(patterns) = &redis_data {
+                        subscriptions.punsubscribe(patterns).await;
+                    } else if let RedisData::Ssubscribe(channels) = &redis_data {
+                        subscriptions.ssubscribe(channels).await;
+                    } else if let RedisData::Sunsubscribe(channels) = &redis_data {
+                        subscriptions.sunsubscribe(channels).await;
+                    } else if matches!(redis_data, RedisData::Ping) && subscriptions.count() > 0 {
+                        let response = b"*2\r\n$4\r\npong\r\n$0\r\n\r\n";
+                        let _ = client_tx.send(response.to_vec()).await;
+                    } else if let RedisData::Dump(key) = &redis_data {
+                        let response = match state.dump(key) {
+                            Ok(response) => response,
+                            Err(e) => format!("-ERR {e}\r\n").into_bytes(),
+                        };
+                        let _ = client_tx.send(response).await;
+                    } else if let RedisData::Migrate(args) = &redis_data {
+                        let response = state.migrate(args).await;
+                        write_offset = state.offset();
+                        let _ = client_tx.send(response.into_bytes()).await;
+                    } else if let RedisData::Psync(replid, offset) = &redis_data {
+                        let psync = state.psync(
+                            socket_addr,
+                            listening_port,
+                            &replid.data,
+                            &offset.data,
+                            capa_eof,
+                            &client_tx,
+                        );
+                        match psync.await {
+                            Ok(replica_rx) => {
                                 let client_tx = client_tx.clone();
-                                let replica_rx = replica_tx.subscribe();
+                                let replica_dropped = replica_dropped.clone();
                                 tokio::spawn(async move {
-                                    send_write_to_replica(replica_rx, client_tx).await
+                                    let _ = send_write_to_replica(replica_rx, client_tx).await;
+                                    replica_dropped.notify_one();
                                 });
-                            };
+                            }
+                            Err(e) => {
+                                eprintln!("Failed to synchronize replica {socket_addr}: {e}");
+                                break 'connection;
+                            }
+                        }
+                    } else if let RedisData::Replicaof(primary) = &redis_data {
+                        let primary = primary
+                            .clone()
+                            .map(|(host, port)| MasterConfig { host, port });
+                        if let Some(epoch) = state.replicaof(primary) {
+                            let state = state.clone();
+                            tokio::spawn(async move { replication_link(state, port, epoch).await });
+                        }
+                        let _ = client_tx.send(b"+OK\r\n".to_vec()).await;
+                    } else if let RedisData::Quit = redis_data {
+                        let _ = client_tx.send(b"+OK\r\n".to_vec()).await;
+                        break 'connection;
+                    } else if let RedisData::Multi = redis_data {
+                        let _ = client_tx.send(transaction.begin().into_bytes()).await;
+                    } else if let RedisData::Watch(keys) = redis_data {
+                        for key in keys {
+                            if !transaction.is_watching(&key) {
+                                let version = state.watch(&key);
+                                transaction.watch(key, version);
+                            }
+                        }
+                        let _ = client_tx.send(b"+OK\r\n".to_vec()).await;
+                    } else if let RedisData::Unwatch = redis_data {
+                        state.unwatch(&transaction.take_watched());
+                        let _ = client_tx.send(b"+OK\r\n".to_vec()).await;
+                    } else if let RedisData::Discard = redis_data {
+                        let response = transaction.discard();
+                        state.unwatch(&transaction.take_watched());
+                        let _ = client_tx.send(response.into_bytes()).await;
+                    } else if let RedisData::Exec = redis_data {
+                        let watched = transaction.take_watched();
+                        let response = transaction.take().map(|queued| {
+                            // writes reach the AOF and the replicas as one MULTI ... EXEC block
+                            state
+                                .exec_requests(queued, &watched)
+                                // a watched key was modified, so nothing ran
+                                .unwrap_or_else(|| "*-1\r\n".to_owned())
+                        });
+                        state.unwatch(&watched);
+                        write_offset = state.offset();
+                        let response = response.unwrap_or_else(|response| response);
+                        let _ = client_tx.send(response.into_bytes()).await;
+                    } else {
+                        // TODO: improve response handling
+                        // successful writes reach the AOF and the replicas before the reply
+                        let response = match state.handle_request(&redis_data, &request) {
+                            Ok(response) => response,
+                            Err(e) => format!("-ERR {e}\r\n"),
+                        };
+                        if redis_data.is_write() {
+                            write_offset = state.offset();
+                        }
+                        if !response.is_empty() {
+                            client_tx.send(response.as_bytes().to_vec()).await.unwrap();
                         }
                     }
-                    Err(e) => {
-                        eprintln!("failed to read from socket; err = {:?}", e);
-                        return;
-                    }
-                };
+                }
             }
+            state.unwatch(&transaction.take_watched());
+            subscriptions.clear();
+            state.replicas().unregister(socket_addr);
         });
     }
 }
@@ -0,0 +1,119 @@
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+use crate::{pubsub::PubSub, resp::bulk_string::BulkString};
+
+/// Keyspace event classes, as configured by `notify-keyspace-events`
+pub mod class {
+    pub const KEYSPACE: u32 = 1 << 0;
+    pub const KEYEVENT: u32 = 1 << 1;
+    pub const GENERIC: u32 = 1 << 2;
+    pub const STRING: u32 = 1 << 3;
+    pub const LIST: u32 = 1 << 4;
+    pub const SET: u32 = 1 << 5;
+    pub const HASH: u32 = 1 << 6;
+    pub const ZSET: u32 = 1 << 7;
+    pub const EXPIRED: u32 = 1 << 8;
+    pub const EVICTED: u32 = 1 << 9;
+    pub const STREAM: u32 = 1 << 10;
+    pub const KEY_MISS: u32 = 1 << 11;
+    pub const MODULE: u32 = 1 << 13;
+    pub const NEW: u32 = 1 << 14;
+    /// the `A` alias, every class except key misses and new keys
+    pub const ALL: u32 =
+        GENERIC | STRING | LIST | SET | HASH | ZSET | EXPIRED | EVICTED | STREAM | MODULE;
+}
+
+const FLAG_CHARS: [(char, u32); 14] = [
+    ('g', class::GENERIC),
+    ('$', class::STRING),
+    ('l', class::LIST),
+    ('s', class::SET),
+    ('h', class::HASH),
+    ('z', class::ZSET),
+    ('x', class::EXPIRED),
+    ('e', class::EVICTED),
+    ('t', class::STREAM),
+    ('d', class::MODULE),
+    ('K', class::KEYSPACE),
+    ('E', class::KEYEVENT),
+    ('m', class::KEY_MISS),
+    ('n', class::NEW),
+];
+
+/// Publishes keyspace and keyevent notifications for the enabled event classes
+#[derive(Debug, Default)]
+pub struct Notifier {
+    flags: AtomicU32,
+    pubsub: Arc<PubSub>,
+}
+
+impl Notifier {
+    pub fn pubsub(&self) -> Arc<PubSub> {
+        self.pubsub.clone()
+    }
+
+    /// Parses a `notify-keyspace-events` string such as `KEA` or `Kx$`
+    pub fn set_flags(&self, config: &str) -> anyhow::Result<()> {
+        let mut flags = 0;
+        for c in config.chars() {
+            flags |= match c {
+                'A' => class::ALL,
+                c => match FLAG_CHARS.iter().find(|(flag, _)| *flag == c) {
+                    Some((_, class)) => *class,
+                    None => anyhow::bail!("Invalid event class character '{c}'"),
+                },
+            };
+        }
+        self.flags.store(flags, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn flags(&self) -> String {
+        let flags = self.flags.load(Ordering::Relaxed);
+        let mut config = String::new();
+        if flags & class::ALL == class::ALL {
+            config.push('A');
+        }
+        for (c, class) in FLAG_CHARS {
+            let covered_by_all = flags & class::ALL == class::ALL && class & class::ALL != 0;
+            if flags & class != 0 && !covered_by_all {
+                config.push(c);
+            }
+        }
+        config
+    }
+
+    pub fn notify(&self, class: u32, event: &str, key: &BulkString) {
+        let flags = self.flags.load(Ordering::Relaxed);
+        if flags & class == 0 {
+            return;
+        }
+        if flags & class::KEYSPACE != 0 {
+            let channel = format!("__keyspace@0__:{}", key.data.as_str());
+            self.pubsub
+                .publish(&BulkString::encode(&channel), &BulkString::encode(event));
+        }
+        if flags & class::KEYEVENT != 0 {
+            let channel = format!("__keyevent@0__:{event}");
+            self.pubsub.publish(&BulkString::encode(&channel), key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flags_round_trip() {
+        let notifier = Notifier::default();
+        notifier.set_flags("KEA").unwrap();
+        assert_eq!(notifier.flags(), "AKE");
+        notifier.set_flags("Ex$").unwrap();
+        assert_eq!(notifier.flags(), "$xE");
+        assert!(notifier.set_flags("Kq").is_err());
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The `save` rules Redis uses when none are configured
+pub const DEFAULT_SAVE_RULES: &str = "3600 1 300 100 60 10000";
+
+/// Snapshot after `seconds` have passed if at least `changes` writes happened since the last save
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SaveRule {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+/// RDB persistence bookkeeping: the `save` rules, the writes since the last successful save and
+/// the state of background saves
+#[derive(Debug)]
+pub struct Persistence {
+    rules: Mutex<Vec<SaveRule>>,
+    dirty: AtomicU64,
+    /// unix time in seconds of the last successful save
+    last_save: AtomicU64,
+    last_bgsave_ok: AtomicBool,
+    bgsave_in_progress: AtomicBool,
+}
+
+impl Default for Persistence {
+    fn default() -> Self {
+        Self {
+            rules: Mutex::new(parse_rules(DEFAULT_SAVE_RULES).expect("valid default save rules")),
+            dirty: AtomicU64::new(0),
+            last_save: AtomicU64::new(unix_time()),
+            last_bgsave_ok: AtomicBool::new(true),
+            bgsave_in_progress: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Persistence {
+    /// Parses a `save` config value such as `3600 1 300 100`, an empty value disables snapshots
+    pub fn set_rules(&self, config: &str) -> anyhow::Result<()> {
+        *self.rules.lock().unwrap() = parse_rules(config)?;
+        Ok(())
+    }
+
+    pub fn rules(&self) -> String {
+        self.rules
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|rule| format!("{} {}", rule.seconds, rule.changes))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub fn mark_dirty(&self) {
+        self.dirty.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dirty(&self) -> u64 {
+        self.dirty.load(Ordering::Relaxed)
+    }
+
+    pub fn last_save(&self) -> u64 {
+        self.last_save.load(Ordering::Relaxed)
+    }
+
+    pub fn last_bgsave_ok(&self) -> bool {
+        self.last_bgsave_ok.load(Ordering::Relaxed)
+    }
+
+    pub fn bgsave_in_progress(&self) -> bool {
+        self.bgsave_in_progress.load(Ordering::Relaxed)
+    }
+
+    /// Claims the background save slot, returning `false` if a save is already running
+    pub fn start_bgsave(&self) -> bool {
+        self.bgsave_in_progress
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Records the outcome of a save of the snapshot taken when `dirty` writes were pending.
+    /// Writes that happened while saving stay pending for the next save.
+    pub fn finish_save(&self, dirty: u64, ok: bool) {
+        if ok {
+            self.dirty.fetch_sub(dirty, Ordering::Relaxed);
+            self.last_save.store(unix_time(), Ordering::Relaxed);
+        }
+    }
+
+    pub fn finish_bgsave(&self, dirty: u64, ok: bool) {
+        self.finish_save(dirty, ok);
+        self.last_bgsave_ok.store(ok, Ordering::Relaxed);
+        self.bgsave_in_progress.store(false, Ordering::Release);
+    }
+
+    /// Whether one of the `save` rules is met and no background save is running
+    pub fn should_save(&self) -> bool {
+        if self.bgsave_in_progress() {
+            return false;
+        }
+        let elapsed = unix_time().saturating_sub(self.last_save());
+        let dirty = self.dirty();
+        self.rules
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|rule| elapsed >= rule.seconds && dirty >= rule.changes)
+    }
+}
+
+fn parse_rules(config: &str) -> anyhow::Result<Vec<SaveRule>> {
+    let values = config
+        .split_whitespace()
+        .map(|v| v.parse::<u64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| anyhow::anyhow!("Invalid save parameters"))?;
+    anyhow::ensure!(values.len().is_multiple_of(2), "Invalid save parameters");
+    Ok(values
+        .chunks(2)
+        .map(|pair| SaveRule {
+            seconds: pair[0],
+            changes: pair[1],
+        })
+        .collect())
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_rules() {
+        let persistence = Persistence::default();
+        assert_eq!(persistence.rules(), DEFAULT_SAVE_RULES);
+        assert!(!persistence.should_save());
+
+        persistence.set_rules("0 2").unwrap();
+        persistence.mark_dirty();
+        assert!(!persistence.should_save());
+        persistence.mark_dirty();
+        assert!(persistence.should_save());
+
+        persistence.finish_save(1, true);
+        assert_eq!(persistence.dirty(), 1);
+        assert!(persistence.set_rules("10").is_err());
+        persistence.set_rules("").unwrap();
+        assert!(!persistence.should_save());
+    }
+}
@@ -0,0 +1,341 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use dashmap::{mapref::one::Ref, DashMap};
+use indexmap::IndexSet;
+use tokio::sync::{mpsc, Notify};
+
+use crate::{cluster::key_hash_slot, glob::glob_match, resp::bulk_string::BulkString};
+
+pub type ClientId = u64;
+
+/// Handle used to push messages to a subscribed connection through its writer.
+///
+/// The writer queue of a connection is bounded and acts as its output buffer limit: a subscriber
+/// that does not keep up is disconnected instead of blocking the publisher.
+#[derive(Debug, Clone)]
+pub struct Subscriber {
+    id: ClientId,
+    tx: mpsc::Sender<Vec<u8>>,
+    disconnect: Arc<Notify>,
+}
+
+impl Subscriber {
+    /// Queues a message without waiting, returning `false` if the output buffer is full or the
+    /// connection is gone
+    fn push(&self, frame: &str) -> bool {
+        self.tx.try_send(frame.as_bytes().to_vec()).is_ok()
+    }
+}
+
+type Subscribers = HashMap<ClientId, Subscriber>;
+
+/// Channel, pattern and shard channel subscriber registries shared by all connections
+#[derive(Debug, Default)]
+pub struct PubSub {
+    next_id: AtomicU64,
+    channels: DashMap<BulkString, Subscribers>,
+    patterns: DashMap<BulkString, Subscribers>,
+    /// shard channels grouped by the hash slot they belong to
+    shard_channels: DashMap<u16, DashMap<BulkString, Subscribers>>,
+}
+
+impl PubSub {
+    pub fn publish(&self, channel: &BulkString, message: &BulkString) -> usize {
+        let mut receivers = 0;
+        let mut slow = Vec::new();
+        if let Some(subscribers) = self.channels.get(channel) {
+            let frame = format!(
+                "*3\r\n$7\r\nmessage\r\n{}{}",
+                channel.decode(),
+                message.decode()
+            );
+            for subscriber in subscribers.values() {
+                match subscriber.push(&frame) {
+                    true => receivers += 1,
+                    false => slow.push(subscriber.clone()),
+                }
+            }
+        }
+        for entry in self.patterns.iter() {
+            if !glob_match(entry.key().data.as_bytes(), channel.data.as_bytes()) {
+                continue;
+            }
+            let frame = format!(
+                "*4\r\n$8\r\npmessage\r\n{}{}{}",
+                entry.key().decode(),
+                channel.decode(),
+                message.decode()
+            );
+            for subscriber in entry.value().values() {
+                match subscriber.push(&frame) {
+                    true => receivers += 1,
+                    false => slow.push(subscriber.clone()),
+                }
+            }
+        }
+        for subscriber in slow {
+            self.disconnect(&subscriber);
+        }
+        receivers
+    }
+
+    /// Publishes to the subscribers of a shard channel only
+    pub fn spublish(&self, channel: &BulkString, message: &BulkString) -> usize {
+        let mut receivers = 0;
+        let mut slow = Vec::new();
+        let slot = key_hash_slot(channel.data.as_bytes());
+        if let Some(subscribers) = self.shard_channels.get(&slot).and_then(|shard| {
+            shard
+                .get(channel)
+                .map(|s| s.values().cloned().collect::<Vec<_>>())
+        }) {
+            let frame = format!(
+                "*3\r\n$8\r\nsmessage\r\n{}{}",
+                channel.decode(),
+                message.decode()
+            );
+            for subscriber in subscribers {
+                match subscriber.push(&frame) {
+                    true => receivers += 1,
+                    false => slow.push(subscriber),
+                }
+            }
+        }
+        for subscriber in slow {
+            self.disconnect(&subscriber);
+        }
+        receivers
+    }
+
+    /// Active shard channels, optionally filtered by a glob-style pattern
+    pub fn shard_channels(&self, pattern: Option<&BulkString>) -> Vec<BulkString> {
+        self.shard_channels
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .iter()
+                    .map(|entry| entry.key().clone())
+                    .collect::<Vec<_>>()
+            })
+            .filter(|channel| {
+                pattern.is_none_or(|p| glob_match(p.data.as_bytes(), channel.data.as_bytes()))
+            })
+            .collect()
+    }
+
+    pub fn shard_numsub(&self, channel: &BulkString) -> usize {
+        let slot = key_hash_slot(channel.data.as_bytes());
+        self.shard_channels
+            .get(&slot)
+            .and_then(|shard| shard.get(channel).map(|s| s.len()))
+            .unwrap_or(0)
+    }
+
+    fn shard(&self, channel: &BulkString) -> Ref<'_, u16, DashMap<BulkString, Subscribers>> {
+        let slot = key_hash_slot(channel.data.as_bytes());
+        self.shard_channels.entry(slot).or_default().downgrade()
+    }
+
+    /// Active channels, optionally filtered by a glob-style pattern
+    pub fn channels(&self, pattern: Option<&BulkString>) -> Vec<BulkString> {
+        self.channels
+            .iter()
+            .filter(|entry| {
+                pattern.is_none_or(|p| glob_match(p.data.as_bytes(), entry.key().data.as_bytes()))
+            })
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+
+    pub fn numsub(&self, channel: &BulkString) -> usize {
+        self.channels.get(channel).map_or(0, |s| s.len())
+    }
+
+    pub fn numpat(&self) -> usize {
+        self.patterns.len()
+    }
+
+    fn add(registry: &DashMap<BulkString, Subscribers>, name: &BulkString, s: &Subscriber) {
+        registry
+            .entry(name.clone())
+            .or_default()
+            .insert(s.id, s.clone());
+    }
+
+    fn remove(registry: &DashMap<BulkString, Subscribers>, name: &BulkString, id: ClientId) {
+        registry.remove_if_mut(name, |_, subscribers| {
+            subscribers.remove(&id);
+            subscribers.is_empty()
+        });
+    }
+
+    /// Drops a subscriber that exceeded its output buffer from every registry and closes its
+    /// connection
+    fn disconnect(&self, subscriber: &Subscriber) {
+        let unregister = |registry: &DashMap<BulkString, Subscribers>| {
+            registry.retain(|_, subscribers| {
+                subscribers.remove(&subscriber.id);
+                !subscribers.is_empty()
+            });
+        };
+        unregister(&self.channels);
+        unregister(&self.patterns);
+        for shard in self.shard_channels.iter() {
+            unregister(shard.value());
+        }
+        subscriber.disconnect.notify_one();
+    }
+}
+
+/// The channels and patterns a single connection is subscribed to
+#[derive(Debug)]
+pub struct Subscriptions {
+    pubsub: Arc<PubSub>,
+    subscriber: Subscriber,
+    channels: IndexSet<BulkString>,
+    patterns: IndexSet<BulkString>,
+    shard_channels: IndexSet<BulkString>,
+}
+
+impl Subscriptions {
+    pub fn new(pubsub: Arc<PubSub>, tx: mpsc::Sender<Vec<u8>>) -> Self {
+        let id = pubsub.next_id.fetch_add(1, Ordering::Relaxed);
+        Self {
+            pubsub,
+            subscriber: Subscriber {
+                id,
+                tx,
+                disconnect: Arc::new(Notify::new()),
+            },
+            channels: IndexSet::new(),
+            patterns: IndexSet::new(),
+            shard_channels: IndexSet::new(),
+        }
+    }
+
+    /// Notified when the connection has to be closed because it fell behind on its messages
+    pub fn disconnected(&self) -> Arc<Notify> {
+        self.subscriber.disconnect.clone()
+    }
+
+    /// Number of subscriptions, the connection is in subscriber mode while this is not zero
+    pub fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len() + self.shard_channels.len()
+    }
+
+    /// Subscribes to the channels. Each confirmation is queued on the writer before registering,
+    /// so that no published message can overtake it.
+    pub async fn subscribe(&mut self, channels: &[BulkString]) {
+        for channel in channels {
+            let is_new = self.channels.insert(channel.clone());
+            self.reply("subscribe", Some(channel)).await;
+            if is_new {
+                PubSub::add(&self.pubsub.channels, channel, &self.subscriber);
+            }
+        }
+    }
+
+    pub async fn psubscribe(&mut self, patterns: &[BulkString]) {
+        for pattern in patterns {
+            let is_new = self.patterns.insert(pattern.clone());
+            self.reply("psubscribe", Some(pattern)).await;
+            if is_new {
+                PubSub::add(&self.pubsub.patterns, pattern, &self.subscriber);
+            }
+        }
+    }
+
+    /// Unsubscribes from the given channels, or from all of them when none are given
+    pub async fn unsubscribe(&mut self, channels: &[BulkString]) {
+        let channels = match channels.is_empty() {
+            true => self.channels.iter().cloned().collect(),
+            false => channels.to_vec(),
+        };
+        if channels.is_empty() {
+            self.reply("unsubscribe", None).await;
+        }
+        for channel in channels {
+            if self.channels.shift_remove(&channel) {
+                PubSub::remove(&self.pubsub.channels, &channel, self.subscriber.id);
+            }
+            self.reply("unsubscribe", Some(&channel)).await;
+        }
+    }
+
+    /// Unsubscribes from the given patterns, or from all of them when none are given
+    pub async fn punsubscribe(&mut self, patterns: &[BulkString]) {
+        let patterns = match patterns.is_empty() {
+            true => self.patterns.iter().cloned().collect(),
+            false => patterns.to_vec(),
+        };
+        if patterns.is_empty() {
+            self.reply("punsubscribe", None).await;
+        }
+        for pattern in patterns {
+            if self.patterns.shift_remove(&pattern) {
+                PubSub::remove(&self.pubsub.patterns, &pattern, self.subscriber.id);
+            }
+            self.reply("punsubscribe", Some(&pattern)).await;
+        }
+    }
+
+    pub async fn ssubscribe(&mut self, channels: &[BulkString]) {
+        for channel in channels {
+            let is_new = self.shard_channels.insert(channel.clone());
+            self.reply("ssubscribe", Some(channel)).await;
+            if is_new {
+                PubSub::add(&self.pubsub.shard(channel), channel, &self.subscriber);
+            }
+        }
+    }
+
+    /// Unsubscribes from the given shard channels, or from all of them when none are given
+    pub async fn sunsubscribe(&mut self, channels: &[BulkString]) {
+        let channels = match channels.is_empty() {
+            true => self.shard_channels.iter().cloned().collect(),
+            false => channels.to_vec(),
+        };
+        if channels.is_empty() {
+            self.reply("sunsubscribe", None).await;
+        }
+        for channel in channels {
+            if self.shard_channels.shift_remove(&channel) {
+                PubSub::remove(&self.pubsub.shard(&channel), &channel, self.subscriber.id);
+            }
+            self.reply("sunsubscribe", Some(&channel)).await;
+        }
+    }
+
+    /// Removes every subscription of the connection, used when it disconnects
+    pub fn clear(&mut self) {
+        for channel in self.channels.drain(..) {
+            PubSub::remove(&self.pubsub.channels, &channel, self.subscriber.id);
+        }
+        for pattern in self.patterns.drain(..) {
+            PubSub::remove(&self.pubsub.patterns, &pattern, self.subscriber.id);
+        }
+        for channel in self.shard_channels.drain(..) {
+            PubSub::remove(&self.pubsub.shard(&channel), &channel, self.subscriber.id);
+        }
+    }
+
+    async fn reply(&self, kind: &str, name: Option<&BulkString>) {
+        // shard channels are counted separately from channels and patterns
+        let count = match kind {
+            "ssubscribe" | "sunsubscribe" => self.shard_channels.len(),
+            _ => self.channels.len() + self.patterns.len(),
+        };
+        let frame = format!(
+            "*3\r\n{}{}:{count}\r\n",
+            BulkString::encode(kind).decode(),
+            name.map_or("$-1\r\n".to_owned(), |n| n.decode()),
+        );
+        let _ = self.subscriber.tx.send(frame.into_bytes()).await;
+    }
+}
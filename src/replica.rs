@@ -1,115 +1,680 @@
-use regex::Regex;
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{tcp::OwnedWriteHalf, TcpStream},
-    sync::{broadcast, mpsc},
+    sync::{futures::Notified, mpsc, Notify},
+    time::{interval, sleep, timeout, timeout_at},
 };
 
 use crate::{
-    resp::RedisData,
+    backlog::Backlog,
+    db::Database,
+    resp::{frame, RedisData},
     state::{MasterConfig, State},
+    transaction::Transaction,
 };
 
+/// Length of the delimiter that ends a snapshot sent in the `$EOF:` format
+const EOF_MARK_LEN: usize = 40;
+
+pub const DEFAULT_BACKLOG_SIZE: usize = 1024 * 1024;
+
+pub const DEFAULT_BACKLOG_TTL: u64 = 3600;
+
+pub const DEFAULT_REPL_TIMEOUT: u64 = 60;
+
+pub const DEFAULT_PING_PERIOD: u64 = 10;
+
+pub const DEFAULT_MIN_REPLICAS_MAX_LAG: u64 = 10;
+
+/// Delay before the first attempt to reconnect to the primary, doubled after each failure
+const RETRY_MIN: Duration = Duration::from_millis(100);
+
+const RETRY_MAX: Duration = Duration::from_secs(5);
+
+/// How often a replica acknowledges the offset it processed, which tells the primary it is
+/// alive and how far behind it is
+const ACK_PERIOD: Duration = Duration::from_secs(1);
+
+/// The connection of a replica to its primary. Replies and the snapshot are read from the same
+/// buffer as the command stream, so that the bytes following them in a read are kept.
+struct MasterLink {
+    stream: TcpStream,
+    pending: Vec<u8>,
+    state: State,
+    /// when data was last received, which `repl-timeout` counts from
+    last_io: Instant,
+}
+
+impl MasterLink {
+    /// Reads more data from the primary into the buffer, failing when the primary stays silent
+    /// for `repl-timeout`, which it doesn't do while it is alive as it pings its replicas
+    async fn fill(&mut self) -> anyhow::Result<()> {
+        let mut buf = [0; 16 * 1024];
+        let repl_timeout = self.state.repl_timeout();
+        let deadline = (self.last_io + repl_timeout).into();
+        let n = timeout_at(deadline, self.stream.read(&mut buf))
+            .await
+            .map_err(|_| {
+                anyhow::anyhow!(
+                    "no data from the primary for {} seconds",
+                    repl_timeout.as_secs()
+                )
+            })??;
+        anyhow::ensure!(n > 0, "connection closed by the primary");
+        self.last_io = Instant::now();
+        self.state.link_io();
+        self.pending.extend_from_slice(&buf[..n]);
+        Ok(())
+    }
+
+    async fn read_line(&mut self) -> anyhow::Result<String> {
+        loop {
+            if let Some(end) = self.pending.windows(2).position(|w| w == b"\r\n") {
+                let line: Vec<u8> = self.pending.drain(..end + 2).take(end).collect();
+                return Ok(String::from_utf8_lossy(&line).to_string());
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// Sends a command and reads its single line reply, failing on an error reply
+    async fn command(&mut self, args: &[&str]) -> anyhow::Result<String> {
+        self.stream.write_all(&frame::encode(args)).await?;
+        let reply = self.read_line().await?;
+        match reply.strip_prefix('-') {
+            Some(e) => anyhow::bail!("{} failed: {e}", args.join(" ")),
+            None => Ok(reply),
+        }
+    }
+
+    /// Reads the snapshot following `+FULLRESYNC`, sent either with its length or between
+    /// `$EOF:<mark>` and the same mark
+    async fn read_rdb(&mut self) -> anyhow::Result<Vec<u8>> {
+        let header = loop {
+            // the primary sends newlines to keep the link alive while it prepares the snapshot
+            let newlines = self.pending.iter().take_while(|&&b| b == b'\n').count();
+            self.pending.drain(..newlines);
+            if self.pending.is_empty() {
+                self.fill().await?;
+                continue;
+            }
+            let line = self.read_line().await?;
+            if !line.is_empty() {
+                break line;
+            }
+        };
+        let header = header
+            .strip_prefix('$')
+            .with_context(|| format!("unexpected reply instead of the snapshot: {header}"))?;
+        match header.strip_prefix("EOF:") {
+            Some(mark) => {
+                anyhow::ensure!(mark.len() == EOF_MARK_LEN, "invalid EOF mark {mark}");
+                let mark = mark.as_bytes();
+                loop {
+                    if let Some(end) = self.pending.windows(mark.len()).position(|w| w == mark) {
+                        let rdb = self.pending.drain(..end).collect();
+                        self.pending.drain(..mark.len());
+                        return Ok(rdb);
+                    }
+                    self.fill().await?;
+                }
+            }
+            None => {
+                let len: usize = header.parse().context("invalid snapshot length")?;
+                while self.pending.len() < len {
+                    self.fill().await?;
+                }
+                Ok(self.pending.drain(..len).collect())
+            }
+        }
+    }
+}
+
+/// Keeps a replica linked to its primary, reconnecting with an exponential backoff whenever
+/// the link is lost, until the role set in `epoch` changes
+pub async fn replication_link(state: State, listening_port: u16, epoch: u64) {
+    let link = async {
+        let mut retry = RETRY_MIN;
+        while let Some(primary) = state.primary() {
+            let connection =
+                initiate_replica_connection(state.clone(), primary, listening_port, epoch);
+            if let Err(e) = connection.await {
+                eprintln!("Replication with the primary failed: {e:#}");
+            }
+            if state.link_down() {
+                retry = RETRY_MIN;
+            }
+            println!("Reconnecting to the primary in {}ms", retry.as_millis());
+            sleep(retry).await;
+            retry = (retry * 2).min(RETRY_MAX);
+        }
+    };
+    tokio::select! {
+        _ = link => {}
+        _ = state.role_changed(epoch) => println!("Replication link of epoch {epoch} stopped"),
+    }
+}
+
 /// The replica initiates a conection with the primary and starts the replication process
 ///
 /// 1. PING (expecting +PONG\r\n back)
 /// 2. REPLCONF listening-port <PORT> (expecting +OK\r\n back)
 /// 3. REPLCONF capa eof capa psync2 (expecting +OK\r\n back)
-/// 4. PSYNC ? -1 (expecting +FULLRESYNC <REPL_ID> 0\r\n back)
-/// 5. Listen to redis_data from the primary
+/// 4. PSYNC <REPL_ID> <OFFSET> when continuing the stream of an earlier link, PSYNC ? -1
+///    otherwise (expecting +CONTINUE or +FULLRESYNC <REPL_ID> <OFFSET>\r\n back)
+/// 5. After a full resync, load the snapshot that follows in place of the dataset
+/// 6. Listen to redis_data from the primary
 pub async fn initiate_replica_connection(
     mut state: State,
     config: MasterConfig,
+    listening_port: u16,
+    epoch: u64,
 ) -> anyhow::Result<()> {
     let address = format!("{}:{}", config.host, config.port);
-    let mut stream = TcpStream::connect(address).await.unwrap();
-    stream.write_all(b"*1\r\n$4\r\nping\r\n").await.unwrap();
-    let mut buf = [0; 1024];
-
-    match stream.read(&mut buf).await {
-        Ok(n) => {
-            if n == 0 {
-                return Ok(());
-            }
-
-            let request = String::from_utf8_lossy(&buf[..n]);
-            println!("replica got request {request}");
-            let replconf1 = b"*3\r\n$8\r\nREPLCONF\r\n$14\r\nlistening-port\r\n$4\r\n6380\r\n";
-            stream.write_all(replconf1).await.unwrap();
-            let replconf2 = b"*3\r\n$8\r\nREPLCONF\r\n$4\r\ncapa\r\n$6\r\npsync2\r\n";
-            stream.write_all(replconf2).await.unwrap();
+    let stream = timeout(state.repl_timeout(), TcpStream::connect(&address))
+        .await
+        .map_err(|_| anyhow::anyhow!("timeout connecting to the primary at {address}"))?
+        .with_context(|| format!("connect to the primary at {address}"))?;
+    let mut link = MasterLink {
+        stream,
+        pending: Vec::new(),
+        state: state.clone(),
+        last_io: Instant::now(),
+    };
+    link.command(&["PING"]).await?;
+    link.command(&["REPLCONF", "listening-port", &listening_port.to_string()])
+        .await?;
+    link.command(&["REPLCONF", "capa", "eof", "capa", "psync2"])
+        .await?;
+    let (replid, offset) = state.psync_args();
+    state.link_syncing();
+    let reply = link.command(&["PSYNC", &replid, &offset]).await?;
+    match reply.split_whitespace().collect::<Vec<_>>()[..] {
+        ["+CONTINUE", ref replid @ ..] => {
+            println!("Successful partial resynchronization with primary");
+            state.continue_replication(replid.first().copied());
         }
-        Err(_) => todo!(),
-    }
-    match stream.read(&mut buf).await {
-        Ok(n) => {
-            if n == 0 {
-                return Ok(());
-            }
-            let psync_initial = b"*3\r\n$5\r\nPSYNC\r\n$1\r\n?\r\n$2\r\n-1\r\n";
-            stream.write_all(psync_initial).await.unwrap();
+        ["+FULLRESYNC", replid, offset] => {
+            let offset = offset.parse()?;
+            println!("Full resync from primary: {replid}:{offset}");
+            let rdb = link.read_rdb().await?;
+            println!(
+                "MASTER <-> REPLICA sync: receiving {} bytes from primary",
+                rdb.len()
+            );
+            let db = tokio::task::spawn_blocking(move || {
+                let db = Database::new(None);
+                db.load_rdb(rdb.as_slice()).map(|()| db)
+            })
+            .await?
+            .context("load the snapshot from the primary")?;
+            state.replace_dataset(db, replid.to_owned(), offset);
+            println!("MASTER <-> REPLICA sync: finished with success");
         }
-
-        Err(_) => todo!(),
+        _ => anyhow::bail!("unexpected reply to PSYNC: {reply}"),
     }
-    println!("initialization complete");
+    state.link_up();
 
+    let mut transaction = Transaction::default();
+    // what the primary sent of the transaction in progress
+    let mut transaction_stream = Vec::new();
+    let mut ack_interval = interval(ACK_PERIOD);
     loop {
-        match stream.read(&mut buf).await {
-            Ok(n) => {
-                if n == 0 {
-                    // connection closed
-                    return Ok(());
-                }
-                let request = String::from_utf8_lossy(&buf[..n]);
-                let re = Regex::new(r"\*\d+\r\n").unwrap();
-                for request in re.split(&request) {
-                    if request.is_empty() {
-                        continue;
+        // a read can end in the middle of a command or hold several of them
+        while let Some(len) = frame::command_len(&link.pending)? {
+            // what is left came from a primary this instance no longer follows
+            if state.epoch() != epoch {
+                return Ok(());
+            }
+            let request: Vec<u8> = link.pending.drain(..len).collect();
+            let redis_data = match RedisData::parse_bytes(&request) {
+                Ok(redis_data) => redis_data,
+                Err(e) => {
+                    eprintln!("failed to parse request {request:?}; err = {e:?}");
+                    match transaction.is_active() {
+                        true => transaction_stream.extend(&request),
+                        false => state.replicate(&request),
                     }
-                    match RedisData::parse(request) {
-                        Ok(redis_data) => {
-                            let response = state
-                                .handle_response(&redis_data)
-                                .expect("failed to generate response");
-                            // after handshake is complete, only the replconf provides responses to
-                            // primary
-                            match redis_data {
-                                RedisData::ReplConf(_, _) => {
-                                    stream.write_all(response.as_bytes()).await
-                                }
-                                _ => Ok(()),
-                            }
-                            .expect("failed to send response");
-                            // TODO: this should be optimised
-                            let total_req = format!(
-                                "*{}\r\n{}",
-                                request.split('$').collect::<Vec<&str>>().len(),
-                                request
-                            );
-                            let n = total_req.as_bytes().len();
-                            state.increment_offset(n);
-                        }
-                        Err(e) => {
-                            eprintln!("failed to parse request {request:?}; err = {e:?}");
+                    continue;
+                }
+            };
+            let is_replconf = matches!(redis_data, RedisData::ReplConf(_, _));
+            // the stream goes on to the backlog and the replicas of this replica as it was
+            // received, so that they share the replication offsets of the primary. Transactions
+            // from the primary are applied as a single block, and forwarded along with it.
+            let response = match redis_data {
+                RedisData::Multi => {
+                    transaction_stream.extend(&request);
+                    transaction.begin()
+                }
+                RedisData::Exec => {
+                    transaction_stream.extend(&request);
+                    let stream = std::mem::take(&mut transaction_stream);
+                    match transaction.take() {
+                        Ok(queued) => state.exec_replicated(queued, &stream),
+                        Err(response) => {
+                            state.replicate(&stream);
+                            response
                         }
                     }
                 }
+                RedisData::Discard => {
+                    transaction_stream.extend(&request);
+                    state.replicate(&std::mem::take(&mut transaction_stream));
+                    transaction.discard()
+                }
+                _ if transaction.is_active() => {
+                    transaction_stream.extend(&request);
+                    transaction.queue(redis_data, request)
+                }
+                _ => state
+                    .handle_replicated(&redis_data, &request)
+                    .unwrap_or_else(|e| format!("-ERR {e}\r\n")),
+            };
+            // after handshake is complete, only the replconf provides responses to primary
+            if is_replconf {
+                link.stream.write_all(response.as_bytes()).await?;
             }
-            Err(e) => {
-                eprintln!("failed to read from socket; err = {:?}", e);
-                return Ok(());
+        }
+        // reading is cancelled by the ticks without losing data, and `repl-timeout` still
+        // counts from the last data received
+        tokio::select! {
+            filled = link.fill() => filled?,
+            _ = ack_interval.tick() => {
+                let offset = state.offset().to_string();
+                let aof_offset = state.aof_fsynced_offset().to_string();
+                let ack = frame::encode(&["REPLCONF", "ACK", &offset, "FACK", &aof_offset]);
+                link.stream.write_all(&ack).await?;
+            }
+        }
+    }
+}
+
+/// The replicas of this instance. Each one has its own queue of writes, which holds what is
+/// propagated while its initial snapshot is produced and sent.
+#[derive(Debug)]
+pub struct Replicas {
+    replicas: Mutex<Vec<Replica>>,
+    /// whether full resyncs stream the snapshot instead of writing it to the RDB file first
+    diskless_sync: AtomicBool,
+    /// created with the first replica, and dropped by `expire_backlog` once none is left
+    backlog: Mutex<Option<Backlog>>,
+    backlog_size: AtomicUsize,
+    /// seconds without replicas after which the backlog is dropped, 0 keeps it forever
+    backlog_ttl: AtomicU64,
+    /// when the last replica disconnected
+    no_replicas_since: Mutex<Option<Instant>>,
+    /// seconds between the PINGs sent to the replicas
+    ping_period: AtomicU64,
+    last_ping: Mutex<Instant>,
+    /// notified whenever a replica acknowledges an offset
+    acked: Notify,
+    /// the number of good replicas below which writes are refused, 0 to accept them anyway
+    min_replicas_to_write: AtomicUsize,
+    /// seconds since its last acknowledgement up to which a replica counts as good
+    min_replicas_max_lag: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Replica {
+    addr: SocketAddr,
+    /// the port announced with `REPLCONF listening-port`
+    listening_port: u16,
+    /// the last offset the replica acknowledged with `REPLCONF ACK`
+    ack_offset: usize,
+    /// the last offset the replica reported to have on disk with `REPLCONF ACK ... FACK`
+    aof_offset: usize,
+    last_ack: Instant,
+    /// whether the replica received its initial snapshot
+    online: bool,
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl Default for Replicas {
+    fn default() -> Self {
+        Self {
+            replicas: Mutex::new(Vec::new()),
+            diskless_sync: AtomicBool::new(false),
+            backlog: Mutex::new(None),
+            backlog_size: AtomicUsize::new(DEFAULT_BACKLOG_SIZE),
+            backlog_ttl: AtomicU64::new(DEFAULT_BACKLOG_TTL),
+            no_replicas_since: Mutex::new(Some(Instant::now())),
+            ping_period: AtomicU64::new(DEFAULT_PING_PERIOD),
+            last_ping: Mutex::new(Instant::now()),
+            acked: Notify::new(),
+            min_replicas_to_write: AtomicUsize::new(0),
+            min_replicas_max_lag: AtomicU64::new(DEFAULT_MIN_REPLICAS_MAX_LAG),
+        }
+    }
+}
+
+impl Replicas {
+    /// Adds a replica that is in sync at `offset` once it received the snapshot for it,
+    /// returning the queue of the writes to send it
+    pub fn register(
+        &self,
+        addr: SocketAddr,
+        listening_port: u16,
+        offset: usize,
+    ) -> mpsc::UnboundedReceiver<Vec<u8>> {
+        self.create_backlog(offset);
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut replicas = self.replicas.lock().unwrap();
+        replicas.retain(|replica| replica.addr != addr);
+        replicas.push(Replica {
+            addr,
+            listening_port,
+            ack_offset: offset,
+            aof_offset: 0,
+            last_ack: Instant::now(),
+            online: false,
+            tx,
+        });
+        *self.no_replicas_since.lock().unwrap() = None;
+        rx
+    }
+
+    /// Adds a replica that continues from `offset` when the backlog still holds everything
+    /// since, returning what it missed and the queue of the writes to send it
+    pub fn register_from(
+        &self,
+        addr: SocketAddr,
+        listening_port: u16,
+        offset: usize,
+    ) -> Option<(Vec<u8>, mpsc::UnboundedReceiver<Vec<u8>>)> {
+        let missed = self.backlog.lock().unwrap().as_ref()?.since(offset)?;
+        let rx = self.register(addr, listening_port, offset);
+        self.set_online(addr);
+        Some((missed, rx))
+    }
+
+    /// Records that a replica received its initial snapshot
+    pub fn set_online(&self, addr: SocketAddr) {
+        let mut replicas = self.replicas.lock().unwrap();
+        if let Some(replica) = replicas.iter_mut().find(|replica| replica.addr == addr) {
+            replica.online = true;
+        }
+    }
+
+    /// Forgets a replica whose connection was closed
+    pub fn unregister(&self, addr: SocketAddr) {
+        self.replicas
+            .lock()
+            .unwrap()
+            .retain(|replica| replica.addr != addr);
+        // updates when the last replica left
+        self.len();
+    }
+
+    /// Drops every replica, closing their connections so that they sync again, after the
+    /// history they followed changed
+    pub fn disconnect_all(&self) {
+        self.replicas.lock().unwrap().clear();
+        self.len();
+    }
+
+    /// Records the offset a replica acknowledged, and the one its AOF holds on disk if it
+    /// reported it
+    pub fn ack(&self, addr: SocketAddr, offset: usize, aof_offset: Option<usize>) {
+        let mut replicas = self.replicas.lock().unwrap();
+        if let Some(replica) = replicas.iter_mut().find(|replica| replica.addr == addr) {
+            replica.ack_offset = offset;
+            replica.aof_offset = aof_offset.unwrap_or(replica.aof_offset);
+            replica.last_ack = Instant::now();
+        }
+        self.acked.notify_waiters();
+    }
+
+    /// Records the acknowledgement a replica sent with `REPLCONF ACK <offset> [FACK <aof
+    /// offset>]`, returning the offset
+    pub fn ack_request(&self, addr: SocketAddr, request: &[u8]) -> anyhow::Result<usize> {
+        let args = frame::args(request)?;
+        let offset = args.get(2).context("missing offset")?;
+        let offset = String::from_utf8_lossy(offset).parse()?;
+        let aof_offset = match args.get(3..5) {
+            Some([fack, aof_offset]) if fack.eq_ignore_ascii_case(b"fack") => {
+                Some(String::from_utf8_lossy(aof_offset).parse()?)
             }
+            _ => None,
         };
+        self.ack(addr, offset, aof_offset);
+        Ok(offset)
+    }
+
+    /// The number of replicas that acknowledged `offset` or a later one
+    pub fn count_acked(&self, offset: usize) -> usize {
+        let replicas = self.replicas.lock().unwrap();
+        replicas
+            .iter()
+            .filter(|replica| !replica.tx.is_closed() && replica.ack_offset >= offset)
+            .count()
+    }
+
+    /// The number of replicas whose AOF holds `offset` or a later one on disk
+    pub fn count_fsynced(&self, offset: usize) -> usize {
+        let replicas = self.replicas.lock().unwrap();
+        replicas
+            .iter()
+            .filter(|replica| !replica.tx.is_closed() && replica.aof_offset >= offset)
+            .count()
+    }
+
+    /// Resolves on the next acknowledgement of a replica. Like with `Notify`, the future
+    /// catches acknowledgements from the moment it is created.
+    pub fn acked(&self) -> Notified<'_> {
+        self.acked.notified()
+    }
+
+    /// The address each replica listens on, with the last offset it acknowledged
+    pub fn list(&self) -> Vec<(SocketAddr, usize)> {
+        let replicas = self.replicas.lock().unwrap();
+        replicas
+            .iter()
+            .filter(|replica| !replica.tx.is_closed())
+            .map(|replica| {
+                let addr = SocketAddr::new(replica.addr.ip(), replica.listening_port);
+                (addr, replica.ack_offset)
+            })
+            .collect()
+    }
+
+    /// The number of replicas that are online and acknowledged an offset within
+    /// `min-replicas-max-lag` seconds
+    pub fn good_count(&self) -> usize {
+        let max_lag = Duration::from_secs(self.min_replicas_max_lag());
+        let replicas = self.replicas.lock().unwrap();
+        replicas
+            .iter()
+            .filter(|replica| {
+                !replica.tx.is_closed() && replica.online && replica.last_ack.elapsed() <= max_lag
+            })
+            .count()
+    }
+
+    /// Whether there are enough good replicas to accept writes, see `min-replicas-to-write`
+    pub fn enough_good(&self) -> bool {
+        let min = self.min_replicas_to_write();
+        min == 0 || self.good_count() >= min
+    }
+
+    /// The `connected_slaves` and `slave<n>` fields of `INFO replication`
+    pub fn info(&self) -> String {
+        let good = match self.min_replicas_to_write() {
+            0 => String::new(),
+            _ => format!("min_slaves_good_slaves:{}\r\n", self.good_count()),
+        };
+        let replicas = self.replicas.lock().unwrap();
+        let replicas: Vec<_> = replicas
+            .iter()
+            .filter(|replica| !replica.tx.is_closed())
+            .collect();
+        let mut info = format!("connected_slaves:{}\r\n{good}", replicas.len());
+        for (n, replica) in replicas.iter().enumerate() {
+            info.push_str(&format!(
+                "slave{n}:ip={},port={},state={},offset={},lag={}\r\n",
+                replica.addr.ip(),
+                replica.listening_port,
+                if replica.online {
+                    "online"
+                } else {
+                    "wait_bgsave"
+                },
+                replica.ack_offset,
+                replica.last_ack.elapsed().as_secs()
+            ));
+        }
+        info
+    }
+
+    /// Creates an empty backlog continuing from `offset`, unless there is one already
+    pub fn create_backlog(&self, offset: usize) {
+        let mut backlog = self.backlog.lock().unwrap();
+        if backlog.is_none() {
+            *backlog = Some(Backlog::new(self.backlog_size(), offset));
+        }
+    }
+
+    /// Replaces the backlog with an empty one continuing from `offset`, after the stream was
+    /// replaced by the one of a new primary
+    pub fn reset_backlog(&self, offset: usize) {
+        *self.backlog.lock().unwrap() = Some(Backlog::new(self.backlog_size(), offset));
+    }
+
+    /// Drops the backlog once there were no replicas for `repl-backlog-ttl` seconds
+    pub fn expire_backlog(&self) {
+        let ttl = self.backlog_ttl.load(Ordering::Relaxed);
+        let expired = self
+            .no_replicas_since
+            .lock()
+            .unwrap()
+            .is_some_and(|since| ttl > 0 && since.elapsed() >= Duration::from_secs(ttl));
+        if expired && self.is_empty() {
+            *self.backlog.lock().unwrap() = None;
+        }
+    }
+
+    /// Appends data to the replication stream: the backlog and the queue of every replica,
+    /// forgetting the replicas that disconnected
+    pub fn send(&self, data: &[u8]) {
+        if let Some(backlog) = self.backlog.lock().unwrap().as_mut() {
+            backlog.feed(data);
+        }
+        self.replicas
+            .lock()
+            .unwrap()
+            .retain(|replica| replica.tx.send(data.to_vec()).is_ok());
+    }
+
+    pub fn len(&self) -> usize {
+        let mut replicas = self.replicas.lock().unwrap();
+        replicas.retain(|replica| !replica.tx.is_closed());
+        let mut no_replicas_since = self.no_replicas_since.lock().unwrap();
+        match replicas.is_empty() {
+            true => *no_replicas_since = no_replicas_since.or(Some(Instant::now())),
+            false => *no_replicas_since = None,
+        }
+        replicas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn diskless_sync(&self) -> bool {
+        self.diskless_sync.load(Ordering::Relaxed)
+    }
+
+    pub fn set_diskless_sync(&self, diskless_sync: bool) {
+        self.diskless_sync.store(diskless_sync, Ordering::Relaxed);
+    }
+
+    pub fn backlog_size(&self) -> usize {
+        self.backlog_size.load(Ordering::Relaxed)
+    }
+
+    pub fn set_backlog_size(&self, size: usize) {
+        self.backlog_size.store(size, Ordering::Relaxed);
+        if let Some(backlog) = self.backlog.lock().unwrap().as_mut() {
+            backlog.resize(size);
+        }
+    }
+
+    pub fn backlog_ttl(&self) -> u64 {
+        self.backlog_ttl.load(Ordering::Relaxed)
+    }
+
+    pub fn set_backlog_ttl(&self, ttl: u64) {
+        self.backlog_ttl.store(ttl, Ordering::Relaxed);
+    }
+
+    pub fn ping_period(&self) -> u64 {
+        self.ping_period.load(Ordering::Relaxed)
+    }
+
+    pub fn set_ping_period(&self, period: u64) {
+        self.ping_period.store(period, Ordering::Relaxed);
+    }
+
+    pub fn min_replicas_to_write(&self) -> usize {
+        self.min_replicas_to_write.load(Ordering::Relaxed)
+    }
+
+    pub fn set_min_replicas_to_write(&self, min: usize) {
+        self.min_replicas_to_write.store(min, Ordering::Relaxed);
+    }
+
+    pub fn min_replicas_max_lag(&self) -> u64 {
+        self.min_replicas_max_lag.load(Ordering::Relaxed)
+    }
+
+    pub fn set_min_replicas_max_lag(&self, lag: u64) {
+        self.min_replicas_max_lag.store(lag, Ordering::Relaxed);
+    }
+
+    /// Whether the replicas are due a PING, which is then counted as sent
+    pub fn ping_due(&self) -> bool {
+        let period = Duration::from_secs(self.ping_period());
+        let mut last_ping = self.last_ping.lock().unwrap();
+        if self.is_empty() || last_ping.elapsed() < period {
+            return false;
+        }
+        *last_ping = Instant::now();
+        true
+    }
+
+    /// The backlog fields of `INFO replication`
+    pub fn backlog_info(&self) -> String {
+        match self.backlog.lock().unwrap().as_ref() {
+            Some(backlog) => format!(
+                "repl_backlog_active:1\r\nrepl_backlog_size:{}\r\nrepl_backlog_first_byte_offset:{}\r\nrepl_backlog_histlen:{}\r\n",
+                backlog.size(),
+                backlog.first_byte_offset(),
+                backlog.histlen()
+            ),
+            None => format!(
+                "repl_backlog_active:0\r\nrepl_backlog_size:{}\r\nrepl_backlog_first_byte_offset:0\r\nrepl_backlog_histlen:0\r\n",
+                self.backlog_size()
+            ),
+        }
     }
 }
 
-/// Send `SET` requests from primary to replicas
+/// Sends the writes queued for a replica over its connection
 pub async fn send_write_to_replica(
-    mut rx: broadcast::Receiver<Vec<u8>>,
+    mut rx: mpsc::UnboundedReceiver<Vec<u8>>,
     client_tx: mpsc::Sender<Vec<u8>>,
 ) -> anyhow::Result<()> {
-    while let Ok(msg) = rx.recv().await {
+    while let Some(msg) = rx.recv().await {
         client_tx.send(msg).await?;
     }
     Ok(())
@@ -124,3 +689,179 @@ pub async fn send_write_to_client(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::resp::rdb::writer::RdbWriter;
+
+    /// A link to a primary, played by the other end of the returned connection
+    async fn master_link() -> (MasterLink, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (primary, _) = listener.accept().await.unwrap();
+        let link = MasterLink {
+            stream,
+            pending: Vec::new(),
+            state: State::default(),
+            last_io: Instant::now(),
+        };
+        (link, primary)
+    }
+
+    #[tokio::test]
+    async fn test_read_rdb() {
+        let rdb = b"REDIS0011\r\n$EOF:\xfa\xff".as_slice();
+        let ping = frame::encode(&["PING"]);
+        let mark = "m".repeat(EOF_MARK_LEN);
+        let header = format!("$EOF:{mark}\r\n");
+        let eof_payload = [header.as_bytes(), rdb, mark.as_bytes()].concat();
+        let len_payload = [format!("${}\r\n", rdb.len()).as_bytes(), rdb].concat();
+        for payload in [eof_payload, len_payload] {
+            let (mut link, mut primary) = master_link().await;
+            // keep alive newlines first, and the snapshot split over several reads
+            let (first, second) = payload.split_at(payload.len() - 3);
+            primary.write_all(b"\n\n").await.unwrap();
+            primary.write_all(first).await.unwrap();
+            let (read, ()) = tokio::join!(link.read_rdb(), async {
+                sleep(Duration::from_millis(50)).await;
+                primary.write_all(&[second, &ping].concat()).await.unwrap();
+            });
+            assert_eq!(read.unwrap(), rdb);
+            // the command stream that followed is kept
+            while link.pending.len() < ping.len() {
+                link.fill().await.unwrap();
+            }
+            assert_eq!(link.pending, ping);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replication_link() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let state = State::default();
+        let primary = MasterConfig {
+            host: "127.0.0.1".to_owned(),
+            port: port.into(),
+        };
+        let epoch = state.replicaof(Some(primary)).unwrap();
+        let link = tokio::spawn(replication_link(state.clone(), 6381, epoch));
+        let info = || {
+            let request = frame::encode(&["INFO", "replication"]);
+            let redis_data = RedisData::parse_bytes(&request).unwrap();
+            state.clone().handle_response(&redis_data).unwrap()
+        };
+        let wait_for = |status: &'static str| async move {
+            while !info().contains(&format!("master_link_status:{status}\r\n")) {
+                sleep(Duration::from_millis(10)).await;
+            }
+        };
+
+        // the first connection syncs an empty dataset, then the primary goes away
+        let (mut connection, _) = listener.accept().await.unwrap();
+        let rdb = RdbWriter::new(Vec::new()).write_rdb(&[]).unwrap();
+        let replies = format!(
+            "+PONG\r\n+OK\r\n+OK\r\n+FULLRESYNC {} 0\r\n${}\r\n",
+            "a".repeat(40),
+            rdb.len()
+        );
+        connection
+            .write_all(&[replies.as_bytes(), &rdb].concat())
+            .await
+            .unwrap();
+        wait_for("up").await;
+        assert!(!info().contains("master_link_down_since_seconds"));
+        let mut last = Instant::now();
+        drop(connection);
+        wait_for("down").await;
+        assert!(info().contains("master_link_down_since_seconds:0\r\n"));
+
+        // reconnecting waits twice as long after each failure
+        for retry in [RETRY_MIN, RETRY_MIN * 2, RETRY_MIN * 4] {
+            let (connection, _) = listener.accept().await.unwrap();
+            drop(connection);
+            let elapsed = last.elapsed();
+            assert!(elapsed >= retry && elapsed < retry * 2, "{elapsed:?}");
+            last = Instant::now();
+        }
+
+        assert_eq!(state.replicaof(None), None);
+        link.await.unwrap();
+    }
+
+    #[test]
+    fn test_count_acked() {
+        let replicas = Replicas::default();
+        let first: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        let _first_rx = replicas.register(first, 6380, 0);
+        let second_rx = replicas.register(second, 6381, 0);
+
+        // repeated acknowledgements of a replica count once
+        replicas.ack(first, 100, None);
+        replicas.ack(first, 120, None);
+        assert_eq!(replicas.count_acked(100), 1);
+        replicas.ack(second, 50, None);
+        assert_eq!(replicas.count_acked(100), 1);
+        replicas.ack(second, 100, None);
+        assert_eq!(replicas.count_acked(100), 2);
+
+        replicas.unregister(first);
+        assert_eq!(replicas.count_acked(100), 1);
+        drop(second_rx);
+        assert_eq!(replicas.count_acked(100), 0);
+    }
+
+    #[test]
+    fn test_enough_good() {
+        let replicas = Replicas::default();
+        let addr: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        assert!(replicas.enough_good());
+        replicas.set_min_replicas_to_write(1);
+        assert!(!replicas.enough_good());
+
+        let rx = replicas.register(addr, 6380, 0);
+        replicas.set_online(addr);
+        replicas.ack(addr, 0, None);
+        assert_eq!(replicas.good_count(), 1);
+        assert!(replicas.enough_good());
+
+        // the last acknowledgement gets older than `min-replicas-max-lag`
+        let lag = Duration::from_secs(replicas.min_replicas_max_lag() + 1);
+        for replica in replicas.replicas.lock().unwrap().iter_mut() {
+            replica.last_ack = Instant::now().checked_sub(lag).unwrap();
+        }
+        assert_eq!(replicas.good_count(), 0);
+        assert!(!replicas.enough_good());
+
+        replicas.ack(addr, 0, None);
+        assert!(replicas.enough_good());
+        drop(rx);
+        assert!(!replicas.enough_good());
+    }
+
+    #[test]
+    fn test_ack_request() {
+        let replicas = Replicas::default();
+        let addr: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let _rx = replicas.register(addr, 6380, 0);
+        let ack = frame::encode(&["REPLCONF", "ACK", "100", "FACK", "80"]);
+        assert_eq!(replicas.ack_request(addr, &ack).unwrap(), 100);
+        assert_eq!(replicas.count_fsynced(80), 1);
+        assert_eq!(replicas.count_fsynced(100), 0);
+
+        // a plain acknowledgement keeps the offset on disk
+        let ack = frame::encode(&["REPLCONF", "ACK", "120"]);
+        assert_eq!(replicas.ack_request(addr, &ack).unwrap(), 120);
+        assert_eq!(replicas.count_acked(120), 1);
+        assert_eq!(replicas.count_fsynced(80), 1);
+
+        let ack = frame::encode(&["REPLCONF", "ACK", "x"]);
+        assert!(replicas.ack_request(addr, &ack).is_err());
+    }
+}
@@ -19,6 +19,28 @@ impl BulkString {
         Ok(bulk_string)
     }
 
+    /// Extracts every `$<length>\r\n<data>\r\n` value from a request, using the declared length so
+    /// that values containing `$` or `\r\n` are kept intact
+    pub fn parse_all(value: &str) -> Vec<Self> {
+        let mut values = Vec::new();
+        let mut rest = value;
+        while let Some(start) = rest.find('$') {
+            rest = &rest[start + 1..];
+            let Some((length, tail)) = rest.split_once("\r\n") else {
+                break;
+            };
+            let Ok(length) = length.parse::<usize>() else {
+                continue;
+            };
+            let Some(data) = tail.get(..length) else {
+                break;
+            };
+            values.push(Self::encode(data));
+            rest = &tail[length..];
+        }
+        values
+    }
+
     pub fn decode(&self) -> String {
         format!("${}\r\n{}", self.length, self.data.decode())
     }
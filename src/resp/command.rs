@@ -1,4 +1,4 @@
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum Command {
     Echo,
     Ping,
@@ -8,12 +8,50 @@ pub enum Command {
     Replconf,
     Psync,
     Wait,
+    Waitaof,
     Config,
     Keys,
     Xadd,
     Xrange,
     Xread,
+    Xinfo,
     Type,
+    Multi,
+    Exec,
+    Discard,
+    Watch,
+    Unwatch,
+    Subscribe,
+    Unsubscribe,
+    Psubscribe,
+    Punsubscribe,
+    Publish,
+    Pubsub,
+    Ssubscribe,
+    Sunsubscribe,
+    Spublish,
+    Quit,
+    Save,
+    Bgsave,
+    Lastsave,
+    Bgrewriteaof,
+    Del,
+    Dump,
+    Restore,
+    Migrate,
+    Replicaof,
+    Role,
+}
+
+impl Command {
+    /// Whether the command modifies the dataset. Read only replicas refuse these, and successful
+    /// ones reach the replicas and the AOF.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Self::Set | Self::Xadd | Self::Del | Self::Restore | Self::Migrate
+        )
+    }
 }
 
 impl TryFrom<&str> for Command {
@@ -29,12 +67,39 @@ impl TryFrom<&str> for Command {
             "replconf" => Ok(Command::Replconf),
             "psync" => Ok(Command::Psync),
             "wait" => Ok(Command::Wait),
+            "waitaof" => Ok(Command::Waitaof),
             "config" => Ok(Command::Config),
             "keys" => Ok(Command::Keys),
             "type" => Ok(Command::Type),
             "xadd" => Ok(Command::Xadd),
             "xrange" => Ok(Command::Xrange),
             "xread" => Ok(Command::Xread),
+            "xinfo" => Ok(Command::Xinfo),
+            "multi" => Ok(Command::Multi),
+            "exec" => Ok(Command::Exec),
+            "discard" => Ok(Command::Discard),
+            "watch" => Ok(Command::Watch),
+            "unwatch" => Ok(Command::Unwatch),
+            "subscribe" => Ok(Command::Subscribe),
+            "unsubscribe" => Ok(Command::Unsubscribe),
+            "psubscribe" => Ok(Command::Psubscribe),
+            "punsubscribe" => Ok(Command::Punsubscribe),
+            "publish" => Ok(Command::Publish),
+            "pubsub" => Ok(Command::Pubsub),
+            "ssubscribe" => Ok(Command::Ssubscribe),
+            "sunsubscribe" => Ok(Command::Sunsubscribe),
+            "spublish" => Ok(Command::Spublish),
+            "quit" => Ok(Command::Quit),
+            "save" => Ok(Command::Save),
+            "bgsave" => Ok(Command::Bgsave),
+            "lastsave" => Ok(Command::Lastsave),
+            "bgrewriteaof" => Ok(Command::Bgrewriteaof),
+            "del" => Ok(Command::Del),
+            "dump" => Ok(Command::Dump),
+            "restore" => Ok(Command::Restore),
+            "migrate" => Ok(Command::Migrate),
+            "replicaof" | "slaveof" => Ok(Command::Replicaof),
+            "role" => Ok(Command::Role),
             _ => Err(anyhow::anyhow!("Invalid command {value}")),
         }
     }
@@ -0,0 +1,170 @@
+use anyhow::Context;
+
+/// Returns the length of the first complete `*<n>\r\n$<len>\r\n<data>\r\n...` command at the start
+/// of `buf`, or `None` when more bytes are needed to complete it
+pub fn command_len(buf: &[u8]) -> anyhow::Result<Option<usize>> {
+    let Some(first) = buf.first() else {
+        return Ok(None);
+    };
+    anyhow::ensure!(
+        *first == b'*',
+        "expected '*', got '{}'",
+        first.escape_ascii()
+    );
+    let Some((count, mut pos)) = read_number(buf, 1)? else {
+        return Ok(None);
+    };
+    for _ in 0..count {
+        let Some(prefix) = buf.get(pos) else {
+            return Ok(None);
+        };
+        anyhow::ensure!(
+            *prefix == b'$',
+            "expected '$', got '{}'",
+            prefix.escape_ascii()
+        );
+        let Some((len, start)) = read_number(buf, pos + 1)? else {
+            return Ok(None);
+        };
+        let end = start + len + 2;
+        if buf.len() < end {
+            return Ok(None);
+        }
+        anyhow::ensure!(
+            &buf[end - 2..end] == b"\r\n",
+            "bulk string not terminated by CRLF"
+        );
+        pos = end;
+    }
+    Ok(Some(pos))
+}
+
+/// Returns the length of the first complete reply at the start of `buf`, of any RESP2 type, or
+/// `None` when more bytes are needed to complete it
+pub fn reply_len(buf: &[u8]) -> anyhow::Result<Option<usize>> {
+    reply_end(buf, 0)
+}
+
+fn reply_end(buf: &[u8], start: usize) -> anyhow::Result<Option<usize>> {
+    let Some(&kind) = buf.get(start) else {
+        return Ok(None);
+    };
+    let Some(line_len) = buf[start..].windows(2).position(|w| w == b"\r\n") else {
+        return Ok(None);
+    };
+    let line = &buf[start + 1..start + line_len];
+    let after = start + line_len + 2;
+    let number = || -> anyhow::Result<i64> {
+        std::str::from_utf8(line)?
+            .parse()
+            .map_err(|_| anyhow::anyhow!("invalid length prefix"))
+    };
+    match kind {
+        b'+' | b'-' | b':' => Ok(Some(after)),
+        b'$' => match usize::try_from(number()?) {
+            // the null bulk string
+            Err(_) => Ok(Some(after)),
+            Ok(len) => {
+                let end = after + len + 2;
+                Ok((buf.len() >= end).then_some(end))
+            }
+        },
+        b'*' => {
+            let mut pos = after;
+            for _ in 0..number()?.max(0) {
+                match reply_end(buf, pos)? {
+                    Some(end) => pos = end,
+                    None => return Ok(None),
+                }
+            }
+            Ok(Some(pos))
+        }
+        kind => anyhow::bail!("unexpected reply type '{}'", kind.escape_ascii()),
+    }
+}
+
+/// Splits a complete command into its arguments, which can hold arbitrary bytes
+pub fn args(buf: &[u8]) -> anyhow::Result<Vec<Vec<u8>>> {
+    anyhow::ensure!(
+        buf.first() == Some(&b'*'),
+        "expected '*', got '{}'",
+        buf.first()
+            .map(|b| b.escape_ascii().to_string())
+            .unwrap_or_default()
+    );
+    let (count, mut pos) = read_number(buf, 1)?.context("incomplete command")?;
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        anyhow::ensure!(buf.get(pos) == Some(&b'$'), "expected '$'");
+        let (len, start) = read_number(buf, pos + 1)?.context("incomplete command")?;
+        let arg = buf.get(start..start + len).context("incomplete command")?;
+        args.push(arg.to_vec());
+        pos = start + len + 2;
+    }
+    Ok(args)
+}
+
+/// Encodes a command as an array of bulk strings
+pub fn encode<A: AsRef<[u8]>>(args: &[A]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        let arg = arg.as_ref();
+        out.extend(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend(arg);
+        out.extend(b"\r\n");
+    }
+    out
+}
+
+/// Reads the `<number>\r\n` starting at `start`, returning it with the position after the CRLF
+fn read_number(buf: &[u8], start: usize) -> anyhow::Result<Option<(usize, usize)>> {
+    let Some(end) = buf[start..].windows(2).position(|w| w == b"\r\n") else {
+        anyhow::ensure!(buf.len() - start <= 20, "invalid length prefix");
+        return Ok(None);
+    };
+    let number = std::str::from_utf8(&buf[start..start + end])?
+        .parse()
+        .map_err(|_| anyhow::anyhow!("invalid length prefix"))?;
+    Ok(Some((number, start + end + 2)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_len() {
+        let set = b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$5\r\nb\r\nar\r\n";
+        let mut buf = set.to_vec();
+        buf.extend(b"*1\r\n$4\r\nPING\r\n");
+        assert_eq!(command_len(&buf).unwrap(), Some(set.len()));
+        assert_eq!(command_len(&set[..set.len() - 1]).unwrap(), None);
+        assert_eq!(command_len(b"*3\r\n$3").unwrap(), None);
+        assert_eq!(command_len(b"").unwrap(), None);
+        assert!(command_len(b"SET foo bar\r\n").is_err());
+        assert!(command_len(b"*1\r\n$x\r\n").is_err());
+
+        let args = args(set).unwrap();
+        assert_eq!(
+            args,
+            [b"SET".to_vec(), b"foo".to_vec(), b"b\r\nar".to_vec()]
+        );
+        assert_eq!(encode(&args), set);
+    }
+
+    #[test]
+    fn test_reply_len() {
+        assert_eq!(reply_len(b"+OK\r\n-ERR x").unwrap(), Some(5));
+        assert_eq!(reply_len(b"-ERR no\r\n").unwrap(), Some(9));
+        assert_eq!(reply_len(b"$5\r\na\r\nbc\r\n:1\r\n").unwrap(), Some(11));
+        assert_eq!(reply_len(b"$5\r\na\r\n").unwrap(), None);
+        assert_eq!(reply_len(b"$-1\r\n").unwrap(), Some(5));
+        assert_eq!(
+            reply_len(b"*2\r\n:1\r\n*1\r\n$1\r\nx\r\n").unwrap(),
+            Some(19)
+        );
+        assert_eq!(reply_len(b"*2\r\n:1\r\n").unwrap(), None);
+        assert_eq!(reply_len(b"*-1\r\n").unwrap(), Some(5));
+        assert!(reply_len(b"?\r\n").is_err());
+    }
+}
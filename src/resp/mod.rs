@@ -1,3 +1,6 @@
+use std::time::Duration;
+
+use anyhow::Context;
 use bulk_string::BulkString;
 
 use command::Command;
@@ -7,6 +10,7 @@ use crate::db::SetConfig;
 
 pub(crate) mod bulk_string;
 pub(crate) mod command;
+pub mod frame;
 pub(crate) mod rdb;
 pub(crate) mod simple_string;
 
@@ -21,27 +25,219 @@ pub enum RedisData {
     ReplConf(BulkString, BulkString),
     Psync(BulkString, BulkString),
     Wait(BulkString, BulkString),
-    Config(BulkString, BulkString),
+    /// numlocal, numreplicas, timeout
+    Waitaof(BulkString, BulkString, BulkString),
+    /// subcommand, arguments
+    Config(BulkString, Vec<BulkString>),
     Keys(BulkString),
     Xadd(BulkString, BulkString, IndexMap<BulkString, BulkString>),
     Xrange(BulkString, BulkString, BulkString),
-    /// streams, (stream_key, sequence_id) pairs, block_duration
-    Xread(BulkString, Vec<(BulkString, BulkString)>, Option<u64>),
+    /// count, (stream_key, sequence_id) pairs, block_duration
+    Xread(Option<usize>, Vec<(BulkString, BulkString)>, Option<u64>),
+    Xinfo(XinfoArg),
+    Multi,
+    Exec,
+    Discard,
+    Watch(Vec<BulkString>),
+    Unwatch,
+    Subscribe(Vec<BulkString>),
+    Unsubscribe(Vec<BulkString>),
+    Psubscribe(Vec<BulkString>),
+    Punsubscribe(Vec<BulkString>),
+    /// channel, message
+    Publish(BulkString, BulkString),
+    Pubsub(PubsubArg),
+    Ssubscribe(Vec<BulkString>),
+    Sunsubscribe(Vec<BulkString>),
+    /// shard channel, message
+    Spublish(BulkString, BulkString),
+    Quit,
+    Save,
+    Bgsave,
+    Lastsave,
+    Bgrewriteaof,
+    Del(Vec<BulkString>),
+    Dump(BulkString),
+    Restore(BulkString, RestoreArgs),
+    Migrate(MigrateArgs),
+    /// host and port of the new primary, `None` for `NO ONE`
+    Replicaof(Option<(String, usize)>),
+    Role,
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum InfoArg {
     All,
     Replication,
+    Persistence,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PubsubArg {
+    /// optional glob-style pattern
+    Channels(Option<BulkString>),
+    Numsub(Vec<BulkString>),
+    Numpat,
+    /// optional glob-style pattern
+    ShardChannels(Option<BulkString>),
+    ShardNumsub(Vec<BulkString>),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct RestoreArgs {
+    /// milliseconds to live, or a unix time in milliseconds with `ABSTTL`, 0 for no expiry
+    pub ttl: u64,
+    pub absttl: bool,
+    pub replace: bool,
+    /// the serialized value returned by `DUMP`
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct MigrateArgs {
+    pub host: String,
+    pub port: u16,
+    pub keys: Vec<BulkString>,
+    pub db: u64,
+    pub timeout: Duration,
+    /// keep the keys on this instance
+    pub copy: bool,
+    /// overwrite the keys on the target
+    pub replace: bool,
+    /// arguments of the `AUTH` sent to the target first, empty when there is none
+    pub auth: Vec<BulkString>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum XinfoArg {
+    /// stream key, number of entries to list when `FULL` is given (0 lists all of them)
+    Stream(BulkString, Option<usize>),
+    Groups(BulkString),
+    /// stream key, group name
+    Consumers(BulkString, BulkString),
 }
 
 impl RedisData {
+    /// The lowercase name of the command in a request, used in error replies
+    pub fn command_name(data: &str) -> String {
+        BulkString::parse_all(data)
+            .first()
+            .map(|v| v.data.to_lowercase())
+            .unwrap_or_default()
+    }
+
+    /// The entry of the command table this command belongs to
+    pub fn command(&self) -> Command {
+        match self {
+            Self::Get(_) => Command::Get,
+            Self::Type(_) => Command::Type,
+            Self::Echo(_) => Command::Echo,
+            Self::Set(..) => Command::Set,
+            Self::Info(_) => Command::Info,
+            Self::Ping => Command::Ping,
+            Self::ReplConf(..) => Command::Replconf,
+            Self::Psync(..) => Command::Psync,
+            Self::Wait(..) => Command::Wait,
+            Self::Waitaof(..) => Command::Waitaof,
+            Self::Config(..) => Command::Config,
+            Self::Keys(_) => Command::Keys,
+            Self::Xadd(..) => Command::Xadd,
+            Self::Xrange(..) => Command::Xrange,
+            Self::Xread(..) => Command::Xread,
+            Self::Xinfo(_) => Command::Xinfo,
+            Self::Multi => Command::Multi,
+            Self::Exec => Command::Exec,
+            Self::Discard => Command::Discard,
+            Self::Watch(_) => Command::Watch,
+            Self::Unwatch => Command::Unwatch,
+            Self::Subscribe(_) => Command::Subscribe,
+            Self::Unsubscribe(_) => Command::Unsubscribe,
+            Self::Psubscribe(_) => Command::Psubscribe,
+            Self::Punsubscribe(_) => Command::Punsubscribe,
+            Self::Publish(..) => Command::Publish,
+            Self::Pubsub(_) => Command::Pubsub,
+            Self::Ssubscribe(_) => Command::Ssubscribe,
+            Self::Sunsubscribe(_) => Command::Sunsubscribe,
+            Self::Spublish(..) => Command::Spublish,
+            Self::Quit => Command::Quit,
+            Self::Save => Command::Save,
+            Self::Bgsave => Command::Bgsave,
+            Self::Lastsave => Command::Lastsave,
+            Self::Bgrewriteaof => Command::Bgrewriteaof,
+            Self::Del(_) => Command::Del,
+            Self::Dump(_) => Command::Dump,
+            Self::Restore(..) => Command::Restore,
+            Self::Migrate(_) => Command::Migrate,
+            Self::Replicaof(_) => Command::Replicaof,
+            Self::Role => Command::Role,
+        }
+    }
+
+    pub fn is_write(&self) -> bool {
+        self.command().is_write()
+    }
+
+    /// Parses a complete command. The payload of `RESTORE` is binary, so that command is parsed
+    /// from the raw arguments and every other one from their text.
+    pub fn parse_bytes(data: &[u8]) -> anyhow::Result<Self> {
+        let args = frame::args(data)?;
+        match args.first() {
+            Some(name) if name.eq_ignore_ascii_case(b"restore") => Self::parse_restore(&args),
+            _ => Self::parse(&String::from_utf8_lossy(data)),
+        }
+    }
+
+    fn parse_restore(args: &[Vec<u8>]) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            args.len() >= 4,
+            "wrong number of arguments for 'restore' command"
+        );
+        let text = |arg: &[u8]| String::from_utf8_lossy(arg).to_string();
+        let integer = |arg: Option<&Vec<u8>>| -> anyhow::Result<i64> {
+            let arg = arg.context("syntax error")?;
+            text(arg)
+                .parse()
+                .map_err(|_| anyhow::anyhow!("value is not an integer or out of range"))
+        };
+        let ttl = integer(args.get(2))?;
+        anyhow::ensure!(ttl >= 0, "Invalid TTL value, must be >= 0");
+        let mut restore = RestoreArgs {
+            ttl: ttl as u64,
+            absttl: false,
+            replace: false,
+            payload: args[3].clone(),
+        };
+        // there is no eviction, so the access hints are only validated
+        let (mut idletime, mut freq) = (false, false);
+        let mut idx = 4;
+        while let Some(option) = args.get(idx) {
+            match text(option).to_lowercase().as_str() {
+                "replace" => restore.replace = true,
+                "absttl" => restore.absttl = true,
+                "idletime" if !freq => {
+                    idx += 1;
+                    let idle = integer(args.get(idx))?;
+                    anyhow::ensure!(idle >= 0, "Invalid IDLETIME value, must be >= 0");
+                    idletime = true;
+                }
+                "freq" if !idletime => {
+                    idx += 1;
+                    let frequency = integer(args.get(idx))?;
+                    anyhow::ensure!(
+                        (0..=255).contains(&frequency),
+                        "Invalid FREQ value, must be >= 0 and <= 255"
+                    );
+                    freq = true;
+                }
+                _ => anyhow::bail!("syntax error"),
+            }
+            idx += 1;
+        }
+        Ok(Self::Restore(BulkString::encode(&text(&args[1])), restore))
+    }
+
     pub fn parse(data: &str) -> anyhow::Result<Self> {
-        let values: Vec<BulkString> = data
-            .split_terminator('$')
-            .skip(1)
-            .filter_map(|x| BulkString::parse(x).ok())
-            .collect();
+        let values = BulkString::parse_all(data);
         anyhow::ensure!(values.len() >= 1);
         let command = Command::try_from(values[0].data.as_str())?;
         let redis_data = match command {
@@ -49,19 +245,51 @@ impl RedisData {
             Command::Get if values.len() == 2 => Self::Get(values[1].clone()),
             Command::Type if values.len() == 2 => Self::Type(values[1].clone()),
             Command::Ping => Self::Ping,
-            Command::Info => {
-                if values.len() > 1 {
-                    // TODO: filter for the argument
-                }
-                Self::Info(InfoArg::Replication)
+            Command::Multi => Self::Multi,
+            Command::Exec => Self::Exec,
+            Command::Discard => Self::Discard,
+            Command::Watch if values.len() >= 2 => Self::Watch(values[1..].to_vec()),
+            Command::Unwatch => Self::Unwatch,
+            Command::Subscribe if values.len() >= 2 => Self::Subscribe(values[1..].to_vec()),
+            Command::Unsubscribe => Self::Unsubscribe(values[1..].to_vec()),
+            Command::Psubscribe if values.len() >= 2 => Self::Psubscribe(values[1..].to_vec()),
+            Command::Punsubscribe => Self::Punsubscribe(values[1..].to_vec()),
+            Command::Publish if values.len() == 3 => {
+                Self::Publish(values[1].clone(), values[2].clone())
             }
+            Command::Pubsub if values.len() >= 2 => match values[1].data.to_lowercase().as_str() {
+                "channels" => Self::Pubsub(PubsubArg::Channels(values.get(2).cloned())),
+                "numsub" => Self::Pubsub(PubsubArg::Numsub(values[2..].to_vec())),
+                "numpat" => Self::Pubsub(PubsubArg::Numpat),
+                "shardchannels" => Self::Pubsub(PubsubArg::ShardChannels(values.get(2).cloned())),
+                "shardnumsub" => Self::Pubsub(PubsubArg::ShardNumsub(values[2..].to_vec())),
+                cmd => anyhow::bail!("unknown subcommand {cmd} for PUBSUB"),
+            },
+            Command::Ssubscribe if values.len() >= 2 => Self::Ssubscribe(values[1..].to_vec()),
+            Command::Sunsubscribe => Self::Sunsubscribe(values[1..].to_vec()),
+            Command::Spublish if values.len() == 3 => {
+                Self::Spublish(values[1].clone(), values[2].clone())
+            }
+            Command::Quit => Self::Quit,
+            Command::Save => Self::Save,
+            Command::Bgsave => Self::Bgsave,
+            Command::Lastsave => Self::Lastsave,
+            Command::Bgrewriteaof => Self::Bgrewriteaof,
+            Command::Info => match values.get(1).map(|v| v.data.to_lowercase()).as_deref() {
+                Some("persistence") => Self::Info(InfoArg::Persistence),
+                Some("all" | "everything" | "default") | None => Self::Info(InfoArg::All),
+                _ => Self::Info(InfoArg::Replication),
+            },
             Command::Replconf if values.len() >= 3 => {
                 Self::ReplConf(values[1].clone(), values[2].clone())
             }
             Command::Config if values.len() >= 3 => {
-                Self::Config(values[1].clone(), values[2].clone())
+                Self::Config(values[1].clone(), values[2..].to_vec())
             }
             Command::Wait if values.len() >= 3 => Self::Wait(values[1].clone(), values[2].clone()),
+            Command::Waitaof if values.len() == 4 => {
+                Self::Waitaof(values[1].clone(), values[2].clone(), values[3].clone())
+            }
             Command::Psync if values.len() >= 3 => {
                 Self::Psync(values[1].clone(), values[2].clone())
             }
@@ -73,26 +301,40 @@ impl RedisData {
                 Self::Xrange(values[1].clone(), values[2].clone(), values[3].clone())
             }
             Command::Xread if values.len() >= 4 => {
-                let (key_start_idx, block_duration) = if values[1].data.as_str() == "block" {
-                    let millis: u64 = values[2].data.parse()?;
-                    (4, Some(millis))
-                } else {
-                    (2, None)
-                };
-                let num_stream_keys = (values.len() - key_start_idx) / 2;
-                let mut pairs = Vec::with_capacity(num_stream_keys);
-                for key_i in key_start_idx..key_start_idx + num_stream_keys {
-                    let id_i = key_i + num_stream_keys;
-                    pairs.push((values[key_i].clone(), values[id_i].clone()));
-                }
-
-                if data.ends_with("$\r\n") {
-                    pairs.push((values[key_start_idx].clone(), BulkString::encode("$")))
+                let mut count = None;
+                let mut block_duration = None;
+                let mut idx = 1;
+                loop {
+                    let option = values.get(idx).map(|v| v.data.to_lowercase());
+                    match option.as_deref() {
+                        Some("count") => {
+                            let n = values.get(idx + 1).context("syntax error in XREAD")?;
+                            count = Some(n.data.parse()?);
+                            idx += 2;
+                        }
+                        Some("block") => {
+                            let millis = values.get(idx + 1).context("syntax error in XREAD")?;
+                            block_duration = Some(millis.data.parse()?);
+                            idx += 2;
+                        }
+                        Some("streams") => {
+                            idx += 1;
+                            break;
+                        }
+                        _ => anyhow::bail!("syntax error in XREAD"),
+                    }
                 }
-
-                Self::Xread(values[key_start_idx - 1].clone(), pairs, block_duration)
+                let streams = &values[idx..];
+                anyhow::ensure!(
+                    !streams.is_empty() && streams.len().is_multiple_of(2),
+                    "Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified."
+                );
+                let (keys, ids) = streams.split_at(streams.len() / 2);
+                let pairs = keys.iter().cloned().zip(ids.iter().cloned()).collect();
+                Self::Xread(count, pairs, block_duration)
             }
-            Command::Xadd if values.len() >= 3 => {
+            // the fields come in pairs
+            Command::Xadd if values.len() >= 5 && values.len() % 2 == 1 => {
                 let key = values[1].clone();
                 let mut id = values[2].clone(); // stream id
                 if id.data.as_str() == "*" {
@@ -107,7 +349,100 @@ impl RedisData {
                 }
                 Self::Xadd(key, id, map)
             }
-            Command::Keys if !values.is_empty() => Self::Keys(values[1].clone()),
+            Command::Xinfo if values.len() >= 3 => match values[1].data.to_lowercase().as_str() {
+                "stream" => {
+                    let full = match values.get(3) {
+                        Some(v) if v.data.to_lowercase() == "full" => {
+                            match (values.get(4), values.get(5)) {
+                                (Some(c), Some(n)) if c.data.to_lowercase() == "count" => {
+                                    Some(n.data.parse()?)
+                                }
+                                (None, None) => Some(10),
+                                _ => anyhow::bail!("syntax error in XINFO STREAM"),
+                            }
+                        }
+                        None => None,
+                        _ => anyhow::bail!("syntax error in XINFO STREAM"),
+                    };
+                    Self::Xinfo(XinfoArg::Stream(values[2].clone(), full))
+                }
+                "groups" => Self::Xinfo(XinfoArg::Groups(values[2].clone())),
+                "consumers" if values.len() >= 4 => {
+                    Self::Xinfo(XinfoArg::Consumers(values[2].clone(), values[3].clone()))
+                }
+                cmd => anyhow::bail!("unknown subcommand {cmd} for XINFO"),
+            },
+            Command::Keys if values.len() == 2 => Self::Keys(values[1].clone()),
+            Command::Del if values.len() >= 2 => Self::Del(values[1..].to_vec()),
+            Command::Dump if values.len() == 2 => Self::Dump(values[1].clone()),
+            Command::Replicaof if values.len() == 3 => {
+                let (host, port) = (values[1].data.as_str(), values[2].data.as_str());
+                match host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+                    true => Self::Replicaof(None),
+                    false => {
+                        let port = port.parse().context("Invalid master port")?;
+                        Self::Replicaof(Some((host.to_owned(), port)))
+                    }
+                }
+            }
+            Command::Role => Self::Role,
+            Command::Migrate if values.len() >= 6 => {
+                let integer = |value: &BulkString| {
+                    value
+                        .data
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("value is not an integer or out of range"))
+                };
+                let mut migrate = MigrateArgs {
+                    host: values[1].data.to_string(),
+                    port: values[2]
+                        .data
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("Invalid port"))?,
+                    keys: vec![values[3].clone()],
+                    db: integer(&values[4])?,
+                    timeout: match integer(&values[5])? {
+                        // like Redis, no timeout means one second
+                        0 => Duration::from_millis(1000),
+                        timeout => Duration::from_millis(timeout),
+                    },
+                    copy: false,
+                    replace: false,
+                    auth: Vec::new(),
+                };
+                let mut idx = 6;
+                while let Some(option) = values.get(idx) {
+                    match option.data.to_lowercase().as_str() {
+                        "copy" => migrate.copy = true,
+                        "replace" => migrate.replace = true,
+                        "auth" => {
+                            migrate.auth = values
+                                .get(idx + 1..idx + 2)
+                                .context("syntax error")?
+                                .to_vec();
+                            idx += 1;
+                        }
+                        "auth2" => {
+                            migrate.auth = values
+                                .get(idx + 1..idx + 3)
+                                .context("syntax error")?
+                                .to_vec();
+                            idx += 2;
+                        }
+                        "keys" => {
+                            anyhow::ensure!(
+                                values[3].data.is_empty(),
+                                "When using MIGRATE KEYS option, the key argument must be set to the empty string"
+                            );
+                            migrate.keys = values[idx + 1..].to_vec();
+                            break;
+                        }
+                        _ => anyhow::bail!("syntax error"),
+                    }
+                    idx += 1;
+                }
+                Self::Migrate(migrate)
+            }
 
             _ => anyhow::bail!("incorrect {values:?} for {command:?}",),
         };
@@ -164,6 +499,19 @@ mod tests {
     //     assert_eq!(result, data);
     // }
     //
+    #[test]
+    fn parse_xread_data() {
+        let result = RedisData::parse(
+            "*10\r\n$5\r\nXREAD\r\n$5\r\nCOUNT\r\n$1\r\n2\r\n$5\r\nBLOCK\r\n$1\r\n0\r\n$7\r\nSTREAMS\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\n$\r\n$3\r\n0-1\r\n",
+        );
+        assert!(result.is_ok());
+        let pairs = vec![
+            (BulkString::encode("a"), BulkString::encode("$")),
+            (BulkString::encode("b"), BulkString::encode("0-1")),
+        ];
+        assert_eq!(result.unwrap(), RedisData::Xread(Some(2), pairs, Some(0)));
+    }
+
     #[test]
     fn parse_ping_redis_data() {
         let result = RedisData::parse("*1\r\n$4\r\nping\r\n");
@@ -172,6 +520,33 @@ mod tests {
         assert_eq!(result, RedisData::Ping);
     }
 
+    #[test]
+    fn parse_restore_binary_payload() {
+        let payload = [0x00, 0x03, b'b', b'\r', b'\n', 0xff];
+        let request = frame::encode(&[b"RESTORE".as_slice(), b"foo", b"0", &payload, b"REPLACE"]);
+        let restore = RestoreArgs {
+            ttl: 0,
+            absttl: false,
+            replace: true,
+            payload: payload.to_vec(),
+        };
+        assert_eq!(
+            RedisData::parse_bytes(&request).unwrap(),
+            RedisData::Restore(BulkString::encode("foo"), restore)
+        );
+        let request = frame::encode(&["RESTORE", "foo", "-1", "x"]);
+        assert!(RedisData::parse_bytes(&request).is_err());
+        let request = frame::encode(&["RESTORE", "foo", "0", "x", "IDLETIME", "1", "FREQ", "1"]);
+        assert!(RedisData::parse_bytes(&request).is_err());
+    }
+
+    #[test]
+    fn parse_migrate_port_out_of_range() {
+        let request = frame::encode(&["MIGRATE", "127.0.0.1", "70000", "k", "0", "1000"]);
+        let error = RedisData::parse_bytes(&request).unwrap_err();
+        assert_eq!(error.to_string(), "Invalid port");
+    }
+
     #[test]
     fn parse_hey() {
         let result = BulkString::parse("3\r\nhey\r\n");
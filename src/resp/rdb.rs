@@ -1,24 +1,40 @@
 // RDB reader - some amount of code here is adopted and modified from https://github.com/badboy/rdb-rs/blob/master/src/parser.rs
 use std::{
+    collections::VecDeque,
     io::{BufReader, Read},
     time::{Duration, UNIX_EPOCH},
 };
 
 use anyhow::Context;
-use bytes::{Buf, BytesMut};
 use dashmap::DashMap;
+use indexmap::IndexMap;
 
 use crate::{
-    db::{DataType, DataValue, SetConfig},
+    db::{DataType, DataValue, SetConfig, StreamData},
     resp::bulk_string::BulkString,
 };
 
-#[derive(Debug)]
-pub(crate) struct Rdb<R> {
-    inner: BufReader<R>,
-    buffer: BytesMut,
-}
+use self::{crc64::crc64, listpack::Element};
+
+pub(crate) mod crc64;
+pub(crate) mod dump;
+pub(crate) mod intset;
+pub(crate) mod listpack;
+pub(crate) mod lzf;
+pub(crate) mod writer;
+pub(crate) mod ziplist;
+pub(crate) mod zipmap;
+
+/// Highest RDB format version the reader understands
+pub const MAX_RDB_VERSION: u32 = 12;
+
 pub mod op_code {
+    pub const SLOT_INFO: u8 = 244;
+    pub const FUNCTION2: u8 = 245;
+    pub const FUNCTION_PRE_GA: u8 = 246;
+    pub const MODULE_AUX: u8 = 247;
+    pub const IDLE: u8 = 248;
+    pub const FREQ: u8 = 249;
     pub const AUX: u8 = 250;
     pub const RESIZEDB: u8 = 251;
     pub const EXPIRETIME_MS: u8 = 252;
@@ -27,185 +43,684 @@ pub mod op_code {
     pub const EOF: u8 = 255;
 }
 
+pub mod value_type {
+    pub const STRING: u8 = 0;
+    pub const LIST: u8 = 1;
+    pub const SET: u8 = 2;
+    pub const ZSET: u8 = 3;
+    pub const HASH: u8 = 4;
+    pub const ZSET_2: u8 = 5;
+    pub const MODULE_PRE_GA: u8 = 6;
+    pub const MODULE_2: u8 = 7;
+    pub const HASH_ZIPMAP: u8 = 9;
+    pub const LIST_ZIPLIST: u8 = 10;
+    pub const SET_INTSET: u8 = 11;
+    pub const ZSET_ZIPLIST: u8 = 12;
+    pub const HASH_ZIPLIST: u8 = 13;
+    pub const LIST_QUICKLIST: u8 = 14;
+    pub const STREAM_LISTPACKS: u8 = 15;
+    pub const HASH_LISTPACK: u8 = 16;
+    pub const ZSET_LISTPACK: u8 = 17;
+    pub const LIST_QUICKLIST_2: u8 = 18;
+    pub const STREAM_LISTPACKS_2: u8 = 19;
+    pub const SET_LISTPACK: u8 = 20;
+    pub const STREAM_LISTPACKS_3: u8 = 21;
+}
+
 pub mod constant {
     pub const RDB_6BITLEN: u8 = 0;
     pub const RDB_14BITLEN: u8 = 1;
     pub const RDB_ENCVAL: u8 = 3;
+    pub const RDB_32BITLEN: u8 = 0x80;
+    pub const RDB_64BITLEN: u8 = 0x81;
 }
 
 pub mod encoding {
-    pub const INT8: u32 = 0;
-    pub const INT16: u32 = 1;
-    pub const INT32: u32 = 2;
-    pub const _LZF: u32 = 3;
+    pub const INT8: u8 = 0;
+    pub const INT16: u8 = 1;
+    pub const INT32: u8 = 2;
+    pub const LZF: u8 = 3;
+}
+
+/// Opcodes of the values a module serializes into a `MODULE_AUX` field
+mod module_op {
+    pub const EOF: u64 = 0;
+    pub const SINT: u64 = 1;
+    pub const UINT: u64 = 2;
+    pub const FLOAT: u64 = 3;
+    pub const DOUBLE: u64 = 4;
+    pub const STRING: u64 = 5;
+}
+
+mod quicklist_container {
+    pub const PLAIN: u64 = 1;
+    pub const PACKED: u64 = 2;
+}
+
+mod stream_flag {
+    pub const DELETED: i64 = 1;
+    pub const SAMEFIELDS: i64 = 2;
 }
 
 #[derive(Debug)]
-enum StringEncoding {
-    Int32(u32),
-    StringValue(String),
-}
-
-impl From<StringEncoding> for BulkString {
-    fn from(value: StringEncoding) -> Self {
-        match value {
-            StringEncoding::Int32(_val) => unimplemented!(),
-            StringEncoding::StringValue(val) => BulkString {
-                length: val.len(),
-                data: val.as_str().into(),
-            },
-        }
+enum Length {
+    Len(u64),
+    /// special string encoding, see [`encoding`]
+    Encoded(u8),
+}
+
+/// A key read from an RDB file, along with the database it is stored in
+#[derive(Debug)]
+pub(crate) struct RdbEntry {
+    pub db: u64,
+    pub key: BulkString,
+    pub value: DataValue,
+}
+
+/// Keeps a running CRC64 of every byte read, to verify the checksum trailer, and the offset
+/// reached in the file
+#[derive(Debug)]
+struct CrcReader<R> {
+    inner: R,
+    crc: u64,
+    pos: u64,
+}
+
+impl<R: Read> Read for CrcReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.crc = crc64(self.crc, &buf[..n]);
+        self.pos += n as u64;
+        Ok(n)
     }
 }
 
+#[derive(Debug)]
+pub(crate) struct Rdb<R> {
+    inner: CrcReader<BufReader<R>>,
+    version: u32,
+    /// `AUX` fields such as `redis-ver` and `ctime`
+    aux: Vec<(String, String)>,
+    /// offset of the opcode being read, where a corrupt file stops being readable
+    record_start: u64,
+}
+
 impl<R: Read> Rdb<R> {
     pub fn new(reader: R) -> Self {
         Self {
-            inner: BufReader::new(reader),
-            buffer: BytesMut::new(),
+            inner: CrcReader {
+                inner: BufReader::new(reader),
+                crc: 0,
+                pos: 0,
+            },
+            version: 0,
+            aux: Vec::new(),
+            record_start: 0,
         }
     }
 
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn aux(&self) -> &[(String, String)] {
+        &self.aux
+    }
+
+    pub fn record_start(&self) -> u64 {
+        self.record_start
+    }
+
+    /// Loads the keys of database 0 that have not expired yet
     pub fn read_rdb_to_map(
         &mut self,
-        values: &mut DashMap<BulkString, DataValue>,
+        values: &DashMap<BulkString, DataValue>,
+    ) -> anyhow::Result<()> {
+        let (mut loaded, mut skipped) = (0, 0);
+        self.read_entries(|entry| {
+            if entry.db != 0 {
+                skipped += 1;
+            } else if !entry.value.expiry.as_ref().is_some_and(|e| e.has_expired()) {
+                values.insert(entry.key, entry.value);
+                loaded += 1;
+            }
+            Ok(())
+        })?;
+        if let Some((_, redis_ver)) = self.aux.iter().find(|(key, _)| key == "redis-ver") {
+            println!("RDB produced by version {redis_ver}");
+        }
+        println!("Loaded {loaded} keys from the RDB file");
+        if skipped > 0 {
+            println!("Skipped {skipped} keys stored in databases other than 0");
+        }
+        Ok(())
+    }
+
+    /// Reads the whole file, calling `on_entry` for every key, and verifies the checksum
+    pub fn read_entries(
+        &mut self,
+        mut on_entry: impl FnMut(RdbEntry) -> anyhow::Result<()>,
     ) -> anyhow::Result<()> {
         self.read_header()?;
+        let mut db = 0;
+        let mut expiry = None;
         loop {
-            let op = self.next().context("read next byte")?;
+            self.record_start = self.inner.pos;
+            let op = self.read_u8().context("read next opcode")?;
             match op {
                 op_code::AUX => {
-                    let _key = self.read_blob()?;
-                    let _value = self.read_blob()?;
-                }
-                op_code::SELECTDB => {
-                    let (_length, _) = self.read_length_with_encoding()?;
+                    let key = self.read_string().context("read aux key")?;
+                    let value = self.read_string().context("read aux value")?;
+                    self.aux.push((
+                        String::from_utf8_lossy(&key).to_string(),
+                        String::from_utf8_lossy(&value).to_string(),
+                    ));
                 }
+                op_code::SELECTDB => db = self.read_length().context("read db number")?,
                 op_code::RESIZEDB => {
-                    let (_db_size, _) = self.read_length_with_encoding()?;
-                    let (_expires_size, _) = self.read_length_with_encoding()?;
+                    let _db_size = self.read_length()?;
+                    let _expires_size = self.read_length()?;
                 }
                 op_code::EXPIRETIME_MS => {
-                    self.buffer.resize(8, 0);
-                    self.inner.read_exact(&mut self.buffer)?;
-                    let expiry = self.buffer.get_u64_le();
-                    let exp = UNIX_EPOCH + Duration::from_millis(expiry);
-                    let _value_type = self.read_blob()?;
-                    let key: BulkString = self.read_blob()?.into();
-                    let value: BulkString = self.read_blob()?.into();
-                    println!("Saved key {key:?} and value {value:?} and expiry {expiry}ms");
-                    let data_value = DataValue {
-                        value: DataType::String(value),
-                        expiry: Some(SetConfig::from_expiration(exp)),
-                    };
-                    values.insert(key, data_value);
+                    let ms = self.read_u64_le().context("read expire time")?;
+                    expiry = Some(UNIX_EPOCH + Duration::from_millis(ms));
                 }
                 op_code::EXPIRETIME => {
-                    self.buffer.resize(4, 0);
-                    self.inner.read_exact(&mut self.buffer)?;
-                    let expiry = self.buffer.get_u64_le();
-                    let exp = UNIX_EPOCH + Duration::from_millis(expiry);
-                    let key: BulkString = self.read_blob()?.into();
-                    let value: BulkString = self.read_blob()?.into();
-                    println!("Saved key {key:?} and value {value:?} and expiry {expiry}s");
-                    let data_value = DataValue {
-                        value: DataType::String(value),
-                        expiry: Some(SetConfig::from_expiration(exp)),
-                    };
-                    values.insert(key, data_value);
-                }
-                op_code::EOF => {
-                    break;
-                }
-                _ => {
-                    let key = self.read_blob()?;
-                    let value = self.read_blob()?.into();
-                    println!("Saved key {key:?} and value {value:?}");
-                    let data_value = DataValue {
-                        value: DataType::String(value),
-                        expiry: None,
-                    };
-                    values.insert(key.into(), data_value);
+                    let secs = self.read_u32_le().context("read expire time")?;
+                    expiry = Some(UNIX_EPOCH + Duration::from_secs(secs as u64));
+                }
+                // eviction hints are not used
+                op_code::IDLE => {
+                    let _idle = self.read_length()?;
+                }
+                op_code::FREQ => {
+                    let _freq = self.read_u8()?;
+                }
+                op_code::SLOT_INFO => {
+                    let _slot_id = self.read_length()?;
+                    let _slot_size = self.read_length()?;
+                    let _expires_slot_size = self.read_length()?;
+                }
+                // functions are not supported, so the library code is skipped
+                op_code::FUNCTION2 => {
+                    let _library = self.read_string().context("read function library")?;
+                }
+                op_code::FUNCTION_PRE_GA => {
+                    anyhow::bail!("Pre-release function format is not supported")
+                }
+                op_code::MODULE_AUX => self.skip_module_aux().context("read module aux")?,
+                op_code::EOF => break,
+                value_type => {
+                    let key = self.read_bulk_string().context("read key")?;
+                    let value = self.read_value(value_type).with_context(|| {
+                        format!(
+                            "read value of type {value_type} for key {}",
+                            key.data.as_str()
+                        )
+                    })?;
+                    on_entry(RdbEntry {
+                        db,
+                        key,
+                        value: DataValue {
+                            value,
+                            expiry: expiry.take().map(SetConfig::from_expiration),
+                        },
+                    })?;
                 }
             }
         }
-        Ok(())
+        self.verify_checksum()
+    }
+
+    /// Reads a single value with its type and no key, as found in a `DUMP` payload written with
+    /// the given RDB version
+    pub fn read_object(&mut self, version: u32) -> anyhow::Result<DataType> {
+        self.version = version;
+        let value_type = self.read_u8().context("read value type")?;
+        self.read_value(value_type)
     }
 
     fn read_header(&mut self) -> anyhow::Result<()> {
-        self.buffer.resize(9, 0);
-        self.inner.read_exact(&mut self.buffer)?;
-        anyhow::ensure!(&self.buffer[..5] == b"REDIS", "Invalid header");
+        let header = self.read_bytes(9).context("read header")?;
+        anyhow::ensure!(&header[..5] == b"REDIS", "Invalid header");
+        let version = std::str::from_utf8(&header[5..])
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(|| anyhow::anyhow!("Invalid RDB version"))?;
+        anyhow::ensure!(
+            (1..=MAX_RDB_VERSION).contains(&version),
+            "Can't handle RDB format version {version}"
+        );
+        self.version = version;
         Ok(())
     }
 
-    fn next(&mut self) -> anyhow::Result<u8> {
-        self.buffer.resize(1, 0);
-        self.inner.read_exact(&mut self.buffer)?;
-        Ok(self.buffer[0])
-    }
-
-    fn read_blob(&mut self) -> anyhow::Result<StringEncoding> {
-        let (length, is_encoded) = self
-            .read_length_with_encoding()
-            .context("read length with encoding")?;
-
-        if is_encoded {
-            let result = match length {
-                encoding::INT8 => self.next()? as u32,
-                encoding::INT16 => {
-                    self.buffer.resize(3, 0);
-                    self.inner.read_exact(&mut self.buffer)?;
-                    let val = self.buffer.get_u16_le();
-                    val as u32
-                }
-                encoding::INT32 => {
-                    self.buffer.resize(4, 0);
-                    self.inner.read_exact(&mut self.buffer)?;
-                    self.buffer.get_u32_le()
-                }
-                // encoding::LZF => {
-                //     let compressed_length = try!(read_length(input));
-                //     let real_length = try!(read_length(input));
-                //     let data = try!(read_exact(input, compressed_length as usize));
-                //     lzf::decompress(&data, real_length as usize).unwrap()
-                // },
-                _ => {
-                    panic!("Unknown encoding: {}", length)
-                }
-            };
-            Ok(StringEncoding::Int32(result))
-        } else {
-            self.buffer.resize(length as usize, 0);
-            self.inner.read_exact(&mut self.buffer)?;
-            let value = String::from_utf8_lossy(&self.buffer).to_string();
-            Ok(StringEncoding::StringValue(value))
+    fn verify_checksum(&mut self) -> anyhow::Result<()> {
+        // checksums were introduced with version 5
+        if self.version < 5 {
+            return Ok(());
         }
+        self.record_start = self.inner.pos;
+        let expected = self.inner.crc;
+        let checksum = self.read_u64_le().context("read checksum")?;
+        // a zero checksum means the file was written with checksums disabled
+        anyhow::ensure!(
+            checksum == 0 || checksum == expected,
+            "Wrong RDB checksum, the file is corrupted: stored {checksum:#018x}, computed {expected:#018x}"
+        );
+        Ok(())
     }
 
-    fn read_length_with_encoding(&mut self) -> anyhow::Result<(u32, bool)> {
-        let length;
-        let mut is_encoded = false;
+    fn read_value(&mut self, value_type: u8) -> anyhow::Result<DataType> {
+        let value = match value_type {
+            value_type::STRING => DataType::String(self.read_bulk_string()?),
+            value_type::LIST => {
+                let len = self.read_length()?;
+                let list = (0..len)
+                    .map(|_| self.read_bulk_string())
+                    .collect::<anyhow::Result<_>>()?;
+                DataType::List(list)
+            }
+            value_type::SET => {
+                let len = self.read_length()?;
+                let set = (0..len)
+                    .map(|_| self.read_bulk_string())
+                    .collect::<anyhow::Result<_>>()?;
+                DataType::Set(set)
+            }
+            value_type::ZSET | value_type::ZSET_2 => {
+                let len = self.read_length()?;
+                let mut zset = Vec::new();
+                for _ in 0..len {
+                    let member = self.read_bulk_string()?;
+                    let score = match value_type {
+                        value_type::ZSET => self.read_double_string()?,
+                        _ => f64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()),
+                    };
+                    zset.push((member, score));
+                }
+                sorted_set(zset)
+            }
+            value_type::HASH => {
+                let len = self.read_length()?;
+                let mut hash = IndexMap::new();
+                for _ in 0..len {
+                    let field = self.read_bulk_string()?;
+                    hash.insert(field, self.read_bulk_string()?);
+                }
+                DataType::Hash(hash)
+            }
+            value_type::HASH_ZIPMAP => {
+                let pairs = zipmap::decode(&self.read_string()?)?;
+                let hash = pairs
+                    .iter()
+                    .map(|(field, value)| Ok((to_bulk_string(field)?, to_bulk_string(value)?)))
+                    .collect::<anyhow::Result<_>>()?;
+                DataType::Hash(hash)
+            }
+            value_type::LIST_ZIPLIST => {
+                let elements = ziplist::decode(&self.read_string()?)?;
+                let list = elements.iter().map(Element::to_bulk_string);
+                DataType::List(list.collect::<anyhow::Result<_>>()?)
+            }
+            value_type::SET_INTSET => {
+                let set = intset::decode(&self.read_string()?)?
+                    .iter()
+                    .map(|v| BulkString::encode(&v.to_string()))
+                    .collect();
+                DataType::Set(set)
+            }
+            value_type::SET_LISTPACK => {
+                let elements = listpack::decode(&self.read_string()?)?;
+                let set = elements.iter().map(Element::to_bulk_string);
+                DataType::Set(set.collect::<anyhow::Result<_>>()?)
+            }
+            value_type::ZSET_ZIPLIST | value_type::ZSET_LISTPACK => {
+                let blob = self.read_string()?;
+                let elements = match value_type {
+                    value_type::ZSET_ZIPLIST => ziplist::decode(&blob)?,
+                    _ => listpack::decode(&blob)?,
+                };
+                let zset = pairs(&elements)?
+                    .map(|(member, score)| Ok((member.to_bulk_string()?, element_to_score(score)?)))
+                    .collect::<anyhow::Result<_>>()?;
+                sorted_set(zset)
+            }
+            value_type::HASH_ZIPLIST | value_type::HASH_LISTPACK => {
+                let blob = self.read_string()?;
+                let elements = match value_type {
+                    value_type::HASH_ZIPLIST => ziplist::decode(&blob)?,
+                    _ => listpack::decode(&blob)?,
+                };
+                let hash = pairs(&elements)?
+                    .map(|(field, value)| Ok((field.to_bulk_string()?, value.to_bulk_string()?)))
+                    .collect::<anyhow::Result<_>>()?;
+                DataType::Hash(hash)
+            }
+            value_type::LIST_QUICKLIST | value_type::LIST_QUICKLIST_2 => {
+                let nodes = self.read_length()?;
+                let mut list = VecDeque::new();
+                for _ in 0..nodes {
+                    let container = match value_type {
+                        value_type::LIST_QUICKLIST_2 => self.read_length()?,
+                        _ => quicklist_container::PACKED,
+                    };
+                    let blob = self.read_string()?;
+                    match (value_type, container) {
+                        (_, quicklist_container::PLAIN) => list.push_back(to_bulk_string(&blob)?),
+                        (value_type::LIST_QUICKLIST, _) => {
+                            for element in ziplist::decode(&blob)? {
+                                list.push_back(element.to_bulk_string()?);
+                            }
+                        }
+                        (_, quicklist_container::PACKED) => {
+                            for element in listpack::decode(&blob)? {
+                                list.push_back(element.to_bulk_string()?);
+                            }
+                        }
+                        (_, container) => anyhow::bail!("Unknown quicklist container {container}"),
+                    }
+                }
+                DataType::List(list)
+            }
+            value_type::STREAM_LISTPACKS
+            | value_type::STREAM_LISTPACKS_2
+            | value_type::STREAM_LISTPACKS_3 => DataType::Stream(self.read_stream(value_type)?),
+            value_type::MODULE_PRE_GA | value_type::MODULE_2 => {
+                anyhow::bail!("Module data types are not supported")
+            }
+            value_type => anyhow::bail!("Unknown RDB value type {value_type}"),
+        };
+        Ok(value)
+    }
 
-        let enc_type = self.next()?;
+    /// Reads a stream stored as listpack nodes. Consumer groups are not supported, so they are
+    /// read and discarded.
+    fn read_stream(&mut self, value_type: u8) -> anyhow::Result<VecDeque<StreamData>> {
+        let nodes = self.read_length()?;
+        let mut entries = VecDeque::new();
+        for _ in 0..nodes {
+            let master_id = self.read_string().context("read stream node key")?;
+            anyhow::ensure!(master_id.len() == 16, "Invalid stream node key");
+            let master_id = (
+                u64::from_be_bytes(master_id[..8].try_into()?),
+                u64::from_be_bytes(master_id[8..].try_into()?),
+            );
+            let elements = listpack::decode(&self.read_string()?)?;
+            read_stream_node(master_id, &elements, &mut entries).context("read stream node")?;
+        }
+
+        let _length = self.read_length()?;
+        let _last_id = (self.read_length()?, self.read_length()?);
+        if value_type >= value_type::STREAM_LISTPACKS_2 {
+            let _first_id = (self.read_length()?, self.read_length()?);
+            let _max_deleted_id = (self.read_length()?, self.read_length()?);
+            let _entries_added = self.read_length()?;
+        }
 
-        match (enc_type & 0xC0) >> 6 {
-            constant::RDB_ENCVAL => {
-                is_encoded = true;
-                length = (enc_type & 0x3F) as u32;
+        let groups = self.read_length()?;
+        for _ in 0..groups {
+            let _name = self.read_string()?;
+            let _last_id = (self.read_length()?, self.read_length()?);
+            if value_type >= value_type::STREAM_LISTPACKS_2 {
+                let _entries_read = self.read_length()?;
+            }
+            let pending = self.read_length()?;
+            for _ in 0..pending {
+                let _id = self.read_bytes(16)?;
+                let _delivery_time = self.read_u64_le()?;
+                let _delivery_count = self.read_length()?;
             }
-            constant::RDB_6BITLEN => {
-                length = (enc_type & 0x3F) as u32;
+            let consumers = self.read_length()?;
+            for _ in 0..consumers {
+                let _name = self.read_string()?;
+                let _seen_time = self.read_u64_le()?;
+                if value_type >= value_type::STREAM_LISTPACKS_3 {
+                    let _active_time = self.read_u64_le()?;
+                }
+                let pending = self.read_length()?;
+                for _ in 0..pending {
+                    let _id = self.read_bytes(16)?;
+                }
             }
+        }
+        Ok(entries)
+    }
+
+    /// Skips the data of a module, which can only be decoded by the module itself
+    fn skip_module_aux(&mut self) -> anyhow::Result<()> {
+        let _module_id = self.read_length()?;
+        let _when_opcode = self.read_length()?;
+        let _when = self.read_length()?;
+        loop {
+            match self.read_length()? {
+                module_op::EOF => return Ok(()),
+                module_op::SINT | module_op::UINT => {
+                    self.read_length()?;
+                }
+                module_op::FLOAT => {
+                    self.read_bytes(4)?;
+                }
+                module_op::DOUBLE => {
+                    self.read_bytes(8)?;
+                }
+                module_op::STRING => {
+                    self.read_string()?;
+                }
+                op => anyhow::bail!("Unknown module opcode {op}"),
+            }
+        }
+    }
+
+    fn read_u8(&mut self) -> anyhow::Result<u8> {
+        let mut buf = [0; 1];
+        self.inner.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn read_u32_le(&mut self) -> anyhow::Result<u32> {
+        let mut buf = [0; 4];
+        self.inner.read_exact(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn read_u64_le(&mut self) -> anyhow::Result<u64> {
+        let mut buf = [0; 8];
+        self.inner.read_exact(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn read_bytes(&mut self, len: usize) -> anyhow::Result<Vec<u8>> {
+        // read through `take` so that a corrupted length can't allocate a huge buffer up front
+        let mut buf = Vec::new();
+        (&mut self.inner).take(len as u64).read_to_end(&mut buf)?;
+        anyhow::ensure!(buf.len() == len, "Unexpected end of file");
+        Ok(buf)
+    }
+
+    fn read_length_encoding(&mut self) -> anyhow::Result<Length> {
+        let first = self.read_u8()?;
+        let length = match (first & 0xc0) >> 6 {
+            constant::RDB_6BITLEN => Length::Len((first & 0x3f) as u64),
             constant::RDB_14BITLEN => {
-                let next_byte = self.next()?;
-                length = (((enc_type & 0x3F) as u32) << 8) | next_byte as u32;
+                let next = self.read_u8()?;
+                Length::Len((((first & 0x3f) as u64) << 8) | next as u64)
+            }
+            constant::RDB_ENCVAL => Length::Encoded(first & 0x3f),
+            _ => match first {
+                constant::RDB_32BITLEN => {
+                    let bytes = self.read_bytes(4)?;
+                    Length::Len(u32::from_be_bytes(bytes.try_into().unwrap()) as u64)
+                }
+                constant::RDB_64BITLEN => {
+                    let bytes = self.read_bytes(8)?;
+                    Length::Len(u64::from_be_bytes(bytes.try_into().unwrap()))
+                }
+                first => anyhow::bail!("Unknown length encoding {first:#x}"),
+            },
+        };
+        Ok(length)
+    }
+
+    fn read_length(&mut self) -> anyhow::Result<u64> {
+        match self.read_length_encoding()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(encoding) => {
+                anyhow::bail!("Expected a length, found string encoding {encoding}")
+            }
+        }
+    }
+
+    fn read_string(&mut self) -> anyhow::Result<Vec<u8>> {
+        let value = match self.read_length_encoding()? {
+            Length::Len(len) => self.read_bytes(len as usize)?,
+            Length::Encoded(encoding::INT8) => (self.read_u8()? as i8).to_string().into_bytes(),
+            Length::Encoded(encoding::INT16) => {
+                let bytes = self.read_bytes(2)?;
+                i16::from_le_bytes(bytes.try_into().unwrap())
+                    .to_string()
+                    .into_bytes()
+            }
+            Length::Encoded(encoding::INT32) => {
+                (self.read_u32_le()? as i32).to_string().into_bytes()
+            }
+            Length::Encoded(encoding::LZF) => {
+                let compressed_len = self.read_length()?;
+                let len = self.read_length()?;
+                let compressed = self.read_bytes(compressed_len as usize)?;
+                lzf::decompress(&compressed, len as usize)?
+            }
+            Length::Encoded(encoding) => anyhow::bail!("Unknown string encoding {encoding}"),
+        };
+        Ok(value)
+    }
+
+    fn read_bulk_string(&mut self) -> anyhow::Result<BulkString> {
+        to_bulk_string(&self.read_string()?)
+    }
+
+    /// Reads a score of the original `ZSET` type, stored as a string with special lengths for NaN
+    /// and the infinities
+    fn read_double_string(&mut self) -> anyhow::Result<f64> {
+        let score = match self.read_u8()? {
+            253 => f64::NAN,
+            254 => f64::INFINITY,
+            255 => f64::NEG_INFINITY,
+            len => {
+                let bytes = self.read_bytes(len as usize)?;
+                std::str::from_utf8(&bytes)?.parse()?
             }
-            _ => {
-                length = self.next()?.to_be() as u32;
+        };
+        Ok(score)
+    }
+}
+
+/// Decodes the entries of a stream listpack node, skipping the ones marked as deleted
+fn read_stream_node(
+    (master_ms, master_seq): (u64, u64),
+    elements: &[Element],
+    entries: &mut VecDeque<StreamData>,
+) -> anyhow::Result<()> {
+    let mut elements = elements.iter();
+    let mut next = || {
+        elements
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Invalid stream node: truncated"))
+    };
+    let count = next()?.to_integer()?;
+    let deleted = next()?.to_integer()?;
+    let master_fields_len = next()?.to_integer()?;
+    let master_fields = (0..master_fields_len)
+        .map(|_| next()?.to_bulk_string())
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    // terminator of the master entry
+    next()?;
+
+    for _ in 0..count + deleted {
+        let flags = next()?.to_integer()?;
+        let ms = master_ms.wrapping_add(next()?.to_integer()? as u64);
+        let seq = master_seq.wrapping_add(next()?.to_integer()? as u64);
+        let mut map = IndexMap::new();
+        if flags & stream_flag::SAMEFIELDS != 0 {
+            for field in &master_fields {
+                map.insert(field.clone(), next()?.to_bulk_string()?);
             }
+        } else {
+            let fields_len = next()?.to_integer()?;
+            for _ in 0..fields_len {
+                let field = next()?.to_bulk_string()?;
+                map.insert(field, next()?.to_bulk_string()?);
+            }
+        }
+        // number of elements of the entry, only used to walk the node backwards
+        next()?;
+        if flags & stream_flag::DELETED == 0 {
+            entries.push_back(StreamData {
+                id: BulkString::encode(&format!("{ms}-{seq}")),
+                map,
+            });
         }
+    }
+    Ok(())
+}
+
+fn pairs(elements: &[Element]) -> anyhow::Result<impl Iterator<Item = (&Element, &Element)>> {
+    anyhow::ensure!(
+        elements.len().is_multiple_of(2),
+        "Expected field value pairs, got {} elements",
+        elements.len()
+    );
+    Ok(elements.chunks(2).map(|pair| (&pair[0], &pair[1])))
+}
+
+fn element_to_score(element: &Element) -> anyhow::Result<f64> {
+    match element {
+        Element::Integer(value) => Ok(*value as f64),
+        Element::String(value) => Ok(std::str::from_utf8(value)?.parse()?),
+    }
+}
+
+fn sorted_set(mut zset: Vec<(BulkString, f64)>) -> DataType {
+    zset.sort_by(|a, b| a.1.total_cmp(&b.1).then_with(|| a.0.data.cmp(&b.0.data)));
+    DataType::ZSet(zset.into_iter().collect())
+}
+
+/// Strings are stored as UTF-8, so a binary value fails the load instead of being altered
+fn to_bulk_string(bytes: &[u8]) -> anyhow::Result<BulkString> {
+    let value = std::str::from_utf8(bytes)
+        .map_err(|_| anyhow::anyhow!("Unsupported value: not valid UTF-8"))?;
+    Ok(BulkString::encode(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_redis_dump() {
+        // empty dump written by Redis 7.2, with integer encoded aux fields
+        let dump = hex::decode("524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2").unwrap();
+        let mut rdb = Rdb::new(dump.as_slice());
+        rdb.read_entries(|_| anyhow::bail!("the dump is empty"))
+            .unwrap();
+        assert_eq!(rdb.version, 11);
+        assert!(rdb
+            .aux
+            .contains(&("redis-bits".to_owned(), "64".to_owned())));
+    }
+
+    #[test]
+    fn test_read_binary_value() {
+        // a string key holding 0xff 0xfe, with the checksum disabled
+        let mut dump = b"REDIS0011\x00\x01k\x02\xff\xfe\xff".to_vec();
+        dump.extend([0; 8]);
+        let mut rdb = Rdb::new(dump.as_slice());
+        let error = rdb.read_entries(|_| Ok(())).unwrap_err();
+        assert!(format!("{error:#}").contains("not valid UTF-8"));
 
-        Ok((length, is_encoded))
+        let listpack = listpack::encode(&[Element::String(vec![0xc3, 0x28])]);
+        assert!(listpack::decode(&listpack).unwrap()[0]
+            .to_bulk_string()
+            .is_err());
     }
 }
@@ -0,0 +1,66 @@
+// DUMP payloads: a single value in its RDB encoding, followed by the RDB version it was written
+// with and a CRC64 of everything before the checksum
+use anyhow::Context;
+
+use crate::db::DataType;
+
+use super::{crc64::crc64, writer::RdbWriter, Rdb, MAX_RDB_VERSION};
+
+/// RDB version of the payloads, the version the writer produces
+const PAYLOAD_VERSION: u16 = 11;
+
+/// Size of the version and checksum footer
+const FOOTER_LEN: usize = 10;
+
+pub(crate) fn serialize(value: &DataType) -> anyhow::Result<Vec<u8>> {
+    let mut payload = RdbWriter::new(Vec::new()).write_object(value)?;
+    payload.extend(PAYLOAD_VERSION.to_le_bytes());
+    let crc = crc64(0, &payload);
+    payload.extend(crc.to_le_bytes());
+    Ok(payload)
+}
+
+/// Reads back a payload, refusing the ones written by a newer RDB version or with a wrong
+/// checksum
+pub(crate) fn deserialize(payload: &[u8]) -> anyhow::Result<DataType> {
+    let body_len = payload
+        .len()
+        .checked_sub(FOOTER_LEN)
+        .context("DUMP payload version or checksum are wrong")?;
+    let version = u16::from_le_bytes([payload[body_len], payload[body_len + 1]]);
+    let crc = u64::from_le_bytes(payload[body_len + 2..].try_into()?);
+    anyhow::ensure!(
+        version as u32 <= MAX_RDB_VERSION && crc == crc64(0, &payload[..body_len + 2]),
+        "DUMP payload version or checksum are wrong"
+    );
+    Rdb::new(&payload[..body_len])
+        .read_object(version as u32)
+        .context("Bad data format")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resp::bulk_string::BulkString;
+
+    #[test]
+    fn test_round_trip() {
+        let value = DataType::String(BulkString::encode("bar"));
+        let payload = serialize(&value).unwrap();
+        // the layout Redis 7.2 produces for DUMP of "bar"
+        assert_eq!(&payload[..7], b"\x00\x03bar\x0b\x00");
+        match deserialize(&payload).unwrap() {
+            DataType::String(s) => assert_eq!(s.data.as_str(), "bar"),
+            value => panic!("unexpected {value:?}"),
+        }
+
+        let mut corrupted = payload.clone();
+        corrupted[2] = b'c';
+        assert!(deserialize(&corrupted).is_err());
+        let mut newer = payload[..payload.len() - 8].to_vec();
+        newer[5] = 99;
+        newer.extend(crc64(0, &newer).to_le_bytes());
+        assert!(deserialize(&newer).is_err());
+        assert!(deserialize(b"\x00").is_err());
+    }
+}
@@ -0,0 +1,25 @@
+/// Decodes an intset, the sorted array encoding used for small sets of integers
+pub fn decode(intset: &[u8]) -> anyhow::Result<Vec<i64>> {
+    anyhow::ensure!(intset.len() >= 8, "Invalid intset: too short");
+    let width = u32::from_le_bytes(intset[..4].try_into()?) as usize;
+    let len = u32::from_le_bytes(intset[4..8].try_into()?) as usize;
+    anyhow::ensure!(
+        matches!(width, 2 | 4 | 8),
+        "Invalid intset: unknown encoding {width}"
+    );
+    let contents = &intset[8..];
+    anyhow::ensure!(
+        contents.len() == width * len,
+        "Invalid intset: expected {len} integers of {width} bytes"
+    );
+    contents
+        .chunks(width)
+        .map(|value| {
+            Ok(match width {
+                2 => i16::from_le_bytes(value.try_into()?) as i64,
+                4 => i32::from_le_bytes(value.try_into()?) as i64,
+                _ => i64::from_le_bytes(value.try_into()?),
+            })
+        })
+        .collect()
+}
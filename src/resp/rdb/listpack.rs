@@ -0,0 +1,214 @@
+/// Listpack encoding, the compact list representation used by Redis for stream nodes and small
+/// collections
+use crate::resp::bulk_string::BulkString;
+
+pub const EOF: u8 = 0xff;
+
+/// An element of a listpack, either a string or an integer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Element {
+    String(Vec<u8>),
+    Integer(i64),
+}
+
+impl Element {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Element::String(value) => value.clone(),
+            Element::Integer(value) => value.to_string().into_bytes(),
+        }
+    }
+
+    /// The element as a string, which has to be valid UTF-8 to be stored
+    pub fn to_bulk_string(&self) -> anyhow::Result<BulkString> {
+        let value = String::from_utf8(self.to_bytes())
+            .map_err(|_| anyhow::anyhow!("Unsupported value: not valid UTF-8"))?;
+        Ok(BulkString::encode(&value))
+    }
+
+    /// The integer value of the element, strings holding a number are parsed
+    pub fn to_integer(&self) -> anyhow::Result<i64> {
+        match self {
+            Element::Integer(value) => Ok(*value),
+            Element::String(value) => Ok(std::str::from_utf8(value)?.parse()?),
+        }
+    }
+}
+
+/// Decodes every element of a listpack blob
+pub fn decode(listpack: &[u8]) -> anyhow::Result<Vec<Element>> {
+    anyhow::ensure!(listpack.len() >= 7, "Invalid listpack: too short");
+    let total_bytes = u32::from_le_bytes(listpack[..4].try_into()?) as usize;
+    anyhow::ensure!(
+        total_bytes == listpack.len(),
+        "Invalid listpack: header says {total_bytes} bytes, got {}",
+        listpack.len()
+    );
+    let mut elements = Vec::new();
+    let mut pos = 6;
+    loop {
+        let encoding = *listpack
+            .get(pos)
+            .ok_or_else(|| anyhow::anyhow!("Invalid listpack: missing terminator"))?;
+        if encoding == EOF {
+            break;
+        }
+        let bytes = |start: usize, len: usize| {
+            listpack
+                .get(start..start + len)
+                .ok_or_else(|| anyhow::anyhow!("Invalid listpack: entry out of bounds"))
+        };
+        let (element, entry_len) = match encoding {
+            0x00..=0x7f => (Element::Integer(encoding as i64), 1),
+            0x80..=0xbf => {
+                let len = (encoding & 0x3f) as usize;
+                (Element::String(bytes(pos + 1, len)?.to_vec()), 1 + len)
+            }
+            0xc0..=0xdf => {
+                let raw = ((encoding as u16 & 0x1f) << 8) | bytes(pos + 1, 1)?[0] as u16;
+                // sign extend the 13 bit value
+                let value = ((raw << 3) as i16 >> 3) as i64;
+                (Element::Integer(value), 2)
+            }
+            0xe0..=0xef => {
+                let len = ((encoding as usize & 0x0f) << 8) | bytes(pos + 1, 1)?[0] as usize;
+                (Element::String(bytes(pos + 2, len)?.to_vec()), 2 + len)
+            }
+            0xf0 => {
+                let len = u32::from_le_bytes(bytes(pos + 1, 4)?.try_into()?) as usize;
+                (Element::String(bytes(pos + 5, len)?.to_vec()), 5 + len)
+            }
+            0xf1 => {
+                let value = i16::from_le_bytes(bytes(pos + 1, 2)?.try_into()?);
+                (Element::Integer(value as i64), 3)
+            }
+            0xf2 => {
+                let raw = bytes(pos + 1, 3)?;
+                let value = i32::from_le_bytes([0, raw[0], raw[1], raw[2]]) >> 8;
+                (Element::Integer(value as i64), 4)
+            }
+            0xf3 => {
+                let value = i32::from_le_bytes(bytes(pos + 1, 4)?.try_into()?);
+                (Element::Integer(value as i64), 5)
+            }
+            0xf4 => {
+                let value = i64::from_le_bytes(bytes(pos + 1, 8)?.try_into()?);
+                (Element::Integer(value), 9)
+            }
+            encoding => anyhow::bail!("Invalid listpack: unknown encoding {encoding:#x}"),
+        };
+        elements.push(element);
+        pos += entry_len + encode_backlen(entry_len).len();
+    }
+    Ok(elements)
+}
+
+/// Encodes elements into a listpack blob
+pub fn encode(elements: &[Element]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for element in elements {
+        let start = body.len();
+        match element {
+            Element::Integer(value) => encode_integer(&mut body, *value),
+            Element::String(value) => encode_string(&mut body, value),
+        }
+        let entry_len = body.len() - start;
+        body.extend(encode_backlen(entry_len));
+    }
+    let total_bytes = 4 + 2 + body.len() + 1;
+    let num_elements = u16::try_from(elements.len()).unwrap_or(u16::MAX);
+    let mut listpack = Vec::with_capacity(total_bytes);
+    listpack.extend((total_bytes as u32).to_le_bytes());
+    listpack.extend(num_elements.to_le_bytes());
+    listpack.extend(body);
+    listpack.push(EOF);
+    listpack
+}
+
+fn encode_integer(buf: &mut Vec<u8>, value: i64) {
+    match value {
+        0..=127 => buf.push(value as u8),
+        -4096..=4095 => {
+            let value = (value as u16) & 0x1fff;
+            buf.push(0xc0 | (value >> 8) as u8);
+            buf.push(value as u8);
+        }
+        -32768..=32767 => {
+            buf.push(0xf1);
+            buf.extend((value as i16).to_le_bytes());
+        }
+        -8388608..=8388607 => {
+            buf.push(0xf2);
+            buf.extend(&(value as i32).to_le_bytes()[..3]);
+        }
+        -2147483648..=2147483647 => {
+            buf.push(0xf3);
+            buf.extend((value as i32).to_le_bytes());
+        }
+        _ => {
+            buf.push(0xf4);
+            buf.extend(value.to_le_bytes());
+        }
+    }
+}
+
+fn encode_string(buf: &mut Vec<u8>, value: &[u8]) {
+    match value.len() {
+        len @ 0..=63 => buf.push(0x80 | len as u8),
+        len @ 64..=4095 => {
+            buf.push(0xe0 | (len >> 8) as u8);
+            buf.push(len as u8);
+        }
+        len => {
+            buf.push(0xf0);
+            buf.extend((len as u32).to_le_bytes());
+        }
+    }
+    buf.extend(value);
+}
+
+/// The entry length stored after each entry, so that the listpack can be walked backwards
+fn encode_backlen(len: usize) -> Vec<u8> {
+    match len {
+        0..=127 => vec![len as u8],
+        128..=16382 => vec![(len >> 7) as u8, (len & 127) as u8 | 128],
+        16383..=2097150 => vec![
+            (len >> 14) as u8,
+            ((len >> 7) & 127) as u8 | 128,
+            (len & 127) as u8 | 128,
+        ],
+        2097151..=268435454 => vec![
+            (len >> 21) as u8,
+            ((len >> 14) & 127) as u8 | 128,
+            ((len >> 7) & 127) as u8 | 128,
+            (len & 127) as u8 | 128,
+        ],
+        _ => vec![
+            (len >> 28) as u8,
+            ((len >> 21) & 127) as u8 | 128,
+            ((len >> 14) & 127) as u8 | 128,
+            ((len >> 7) & 127) as u8 | 128,
+            (len & 127) as u8 | 128,
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let elements = vec![
+            Element::Integer(7),
+            Element::Integer(-1),
+            Element::Integer(1000),
+            Element::Integer(-40000),
+            Element::Integer(8_000_000),
+            Element::Integer(i64::MAX),
+            Element::String(b"field".to_vec()),
+            Element::String(vec![b'x'; 300]),
+        ];
+        assert_eq!(decode(&encode(&elements)).unwrap(), elements);
+    }
+}
@@ -0,0 +1,61 @@
+/// Decompresses an LZF block as produced by Redis for compressed RDB strings
+pub fn decompress(input: &[u8], expected_len: usize) -> anyhow::Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(expected_len);
+    let mut ip = 0;
+    while ip < input.len() {
+        let ctrl = input[ip] as usize;
+        ip += 1;
+        if ctrl < 32 {
+            // literal run of ctrl + 1 bytes
+            let run = input
+                .get(ip..ip + ctrl + 1)
+                .ok_or_else(|| anyhow::anyhow!("Invalid LZF data: literal run out of bounds"))?;
+            output.extend_from_slice(run);
+            ip += ctrl + 1;
+        } else {
+            // back reference of len + 2 bytes
+            let mut len = ctrl >> 5;
+            if len == 7 {
+                len += *input
+                    .get(ip)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid LZF data: truncated reference"))?
+                    as usize;
+                ip += 1;
+            }
+            let low = *input
+                .get(ip)
+                .ok_or_else(|| anyhow::anyhow!("Invalid LZF data: truncated reference"))?
+                as usize;
+            ip += 1;
+            let offset = ((ctrl & 0x1f) << 8) + low + 1;
+            anyhow::ensure!(
+                offset <= output.len(),
+                "Invalid LZF data: back reference out of bounds"
+            );
+            let start = output.len() - offset;
+            // the reference may overlap the bytes being written, so copy byte by byte
+            for i in 0..len + 2 {
+                output.push(output[start + i]);
+            }
+        }
+    }
+    anyhow::ensure!(
+        output.len() == expected_len,
+        "Invalid LZF data: expected {expected_len} bytes, got {}",
+        output.len()
+    );
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decompress() {
+        // a literal "a" followed by a back reference repeating it 15 times
+        let compressed = [0x00, b'a', 0xe0, 0x06, 0x00];
+        assert_eq!(decompress(&compressed, 16).unwrap(), b"a".repeat(16));
+        assert!(decompress(&[0x20, 0x05], 3).is_err());
+    }
+}
@@ -0,0 +1,406 @@
+// RDB writer, producing dumps in the version 11 format Redis 7.2 writes
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    db::{DataType, DataValue, StreamData},
+    resp::bulk_string::BulkString,
+};
+
+use super::{
+    constant,
+    crc64::crc64,
+    listpack::{self, Element},
+    op_code, value_type,
+};
+
+pub const RDB_VERSION: &[u8] = b"REDIS0011";
+
+/// Maximum number of entries stored in a single stream listpack node, as `stream-node-max-entries`
+const STREAM_NODE_MAX_ENTRIES: usize = 100;
+
+/// Maximum number of elements stored in a single list listpack node
+const LIST_NODE_MAX_ENTRIES: usize = 128;
+
+/// Quicklist node container holding a listpack
+const QUICKLIST_NODE_PACKED: u64 = 2;
+
+mod stream_flag {
+    pub const NONE: i64 = 0;
+    pub const SAMEFIELDS: i64 = 2;
+}
+
+#[derive(Debug)]
+pub(crate) struct RdbWriter<W> {
+    inner: W,
+    crc: u64,
+}
+
+impl<W: Write> RdbWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, crc: 0 }
+    }
+
+    /// Writes a complete dump of database 0 holding `entries`, followed by the CRC64 trailer
+    pub fn write_rdb(self, entries: &[(BulkString, DataValue)]) -> anyhow::Result<W> {
+        self.write_databases([(0, entries)])
+    }
+
+    /// Writes a complete dump of several databases, given with their index
+    pub fn write_databases<'a>(
+        mut self,
+        databases: impl IntoIterator<Item = (u64, &'a [(BulkString, DataValue)])>,
+    ) -> anyhow::Result<W> {
+        self.write_raw(RDB_VERSION)?;
+        let ctime = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        self.write_aux("redis-ver", "7.2.0")?;
+        self.write_aux("redis-bits", "64")?;
+        self.write_aux("ctime", &ctime.to_string())?;
+        self.write_aux("aof-base", "0")?;
+
+        for (db, entries) in databases {
+            self.write_database(db, entries)?;
+        }
+
+        self.write_raw(&[op_code::EOF])?;
+        let crc = self.crc;
+        self.inner.write_all(&crc.to_le_bytes())?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn write_database(
+        &mut self,
+        db: u64,
+        entries: &[(BulkString, DataValue)],
+    ) -> anyhow::Result<()> {
+        self.write_raw(&[op_code::SELECTDB])?;
+        self.write_length(db)?;
+        let expires = entries
+            .iter()
+            .filter(|(_, value)| {
+                value
+                    .expiry
+                    .as_ref()
+                    .is_some_and(|e| e.expiration().is_some())
+            })
+            .count();
+        self.write_raw(&[op_code::RESIZEDB])?;
+        self.write_length(entries.len() as u64)?;
+        self.write_length(expires as u64)?;
+
+        for (key, value) in entries {
+            if let Some(expiration) = value.expiry.as_ref().and_then(|e| e.expiration()) {
+                let ms = expiration.duration_since(UNIX_EPOCH)?.as_millis() as u64;
+                self.write_raw(&[op_code::EXPIRETIME_MS])?;
+                self.write_raw(&ms.to_le_bytes())?;
+            }
+            self.write_entry(key, &value.value)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a single value with its type and no key, as the body of a `DUMP` payload
+    pub fn write_object(mut self, value: &DataType) -> anyhow::Result<W> {
+        self.write_raw(&[object_type(value)])?;
+        self.write_value(value)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn write_entry(&mut self, key: &BulkString, value: &DataType) -> anyhow::Result<()> {
+        self.write_raw(&[object_type(value)])?;
+        self.write_string(key.data.as_bytes())?;
+        self.write_value(value)
+    }
+
+    fn write_value(&mut self, value: &DataType) -> anyhow::Result<()> {
+        match value {
+            DataType::String(value) => self.write_string(value.data.as_bytes()),
+            DataType::List(list) => {
+                let nodes: Vec<_> = list.iter().collect();
+                let nodes: Vec<_> = nodes.chunks(LIST_NODE_MAX_ENTRIES).collect();
+                self.write_length(nodes.len() as u64)?;
+                for node in nodes {
+                    let elements: Vec<_> = node
+                        .iter()
+                        .map(|value| Element::String(value.data.as_bytes().to_vec()))
+                        .collect();
+                    self.write_length(QUICKLIST_NODE_PACKED)?;
+                    self.write_string(&listpack::encode(&elements))?;
+                }
+                Ok(())
+            }
+            DataType::Set(set) => {
+                self.write_length(set.len() as u64)?;
+                set.iter()
+                    .try_for_each(|member| self.write_string(member.data.as_bytes()))
+            }
+            DataType::ZSet(zset) => {
+                self.write_length(zset.len() as u64)?;
+                // like Redis, write from the highest score down so that loading inserts at the head
+                zset.iter().rev().try_for_each(|(member, score)| {
+                    self.write_string(member.data.as_bytes())?;
+                    self.write_raw(&score.to_le_bytes())
+                })
+            }
+            DataType::Hash(hash) => {
+                self.write_length(hash.len() as u64)?;
+                hash.iter().try_for_each(|(field, value)| {
+                    self.write_string(field.data.as_bytes())?;
+                    self.write_string(value.data.as_bytes())
+                })
+            }
+            DataType::Stream(stream) => self.write_stream(stream),
+        }
+    }
+
+    /// Writes a stream as listpack nodes keyed by their master entry ID, followed by the stream
+    /// metadata. Consumer groups are not supported, so none are written.
+    fn write_stream(&mut self, stream: &VecDeque<StreamData>) -> anyhow::Result<()> {
+        let entries = stream
+            .iter()
+            .map(|entry| Ok((parse_id(&entry.id)?, entry)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let nodes: Vec<_> = entries.chunks(STREAM_NODE_MAX_ENTRIES).collect();
+        self.write_length(nodes.len() as u64)?;
+        for node in &nodes {
+            let master_id = node[0].0;
+            let mut key = master_id.0.to_be_bytes().to_vec();
+            key.extend(master_id.1.to_be_bytes());
+            self.write_string(&key)?;
+            self.write_string(&stream_node(node))?;
+        }
+
+        let first_id = entries.first().map_or((0, 0), |(id, _)| *id);
+        let last_id = entries.last().map_or((0, 0), |(id, _)| *id);
+        self.write_length(entries.len() as u64)?;
+        for (ms, seq) in [last_id, first_id, (0, 0)] {
+            self.write_length(ms)?;
+            self.write_length(seq)?;
+        }
+        // entries added, then the number of consumer groups
+        self.write_length(entries.len() as u64)?;
+        self.write_length(0)
+    }
+
+    fn write_aux(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        self.write_raw(&[op_code::AUX])?;
+        self.write_string(key.as_bytes())?;
+        self.write_string(value.as_bytes())
+    }
+
+    fn write_string(&mut self, value: &[u8]) -> anyhow::Result<()> {
+        self.write_length(value.len() as u64)?;
+        self.write_raw(value)
+    }
+
+    fn write_length(&mut self, length: u64) -> anyhow::Result<()> {
+        match length {
+            0..=0x3f => self.write_raw(&[length as u8]),
+            0x40..=0x3fff => self.write_raw(&[0x40 | (length >> 8) as u8, length as u8]),
+            0x4000..=0xffff_ffff => {
+                self.write_raw(&[constant::RDB_32BITLEN])?;
+                self.write_raw(&(length as u32).to_be_bytes())
+            }
+            _ => {
+                self.write_raw(&[constant::RDB_64BITLEN])?;
+                self.write_raw(&length.to_be_bytes())
+            }
+        }
+    }
+
+    fn write_raw(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.crc = crc64(self.crc, bytes);
+        self.inner.write_all(bytes)?;
+        Ok(())
+    }
+}
+
+/// Builds the listpack of a stream node. The fields of the first entry become the master fields,
+/// and entries with the same fields only store their values.
+fn stream_node(node: &[((u64, u64), &StreamData)]) -> Vec<u8> {
+    let ((master_ms, master_seq), master) = node[0];
+    let mut elements = vec![
+        Element::Integer(node.len() as i64),
+        Element::Integer(0),
+        Element::Integer(master.map.len() as i64),
+    ];
+    elements.extend(
+        master
+            .map
+            .keys()
+            .map(|field| Element::String(field.data.as_bytes().to_vec())),
+    );
+    elements.push(Element::Integer(0));
+
+    for ((ms, seq), entry) in node {
+        let same_fields = entry.map.keys().eq(master.map.keys());
+        let flag = match same_fields {
+            true => stream_flag::SAMEFIELDS,
+            false => stream_flag::NONE,
+        };
+        elements.push(Element::Integer(flag));
+        elements.push(Element::Integer((ms - master_ms) as i64));
+        elements.push(Element::Integer(seq.wrapping_sub(master_seq) as i64));
+        if !same_fields {
+            elements.push(Element::Integer(entry.map.len() as i64));
+        }
+        for (field, value) in &entry.map {
+            if !same_fields {
+                elements.push(Element::String(field.data.as_bytes().to_vec()));
+            }
+            elements.push(Element::String(value.data.as_bytes().to_vec()));
+        }
+        // number of elements of the entry, so that the node can be walked backwards
+        let lp_count = match same_fields {
+            true => entry.map.len() + 3,
+            false => entry.map.len() * 2 + 4,
+        };
+        elements.push(Element::Integer(lp_count as i64));
+    }
+    listpack::encode(&elements)
+}
+
+fn parse_id(id: &BulkString) -> anyhow::Result<(u64, u64)> {
+    let (ms, seq) = id
+        .data
+        .split_once('-')
+        .ok_or_else(|| anyhow::anyhow!("Invalid stream ID {}", id.data.as_str()))?;
+    Ok((ms.parse()?, seq.parse()?))
+}
+
+/// The RDB type a value is written as
+fn object_type(value: &DataType) -> u8 {
+    match value {
+        DataType::String(_) => value_type::STRING,
+        DataType::List(_) => value_type::LIST_QUICKLIST_2,
+        DataType::Set(_) => value_type::SET,
+        DataType::ZSet(_) => value_type::ZSET_2,
+        DataType::Hash(_) => value_type::HASH,
+        DataType::Stream(_) => value_type::STREAM_LISTPACKS_3,
+    }
+}
+
+/// Writes the dump to a temporary file next to `path` and renames it into place, so that a
+/// crash while saving never leaves a truncated dump behind
+pub(crate) fn write_file(path: &Path, entries: &[(BulkString, DataValue)]) -> anyhow::Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let temp_path = dir.join(format!("temp-{}.rdb", std::process::id()));
+    let result = (|| {
+        let file = File::create(&temp_path)?;
+        let writer = RdbWriter::new(BufWriter::new(file)).write_rdb(entries)?;
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use dashmap::DashMap;
+
+    use indexmap::IndexMap;
+
+    use super::*;
+    use crate::{db::SetConfig, resp::rdb::Rdb};
+
+    #[test]
+    fn test_write_rdb_round_trip() {
+        let expiration = UNIX_EPOCH + Duration::from_millis(4_102_444_800_000);
+        let entries = vec![
+            (
+                BulkString::encode("foo"),
+                DataValue {
+                    value: DataType::String(BulkString::encode("bar")),
+                    expiry: None,
+                },
+            ),
+            (
+                BulkString::encode("session"),
+                DataValue {
+                    value: DataType::String(BulkString::encode("x".repeat(100).as_str())),
+                    expiry: Some(SetConfig::from_expiration(expiration)),
+                },
+            ),
+        ];
+        let dump = RdbWriter::new(Vec::new()).write_rdb(&entries).unwrap();
+        assert_eq!(&dump[..9], RDB_VERSION);
+        let (body, checksum) = dump.split_at(dump.len() - 8);
+        assert_eq!(crc64(0, body).to_le_bytes(), checksum);
+
+        let values = DashMap::new();
+        Rdb::new(dump.as_slice()).read_rdb_to_map(&values).unwrap();
+        assert_eq!(values.len(), 2);
+        let session = values.get(&BulkString::encode("session")).unwrap();
+        assert_eq!(
+            session.expiry.as_ref().unwrap().expiration(),
+            Some(expiration)
+        );
+    }
+
+    #[test]
+    fn test_round_trip_all_types() {
+        let bulk = |s: &str| BulkString::encode(s);
+        let stream = (1..=150)
+            .map(|i| StreamData {
+                id: bulk(&format!("{}-{}", 1000 + i / 3, i % 3)),
+                map: match i % 4 {
+                    0 => IndexMap::from([(bulk("other"), bulk(&i.to_string()))]),
+                    _ => IndexMap::from([(bulk("a"), bulk("1")), (bulk("b"), bulk("x"))]),
+                },
+            })
+            .collect();
+        let values = [
+            DataType::List((0..300).map(|i| bulk(&i.to_string())).collect()),
+            DataType::Set([bulk("a"), bulk("b")].into_iter().collect()),
+            DataType::ZSet(
+                [(bulk("low"), -1.5), (bulk("high"), 2.0)]
+                    .into_iter()
+                    .collect(),
+            ),
+            DataType::Hash([(bulk("field"), bulk("value"))].into_iter().collect()),
+            DataType::Stream(stream),
+        ];
+        let entries: Vec<_> = values
+            .into_iter()
+            .enumerate()
+            .map(|(i, value)| {
+                let value = DataValue {
+                    value,
+                    expiry: None,
+                };
+                (bulk(&format!("key{i}")), value)
+            })
+            .collect();
+        let mut dump = RdbWriter::new(Vec::new()).write_rdb(&entries).unwrap();
+
+        let mut loaded = Vec::new();
+        Rdb::new(dump.as_slice())
+            .read_entries(|entry| {
+                loaded.push((entry.key, entry.value));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(format!("{loaded:?}"), format!("{entries:?}"));
+
+        // flip a bit of the last key
+        let len = dump.len();
+        dump[len - 20] ^= 1;
+        let err = Rdb::new(dump.as_slice())
+            .read_entries(|_| Ok(()))
+            .unwrap_err();
+        assert!(err.to_string().contains("checksum"), "{err}");
+    }
+}
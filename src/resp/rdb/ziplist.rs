@@ -0,0 +1,97 @@
+/// Ziplist encoding, used by RDB files written before Redis 7 for small lists, hashes and
+/// sorted sets
+use super::listpack::Element;
+
+const END: u8 = 0xff;
+
+/// Decodes every element of a ziplist blob
+pub fn decode(ziplist: &[u8]) -> anyhow::Result<Vec<Element>> {
+    anyhow::ensure!(ziplist.len() >= 11, "Invalid ziplist: too short");
+    let total_bytes = u32::from_le_bytes(ziplist[..4].try_into()?) as usize;
+    anyhow::ensure!(
+        total_bytes == ziplist.len(),
+        "Invalid ziplist: header says {total_bytes} bytes, got {}",
+        ziplist.len()
+    );
+    let bytes = |start: usize, len: usize| {
+        ziplist
+            .get(start..start + len)
+            .ok_or_else(|| anyhow::anyhow!("Invalid ziplist: entry out of bounds"))
+    };
+    let mut elements = Vec::new();
+    let mut pos = 10;
+    loop {
+        let first = bytes(pos, 1)?[0];
+        if first == END {
+            break;
+        }
+        // length of the previous entry, 1 byte or 0xfe followed by 4 bytes
+        pos += if first == 0xfe { 5 } else { 1 };
+        let encoding = bytes(pos, 1)?[0];
+        let (element, entry_len) = match encoding >> 6 {
+            0 => {
+                let len = (encoding & 0x3f) as usize;
+                (Element::String(bytes(pos + 1, len)?.to_vec()), 1 + len)
+            }
+            1 => {
+                let len = ((encoding as usize & 0x3f) << 8) | bytes(pos + 1, 1)?[0] as usize;
+                (Element::String(bytes(pos + 2, len)?.to_vec()), 2 + len)
+            }
+            2 => {
+                let len = u32::from_be_bytes(bytes(pos + 1, 4)?.try_into()?) as usize;
+                (Element::String(bytes(pos + 5, len)?.to_vec()), 5 + len)
+            }
+            _ => match encoding {
+                0xc0 => {
+                    let value = i16::from_le_bytes(bytes(pos + 1, 2)?.try_into()?);
+                    (Element::Integer(value as i64), 3)
+                }
+                0xd0 => {
+                    let value = i32::from_le_bytes(bytes(pos + 1, 4)?.try_into()?);
+                    (Element::Integer(value as i64), 5)
+                }
+                0xe0 => {
+                    let value = i64::from_le_bytes(bytes(pos + 1, 8)?.try_into()?);
+                    (Element::Integer(value), 9)
+                }
+                0xf0 => {
+                    let raw = bytes(pos + 1, 3)?;
+                    let value = i32::from_le_bytes([0, raw[0], raw[1], raw[2]]) >> 8;
+                    (Element::Integer(value as i64), 4)
+                }
+                0xfe => {
+                    let value = bytes(pos + 1, 1)?[0] as i8;
+                    (Element::Integer(value as i64), 2)
+                }
+                // 4 bit immediate between 0 and 12, stored as 1 to 13
+                0xf1..=0xfd => (Element::Integer((encoding & 0x0f) as i64 - 1), 1),
+                encoding => anyhow::bail!("Invalid ziplist: unknown encoding {encoding:#x}"),
+            },
+        };
+        elements.push(element);
+        pos += entry_len;
+    }
+    Ok(elements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        // ["a", 5, 300] as written by Redis 6
+        let ziplist = [
+            0x14, 0x00, 0x00, 0x00, 0x0e, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x01, b'a', 0x03,
+            0xf6, 0x02, 0xc0, 0x2c, 0x01, 0xff,
+        ];
+        assert_eq!(
+            decode(&ziplist).unwrap(),
+            vec![
+                Element::String(b"a".to_vec()),
+                Element::Integer(5),
+                Element::Integer(300)
+            ]
+        );
+    }
+}
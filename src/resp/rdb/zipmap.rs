@@ -0,0 +1,36 @@
+/// Decodes a zipmap, the hash encoding of RDB files written before Redis 2.6
+pub fn decode(zipmap: &[u8]) -> anyhow::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let bytes = |start: usize, len: usize| {
+        zipmap
+            .get(start..start + len)
+            .ok_or_else(|| anyhow::anyhow!("Invalid zipmap: entry out of bounds"))
+    };
+    // lengths below 254 take one byte, 254 is followed by a 4 byte length
+    let read_len = |pos: &mut usize| -> anyhow::Result<Option<usize>> {
+        let first = bytes(*pos, 1)?[0];
+        *pos += 1;
+        match first {
+            0xff => Ok(None),
+            0xfe => {
+                let len = u32::from_le_bytes(bytes(*pos, 4)?.try_into()?) as usize;
+                *pos += 4;
+                Ok(Some(len))
+            }
+            len => Ok(Some(len as usize)),
+        }
+    };
+    let mut pairs = Vec::new();
+    // the first byte is the number of pairs, which is not reliable past 253
+    let mut pos = 1;
+    while let Some(key_len) = read_len(&mut pos)? {
+        let key = bytes(pos, key_len)?.to_vec();
+        pos += key_len;
+        let value_len =
+            read_len(&mut pos)?.ok_or_else(|| anyhow::anyhow!("Invalid zipmap: missing value"))?;
+        let free = bytes(pos, 1)?[0] as usize;
+        let value = bytes(pos + 1, value_len)?.to_vec();
+        pos += 1 + value_len + free;
+        pairs.push((key, value));
+    }
+    Ok(pairs)
+}
@@ -1,27 +1,76 @@
 use std::{
+    io::Read,
     net::SocketAddr,
     sync::{
         atomic::{AtomicUsize, Ordering},
-        Arc, Mutex,
+        Arc, Mutex, PoisonError, RwLock,
     },
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use bytes::BytesMut;
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
-use tokio::{sync::broadcast::Receiver, time::timeout};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::{mpsc, Notify},
+    task::JoinSet,
+    time::{timeout, timeout_at, Instant},
+};
 
 use crate::{
-    db::{Database, InputData, StreamData},
-    resp::{bulk_string::BulkString, InfoArg, RedisData},
+    aof::{Aof, FsyncPolicy},
+    config::{parse_memory, parse_yes_no, AofConfig},
+    db::{DataValue, Database, InputData, SetConfig, StreamData, NULL_ARRAY},
+    pubsub::PubSub,
+    replica::{Replicas, DEFAULT_REPL_TIMEOUT},
+    resp::{
+        bulk_string::BulkString,
+        frame,
+        rdb::{
+            dump,
+            writer::{self, RdbWriter},
+        },
+        InfoArg, MigrateArgs, PubsubArg, RedisData,
+    },
 };
 
+/// Length of the delimiter that ends a diskless snapshot
+const EOF_MARK_LEN: usize = 40;
+
+/// Asks the replicas for the offset they processed
+const GETACK: &[u8] = b"*3\r\n$8\r\nREPLCONF\r\n$6\r\nGETACK\r\n$1\r\n*\r\n";
+
 #[derive(Debug)]
 pub struct ReplicaConfig {
     replid: String,
     repl_offset: AtomicUsize,
-    num_replicas: AtomicUsize,
+    /// the previous replication ID, which replicas of the former primary can still continue
+    /// from up to `second_repl_offset`
+    replid2: String,
+    second_repl_offset: Option<usize>,
+    /// whether the dataset comes from the current primary, so that a new link can continue
+    /// its stream
+    synced: bool,
     role: Role,
+    /// incremented whenever the role changes, so that the replication link started for an
+    /// earlier role stops
+    epoch: u64,
+    link: PrimaryLink,
+    /// how long the link to the primary can stay silent before it is considered lost
+    repl_timeout: Duration,
+    /// whether a replica refuses writes from its clients
+    read_only: bool,
+}
+
+/// The state of a replica's link to its primary
+#[derive(Debug, Default)]
+struct PrimaryLink {
+    up: bool,
+    sync_in_progress: bool,
+    /// when data was last received from the primary
+    last_io: Option<Instant>,
+    /// when the link was lost, `None` if it was never up
+    down_since: Option<Instant>,
 }
 
 #[derive(Debug, Clone)]
@@ -33,80 +82,423 @@ pub struct MasterConfig {
 #[derive(Debug, Clone)]
 pub enum Role {
     Master,
-    Slave,
+    Slave(MasterConfig),
 }
 
 impl ReplicaConfig {
-    fn generate_response(&self) -> String {
-        match self.role {
-            Role::Slave => {
-                format!(
-                    "# Replication\r\nrole:slave\r\nmaster_replid:{}\r\nmaster_repl_offset:{}\r\n",
-                    self.replid,
-                    self.repl_offset.load(Ordering::Relaxed)
-                )
-            }
-            Role::Master => {
-                format!(
-                    "# Replication\r\nrole:master\r\nmaster_replid:{}\r\nmaster_repl_offset:{}\r\n",
-                    self.replid,
-                    self.repl_offset.load(Ordering::Relaxed)
-                )
+    fn generate_response(&self, replicas: &Replicas) -> String {
+        let role = match &self.role {
+            Role::Slave(primary) => {
+                let seconds = |instant: Option<Instant>| {
+                    instant.map_or(-1, |instant| instant.elapsed().as_secs() as i64)
+                };
+                let mut role = format!(
+                    "slave\r\nmaster_host:{}\r\nmaster_port:{}\r\nmaster_link_status:{}\r\nmaster_last_io_seconds_ago:{}\r\nmaster_sync_in_progress:{}",
+                    primary.host,
+                    primary.port,
+                    if self.link.up { "up" } else { "down" },
+                    seconds(self.link.last_io),
+                    self.link.sync_in_progress as u8
+                );
+                if !self.link.up {
+                    role.push_str(&format!(
+                        "\r\nmaster_link_down_since_seconds:{}",
+                        seconds(self.link.down_since)
+                    ));
+                }
+                role
             }
-        }
+            Role::Master => "master".to_owned(),
+        };
+        format!(
+            "# Replication\r\nrole:{role}\r\n{}master_replid:{}\r\nmaster_replid2:{}\r\nmaster_repl_offset:{}\r\nsecond_repl_offset:{}\r\n{}",
+            replicas.info(),
+            self.replid,
+            self.replid2,
+            self.repl_offset.load(Ordering::Relaxed),
+            self.second_repl_offset.map_or(-1, |offset| offset as i64),
+            replicas.backlog_info()
+        )
+    }
+
+    /// Whether a replica that followed `replid` up to `offset` can continue from this stream
+    fn can_continue(&self, replid: &str, offset: usize) -> bool {
+        replid == self.replid
+            || (replid == self.replid2
+                && self
+                    .second_repl_offset
+                    .is_some_and(|second| offset <= second))
+    }
+
+    /// Starts a new history, keeping the current one as the second ID so that the replicas
+    /// that followed it can continue
+    fn shift_replid(&mut self, replid: String) {
+        self.replid2 = std::mem::replace(&mut self.replid, replid);
+        self.second_repl_offset = Some(self.repl_offset.load(Ordering::Relaxed) + 1);
     }
 }
 
+fn random_replid() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .map(char::from)
+        .take(40)
+        .collect()
+}
+
 #[derive(Debug, Clone)]
 pub struct State {
     replica_config: Arc<Mutex<ReplicaConfig>>,
     db: Arc<Database>,
+    /// Commands hold the read side while they run, `EXEC` holds the write side so that no
+    /// other connection interleaves with a transaction. It guards no data, so the poisoning left
+    /// by a command that panicked is ignored.
+    command_lock: Arc<RwLock<()>>,
+    pubsub: Arc<PubSub>,
+    aof: Arc<Aof>,
+    replicas: Arc<Replicas>,
+    /// notified when the role changes
+    role_change: Arc<Notify>,
 }
 
 impl State {
-    pub fn new(replicaof: bool, db: Database) -> Self {
+    pub fn new(primary: Option<MasterConfig>, db: Database) -> Self {
         let replica_config = ReplicaConfig {
-            replid: thread_rng()
-                .sample_iter(&Alphanumeric)
-                .map(char::from)
-                .take(40)
-                .collect(),
+            replid: random_replid(),
             repl_offset: AtomicUsize::new(0),
-            num_replicas: AtomicUsize::new(0),
-            role: match replicaof {
-                true => Role::Slave,
-                false => Role::Master,
+            replid2: "0".repeat(40),
+            second_repl_offset: None,
+            synced: false,
+            role: match primary {
+                Some(primary) => Role::Slave(primary),
+                None => Role::Master,
             },
+            epoch: 0,
+            link: PrimaryLink::default(),
+            repl_timeout: Duration::from_secs(DEFAULT_REPL_TIMEOUT),
+            read_only: true,
         };
 
         Self {
             replica_config: Arc::new(Mutex::new(replica_config)),
+            command_lock: Arc::new(RwLock::new(())),
+            pubsub: db.notifier().pubsub(),
             db: Arc::new(db),
+            aof: Arc::new(Aof::disabled(AofConfig::default())),
+            replicas: Arc::new(Replicas::default()),
+            role_change: Arc::new(Notify::new()),
         }
     }
 
-    pub fn increment_offset(&self, increment: usize) {
-        self.replica_config
-            .lock()
-            .unwrap()
+    /// Replaces the AOF, before the state is shared with the connections
+    pub fn with_aof(mut self, aof: Aof) -> Self {
+        self.aof = Arc::new(aof);
+        self
+    }
+
+    /// Records a write that was applied, in the RESP form it is replayed from, and sends it to
+    /// the replicas. Writes have to be propagated while the command lock is held so that they
+    /// are ordered with the snapshots sent to new replicas.
+    pub fn propagate(&self, request: &[u8]) {
+        // the AOF gets the writes in the order of the replication stream, so that the offset
+        // that covers a write tells whether it is on disk
+        let replica_config = self.replica_config.lock().unwrap();
+        // a replica forwards the stream of its primary instead, after the write
+        let is_master = matches!(replica_config.role, Role::Master);
+        let offset = replica_config.repl_offset.load(Ordering::Relaxed);
+        let offset = offset + if is_master { request.len() } else { 0 };
+        if let Err(e) = self.aof.append(request, offset) {
+            eprintln!("Failed to write to the AOF: {e}");
+        }
+        if is_master {
+            self.replicas.send(request);
+            replica_config
+                .repl_offset
+                .fetch_add(request.len(), Ordering::Acquire);
+        }
+    }
+
+    fn is_master(&self) -> bool {
+        matches!(self.replica_config.lock().unwrap().role, Role::Master)
+    }
+
+    /// Appends data to the replication stream, advancing the replication offset
+    pub fn replicate(&self, data: &[u8]) {
+        let replica_config = self.replica_config.lock().unwrap();
+        self.replicas.send(data);
+        replica_config
             .repl_offset
-            .fetch_add(increment, Ordering::Acquire);
+            .fetch_add(data.len(), Ordering::Acquire);
     }
 
-    pub fn increment_num_replicas(&self) {
-        self.replica_config
-            .lock()
-            .unwrap()
-            .num_replicas
-            .fetch_add(1, Ordering::Acquire);
+    /// Advances the replication offset over a write from the primary that could not be applied,
+    /// keeping it in line with the primary. The replicas of this instance would miss the write
+    /// in their stream, so they are dropped along with the backlog to sync again from the
+    /// dataset, which lacks it too.
+    fn skip_replicated(&self, request: &[u8]) {
+        let replica_config = self.replica_config.lock().unwrap();
+        let offset = replica_config
+            .repl_offset
+            .fetch_add(request.len(), Ordering::Acquire);
+        self.replicas.reset_backlog(offset + request.len());
+        self.replicas.disconnect_all();
+    }
+
+    /// Pings the replicas every `repl-ping-replica-period` so that they can tell an idle
+    /// primary from a lost one, and drops the replication backlog of a primary without
+    /// replicas for `repl-backlog-ttl`
+    pub fn replication_cron(&self) {
+        if !self.is_master() {
+            return;
+        }
+        if self.replicas.ping_due() {
+            self.replicate(b"*1\r\n$4\r\nPING\r\n");
+        }
+        self.replicas.expire_backlog();
+    }
+
+    /// The primary this instance replicates, if it is a replica
+    pub fn primary(&self) -> Option<MasterConfig> {
+        match &self.replica_config.lock().unwrap().role {
+            Role::Slave(primary) => Some(primary.clone()),
+            Role::Master => None,
+        }
+    }
+
+    pub fn repl_timeout(&self) -> Duration {
+        self.replica_config.lock().unwrap().repl_timeout
+    }
+
+    pub fn set_repl_timeout(&self, timeout: Duration) {
+        self.replica_config.lock().unwrap().repl_timeout = timeout;
+    }
+
+    pub fn set_replica_read_only(&self, read_only: bool) {
+        self.replica_config.lock().unwrap().read_only = read_only;
+    }
+
+    /// Whether writes from clients are refused, because this is a read only replica. The
+    /// writes of the primary are applied by the replication link.
+    pub fn is_read_only(&self) -> bool {
+        let replica_config = self.replica_config.lock().unwrap();
+        replica_config.read_only && matches!(replica_config.role, Role::Slave(_))
+    }
+
+    /// Whether writes are refused because a primary has fewer good replicas than
+    /// `min-replicas-to-write`
+    pub fn lacks_good_replicas(&self) -> bool {
+        self.is_master() && !self.replicas.enough_good()
+    }
+
+    /// Records that data was received from the primary
+    pub fn link_io(&self) {
+        self.replica_config.lock().unwrap().link.last_io = Some(Instant::now());
+    }
+
+    /// Records that the replica asked its primary for a resync and waits for the answer
+    pub fn link_syncing(&self) {
+        self.replica_config.lock().unwrap().link.sync_in_progress = true;
+    }
+
+    /// Records that the replica follows the stream of its primary
+    pub fn link_up(&self) {
+        let link = &mut self.replica_config.lock().unwrap().link;
+        link.up = true;
+        link.sync_in_progress = false;
+        link.down_since = None;
+    }
+
+    /// Records that the link to the primary was lost, returning whether it was up
+    pub fn link_down(&self) -> bool {
+        let link = &mut self.replica_config.lock().unwrap().link;
+        let was_up = link.up;
+        link.up = false;
+        link.sync_in_progress = false;
+        if was_up {
+            link.down_since = Some(Instant::now());
+        }
+        was_up
+    }
+
+    /// The arguments of the `PSYNC` a replica sends: the stream it follows and the offset of
+    /// the next byte it needs, or `? -1` to ask for a full resync
+    pub fn psync_args(&self) -> (String, String) {
+        let replica_config = self.replica_config.lock().unwrap();
+        match replica_config.synced {
+            true => (
+                replica_config.replid.clone(),
+                (replica_config.repl_offset.load(Ordering::Relaxed) + 1).to_string(),
+            ),
+            false => ("?".to_owned(), "-1".to_owned()),
+        }
+    }
+
+    /// Follows the stream of the primary after `+CONTINUE`, which names the new replication ID
+    /// when the primary's history changed since the last link
+    pub fn continue_replication(&self, replid: Option<&str>) {
+        let mut replica_config = self.replica_config.lock().unwrap();
+        if let Some(replid) = replid.filter(|replid| *replid != replica_config.replid) {
+            replica_config.shift_replid(replid.to_owned());
+            // the replicas of this replica continue with the new ID after syncing again
+            self.replicas.disconnect_all();
+        }
+    }
+
+    /// Makes this instance a replica of `primary`, or a primary with `None`. Returns the epoch
+    /// of the replication link to start when it became the replica of a new primary.
+    pub fn replicaof(&self, primary: Option<MasterConfig>) -> Option<u64> {
+        let command_lock = self.command_lock.clone();
+        let _guard = command_lock.write().unwrap_or_else(PoisonError::into_inner);
+        let mut replica_config = self.replica_config.lock().unwrap();
+        match (&replica_config.role, primary) {
+            (Role::Master, None) => return None,
+            (Role::Slave(current), Some(primary))
+                if current.host == primary.host && current.port == primary.port =>
+            {
+                return None
+            }
+            (Role::Slave(_), None) => {
+                // the replicas of this instance can continue with the history they followed
+                replica_config.shift_replid(random_replid());
+                replica_config.role = Role::Master;
+                println!("MASTER MODE enabled");
+            }
+            (_, Some(primary)) => {
+                // the dataset is where this instance's history got to, which the new primary
+                // can continue from when it followed the same history
+                replica_config.synced = true;
+                println!("REPLICAOF {}:{} enabled", primary.host, primary.port);
+                replica_config.role = Role::Slave(primary);
+            }
+        }
+        replica_config.epoch += 1;
+        replica_config.link = PrimaryLink::default();
+        self.role_change.notify_waiters();
+        // the replicas learn about the new replication ID or primary when they sync again
+        self.replicas.disconnect_all();
+        let is_replica = matches!(replica_config.role, Role::Slave(_));
+        is_replica.then_some(replica_config.epoch)
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.replica_config.lock().unwrap().epoch
+    }
+
+    /// Resolves once the role changed after `epoch`
+    pub async fn role_changed(&self, epoch: u64) {
+        loop {
+            // created before the check so that a change in between still wakes it
+            let notified = self.role_change.notified();
+            if self.epoch() != epoch {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// The reply to `ROLE`
+    fn role(&self) -> String {
+        let replica_config = self.replica_config.lock().unwrap();
+        let offset = replica_config.repl_offset.load(Ordering::Relaxed);
+        match &replica_config.role {
+            Role::Master => {
+                let replicas = self.replicas.list();
+                let mut response =
+                    format!("*3\r\n$6\r\nmaster\r\n:{offset}\r\n*{}\r\n", replicas.len());
+                for (addr, ack_offset) in replicas {
+                    let entry = [
+                        addr.ip().to_string(),
+                        addr.port().to_string(),
+                        ack_offset.to_string(),
+                    ];
+                    response.push_str("*3\r\n");
+                    for field in entry {
+                        response.push_str(&BulkString::encode(&field).decode());
+                    }
+                }
+                response
+            }
+            Role::Slave(primary) => {
+                let link = &replica_config.link;
+                let state = match (link.up, link.sync_in_progress) {
+                    (true, _) => "connected",
+                    (false, true) => "sync",
+                    (false, false) => "connecting",
+                };
+                let offset = match replica_config.synced {
+                    true => offset as i64,
+                    false => -1,
+                };
+                format!(
+                    "*5\r\n$5\r\nslave\r\n{}:{}\r\n{}:{offset}\r\n",
+                    BulkString::encode(&primary.host).decode(),
+                    primary.port,
+                    BulkString::encode(state).decode()
+                )
+            }
+        }
+    }
+
+    pub fn replicas(&self) -> &Replicas {
+        &self.replicas
+    }
+
+    /// Flushes the AOF and starts a rewrite once it grew past the `auto-aof-rewrite-*` limits
+    pub fn aof_cron(&self) {
+        // every write counted in the offset was appended already
+        let offset = self.offset();
+        self.aof.cron(offset);
+        if !self.aof.should_rewrite() {
+            return;
+        }
+        let command_lock = self.command_lock.clone();
+        let _guard = command_lock.write().unwrap_or_else(PoisonError::into_inner);
+        if let Err(e) = self.bgrewriteaof() {
+            println!("Background append only file rewriting failed to start: {e}");
+        }
+    }
+
+    pub fn aof_fsynced_offset(&self) -> usize {
+        self.aof.fsynced_offset()
+    }
+
+    /// Takes a consistent point-in-time copy of the dataset
+    pub(crate) fn snapshot(&self) -> Vec<(BulkString, DataValue)> {
+        let command_lock = self.command_lock.clone();
+        let _guard = command_lock.write().unwrap_or_else(PoisonError::into_inner);
+        self.db.snapshot()
+    }
+
+    pub(crate) fn load_rdb(&self, reader: impl Read) -> anyhow::Result<()> {
+        self.db.load_rdb(reader)
+    }
+
+    /// Replaces the dataset with the snapshot of a full resync and takes over the replication
+    /// ID and offset of the primary
+    pub fn replace_dataset(&self, db: Database, replid: String, offset: usize) {
+        let command_lock = self.command_lock.clone();
+        let _guard = command_lock.write().unwrap_or_else(PoisonError::into_inner);
+        self.db.replace_with(db);
+        let mut replica_config = self.replica_config.lock().unwrap();
+        replica_config.replid = replid;
+        replica_config.replid2 = "0".repeat(40);
+        replica_config.second_repl_offset = None;
+        replica_config.repl_offset.store(offset, Ordering::Release);
+        replica_config.synced = true;
+        self.replicas.reset_backlog(offset);
+        drop(replica_config);
+        // the replicas of this replica hold a dataset that no longer matches
+        self.replicas.disconnect_all();
+        // the AOF still describes the dataset that was just dropped
+        if self.aof.is_enabled() {
+            if let Err(e) = self.bgrewriteaof() {
+                println!("Background append only file rewriting failed to start: {e}");
+            }
+        }
     }
 
     pub fn replica_count(&self) -> usize {
-        self.replica_config
-            .lock()
-            .unwrap()
-            .num_replicas
-            .load(Ordering::Relaxed)
+        self.replicas.len()
     }
 
     pub fn offset(&self) -> usize {
@@ -117,73 +509,686 @@ impl State {
             .load(Ordering::Relaxed)
     }
 
-    pub async fn count_synced_replicas(
+    /// Blocks until `numreplicas` replicas acknowledged `offset`, or until the timeout in
+    /// milliseconds elapses (0 blocks forever), returning how many did
+    pub async fn wait(
         &self,
-        target_num_replicas: BulkString,
-        timeout_duration: BulkString,
-        mut rx: Receiver<(usize, SocketAddr)>,
+        numreplicas: &BulkString,
+        timeout: &BulkString,
+        offset: usize,
     ) -> anyhow::Result<usize> {
-        let connected_replicas = self.replica_count();
-        let primary_offset = self.offset();
-        println!("connected replicas: {connected_replicas}");
-        println!("primary offset: {primary_offset}");
-        if primary_offset == 0 {
-            return Ok(connected_replicas);
-        }
-        let target_num_replicas: usize = target_num_replicas.data.parse()?;
-        let target_count = target_num_replicas.min(connected_replicas);
-        let timeout_duration = {
-            let timeout_duration: u64 = timeout_duration.data.parse()?;
-            Duration::from_millis(timeout_duration)
-        };
-        let mut synced_replicas = 0;
-        while let Ok(res) = timeout(timeout_duration, rx.recv()).await {
-            match res {
-                Ok((offset, socket_addr)) => {
-                    println!("replica_offset: {offset} from {socket_addr}");
-                    if offset >= primary_offset {
-                        synced_replicas += 1;
-                    }
-                    if synced_replicas >= target_count {
-                        break;
+        let numreplicas = parse_count(numreplicas)?;
+        let deadline = parse_deadline(timeout)?;
+        let mut getack_sent = false;
+        loop {
+            let acked = self.replicas.acked();
+            let count = self.replicas.count_acked(offset);
+            if count >= numreplicas {
+                return Ok(count);
+            }
+            // the replicas acknowledge what they processed right away instead of at their
+            // next periodic ACK
+            if !getack_sent {
+                self.replicate(GETACK);
+                getack_sent = true;
+            }
+            match deadline {
+                Some(deadline) => {
+                    if timeout_at(deadline, acked).await.is_err() {
+                        return Ok(self.replicas.count_acked(offset));
                     }
                 }
-                Err(e) => {
-                    eprintln!("error getting offset from replica due to {e:?}")
+                None => acked.await,
+            }
+        }
+    }
+
+    /// Blocks until `offset` is on disk in the AOF of this instance, unless `numlocal` is 0, and
+    /// in the AOF of `numreplicas` replicas, or until the timeout in milliseconds elapses (0
+    /// blocks forever). Returns whether the local AOF has it and how many replicas do.
+    pub async fn waitaof(
+        &self,
+        numlocal: &BulkString,
+        numreplicas: &BulkString,
+        timeout: &BulkString,
+        offset: usize,
+    ) -> anyhow::Result<(usize, usize)> {
+        let numlocal = parse_count(numlocal)?;
+        let numreplicas = parse_count(numreplicas)?;
+        let deadline = parse_deadline(timeout)?;
+        anyhow::ensure!(
+            numlocal == 0 || self.aof.is_enabled(),
+            "WAITAOF cannot be used when numlocal is set but appendonly is disabled."
+        );
+        anyhow::ensure!(
+            numlocal == 0 || self.aof.fsync_policy() != FsyncPolicy::No,
+            "WAITAOF cannot be used when numlocal is set but appendfsync is no."
+        );
+        let counts = || {
+            let local = (self.aof.is_enabled() && self.aof.fsynced_offset() >= offset) as usize;
+            (local, self.replicas.count_fsynced(offset))
+        };
+        let mut getack_sent = false;
+        loop {
+            let acked = self.replicas.acked();
+            let fsynced = self.aof.fsynced();
+            let (local, replicas) = counts();
+            if local >= numlocal && replicas >= numreplicas {
+                return Ok((local, replicas));
+            }
+            if replicas < numreplicas && !getack_sent {
+                self.replicate(GETACK);
+                getack_sent = true;
+            }
+            let progress = async {
+                tokio::select! {
+                    _ = acked => {}
+                    _ = fsynced => {}
+                }
+            };
+            match deadline {
+                Some(deadline) => {
+                    if timeout_at(deadline, progress).await.is_err() {
+                        return Ok(counts());
+                    }
                 }
+                None => progress.await,
             }
         }
-        Ok(synced_replicas)
     }
 
     pub fn swap_pairs(
         &mut self,
         pairs: &[(BulkString, BulkString)],
-    ) -> Vec<(BulkString, BulkString)> {
+    ) -> Option<Vec<(BulkString, BulkString)>> {
         self.db.swap_and_fetch_max_id(pairs)
     }
 
+    /// Blocks until any of the requested streams receives an entry past its ID, or until
+    /// `block_duration` milliseconds have passed (0 blocks forever)
+    pub async fn xread_blocking(
+        &self,
+        count: Option<usize>,
+        key_id_pairs: &[(BulkString, BulkString)],
+        block_duration: u64,
+    ) -> String {
+        let deadline =
+            (block_duration != 0).then(|| Instant::now() + Duration::from_millis(block_duration));
+        let mut watchers = JoinSet::new();
+        let response = loop {
+            // subscribe before reading so that entries added in between are not missed
+            for (key, _) in key_id_pairs {
+                let mut rx = self.db.subscribe_stream(key);
+                watchers.spawn(async move { rx.changed().await });
+            }
+            let response = self.db.xread(count, key_id_pairs);
+            if response != NULL_ARRAY {
+                break response;
+            }
+            match deadline {
+                Some(deadline) => {
+                    if timeout_at(deadline, watchers.join_next()).await.is_err() {
+                        break response;
+                    }
+                }
+                None => {
+                    watchers.join_next().await;
+                }
+            }
+            watchers.shutdown().await;
+        };
+        // the receivers are dropped once their tasks are, which lets the watchers go
+        watchers.shutdown().await;
+        for (key, _) in key_id_pairs {
+            self.db.unsubscribe_stream(key);
+        }
+        response
+    }
+
     pub fn handle_response(&mut self, redis_data: &RedisData) -> anyhow::Result<String> {
+        let command_lock = self.command_lock.clone();
+        if let RedisData::Save | RedisData::Bgsave | RedisData::Bgrewriteaof = redis_data {
+            // snapshots keep every other command out while the dataset is copied
+            let _guard = command_lock.write().unwrap_or_else(PoisonError::into_inner);
+            return self.execute(redis_data);
+        }
+        let _guard = command_lock.read().unwrap_or_else(PoisonError::into_inner);
+        self.execute(redis_data)
+    }
+
+    /// The serialized value of a key as a bulk string, which holds binary data
+    pub fn dump(&self, key: &BulkString) -> anyhow::Result<Vec<u8>> {
+        let command_lock = self.command_lock.clone();
+        let _guard = command_lock.read().unwrap_or_else(PoisonError::into_inner);
+        let Some(value) = self.db.get_value(key) else {
+            return Ok(b"$-1\r\n".to_vec());
+        };
+        let payload = dump::serialize(&value.value)?;
+        let mut response = format!("${}\r\n", payload.len()).into_bytes();
+        response.extend(payload);
+        response.extend(b"\r\n");
+        Ok(response)
+    }
+
+    /// Moves keys to another instance with `RESTORE`
+    pub async fn migrate(&self, args: &MigrateArgs) -> String {
+        let mut restores = Vec::new();
+        for key in &args.keys {
+            let Some(value) = self.db.get_value(key) else {
+                continue;
+            };
+            let payload = match dump::serialize(&value.value) {
+                Ok(payload) => payload,
+                Err(e) => return format!("-ERR {e}\r\n"),
+            };
+            // the remaining time to live, a key about to expire gets the shortest one
+            let ttl = value
+                .expiry
+                .and_then(|expiry| expiry.expiration())
+                .map(|expiration| {
+                    let remaining = expiration.duration_since(SystemTime::now());
+                    remaining.map_or(1, |remaining| remaining.as_millis().max(1))
+                })
+                .unwrap_or(0)
+                .to_string();
+            let mut restore: Vec<&[u8]> =
+                vec![b"RESTORE", key.data.as_bytes(), ttl.as_bytes(), &payload];
+            if args.replace {
+                restore.push(b"REPLACE");
+            }
+            restores.push((key.clone(), frame::encode(&restore)));
+        }
+        if restores.is_empty() {
+            return "+NOKEY\r\n".to_owned();
+        }
+
+        let mut requests = Vec::new();
+        if !args.auth.is_empty() {
+            let mut auth = vec!["AUTH"];
+            auth.extend(args.auth.iter().map(|arg| arg.data.as_str()));
+            requests.push(frame::encode(&auth));
+        }
+        if args.db != 0 {
+            requests.push(frame::encode(&["SELECT", &args.db.to_string()]));
+        }
+        let setup = requests.len();
+        requests.extend(restores.iter().map(|(_, restore)| restore.clone()));
+        let replies = timeout(args.timeout, exchange(&args.host, args.port, &requests)).await;
+        let mut replies = match replies {
+            Ok(Ok(replies)) => replies,
+            Ok(Err(e)) => {
+                return format!("-IOERR error or timeout writing to target instance: {e}\r\n")
+            }
+            Err(_) => return "-IOERR error or timeout reading to target instance\r\n".to_owned(),
+        };
+
+        let mut error = None;
+        // a failed AUTH or SELECT fails the restores after it as well
+        if let Some(e) = replies[..setup]
+            .iter()
+            .find_map(|reply| reply.strip_prefix('-'))
+        {
+            return format!("-ERR Target instance replied with error: {e}\r\n");
+        }
+        replies.drain(..setup);
+        let mut moved = Vec::new();
+        for ((key, _), reply) in restores.into_iter().zip(replies) {
+            match reply.strip_prefix('-') {
+                Some(e) => {
+                    error = Some(format!("-ERR Target instance replied with error: {e}\r\n"))
+                }
+                None => moved.push(key),
+            }
+        }
+        // the keys that moved are deleted on the AOF and the replicas as well
+        if !args.copy && !moved.is_empty() {
+            let command_lock = self.command_lock.clone();
+            let _guard = command_lock.read().unwrap_or_else(PoisonError::into_inner);
+            self.db.del(&moved);
+            let mut del = vec!["DEL"];
+            del.extend(moved.iter().map(|key| key.data.as_str()));
+            self.propagate(&frame::encode(&del));
+        }
+        error.unwrap_or_else(|| "+OK\r\n".to_owned())
+    }
+
+    /// Runs a command from a client, propagating it when it is a successful write
+    pub fn handle_request(
+        &mut self,
+        redis_data: &RedisData,
+        request: &[u8],
+    ) -> anyhow::Result<String> {
+        if !redis_data.is_write() {
+            return self.handle_response(redis_data);
+        }
+        let command_lock = self.command_lock.clone();
+        let _guard = command_lock.read().unwrap_or_else(PoisonError::into_inner);
+        let response = self.execute(redis_data)?;
+        if !response.starts_with('-') {
+            self.propagate(&self.rewrite(redis_data, request, &response));
+        }
+        Ok(response)
+    }
+
+    /// Runs a command received from the primary, then forwards it as it was received to the
+    /// backlog and the replicas of this instance. Both happen under the same command lock guard,
+    /// so that a snapshot sent to a replica holds the write exactly when its offset covers it.
+    /// A write that could not be applied is not forwarded.
+    pub fn handle_replicated(
+        &mut self,
+        redis_data: &RedisData,
+        request: &[u8],
+    ) -> anyhow::Result<String> {
+        let command_lock = self.command_lock.clone();
+        let _guard = command_lock.read().unwrap_or_else(PoisonError::into_inner);
+        let response = self.execute(redis_data);
+        match &response {
+            Ok(response) => {
+                if redis_data.is_write() && !response.starts_with('-') {
+                    self.propagate(&self.rewrite(redis_data, request, response));
+                }
+                self.replicate(request);
+            }
+            Err(_) if redis_data.is_write() => self.skip_replicated(request),
+            Err(_) => self.replicate(request),
+        }
+        // the write was appended before the offset covered it
+        self.aof.synced_on_append(self.offset());
+        response
+    }
+
+    /// The form of a successful write that reaches the AOF and the replicas. Commands whose
+    /// effect depends on when or where they run are replaced with ones that give the same result
+    /// everywhere: relative expirations become unix times and generated stream IDs are spelled
+    /// out.
+    fn rewrite(&self, redis_data: &RedisData, request: &[u8], response: &str) -> Vec<u8> {
+        match redis_data {
+            RedisData::Set(key, value, config) => match config.expiration() {
+                Some(expiration) => frame::encode(&[
+                    "SET",
+                    &key.data,
+                    &value.data,
+                    "PXAT",
+                    &unix_millis(expiration).to_string(),
+                ]),
+                None => request.to_vec(),
+            },
+            RedisData::Xadd(key, _, map) => {
+                // the reply is the bulk string of the ID that was added, which may have been
+                // generated from `*` or `<ms>-*`
+                let id = response.split("\r\n").nth(1).unwrap_or_default();
+                let mut args = vec!["XADD", &key.data, id];
+                for (field, value) in map {
+                    args.extend([field.data.as_str(), value.data.as_str()]);
+                }
+                frame::encode(&args)
+            }
+            RedisData::Restore(key, args) if args.ttl != 0 && !args.absttl => {
+                let ttl = self.db.expiration(key).map(unix_millis).unwrap_or_default();
+                let ttl = ttl.to_string();
+                let mut restore = vec![
+                    b"RESTORE".as_slice(),
+                    key.data.as_bytes(),
+                    ttl.as_bytes(),
+                    &args.payload,
+                    b"ABSTTL",
+                ];
+                if args.replace {
+                    restore.push(b"REPLACE");
+                }
+                frame::encode(&restore)
+            }
+            _ => request.to_vec(),
+        }
+    }
+
+    /// Answers the `PSYNC` of a replica, returning the queue of the writes to send it. A replica
+    /// that follows this stream or the previous one continues from the backlog when it still
+    /// holds the offset asked for, any other gets a full resync.
+    pub async fn psync(
+        &self,
+        addr: SocketAddr,
+        listening_port: u16,
+        replid: &str,
+        offset: &str,
+        capa_eof: bool,
+        client_tx: &mpsc::Sender<Vec<u8>>,
+    ) -> anyhow::Result<mpsc::UnboundedReceiver<Vec<u8>>> {
+        // a replica serves its own replicas the stream of its primary, once it follows it
+        if !self.is_master() && !self.replica_config.lock().unwrap().link.up {
+            let response = "-NOMASTERLINK Can't SYNC while not connected with my master\r\n";
+            client_tx.send(response.as_bytes().to_vec()).await?;
+            anyhow::bail!("not connected to the primary");
+        }
+        if let Some((response, rx)) = self.partial_resync(addr, listening_port, replid, offset) {
+            println!("Partial resynchronization request from {addr} accepted");
+            client_tx.send(response).await?;
+            return Ok(rx);
+        }
+        self.full_resync(addr, listening_port, capa_eof, client_tx)
+            .await
+    }
+
+    /// The `+CONTINUE` reply followed by the missed part of the stream, and the queue of the
+    /// replica, if it can continue from `offset`
+    fn partial_resync(
+        &self,
+        addr: SocketAddr,
+        listening_port: u16,
+        replid: &str,
+        offset: &str,
+    ) -> Option<(Vec<u8>, mpsc::UnboundedReceiver<Vec<u8>>)> {
+        // PSYNC names the offset of the next byte the replica needs, counting from 1
+        let offset = offset.parse::<usize>().ok()?.checked_sub(1)?;
+        let _guard = self
+            .command_lock
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        let replica_config = self.replica_config.lock().unwrap();
+        if !replica_config.can_continue(replid, offset + 1) {
+            return None;
+        }
+        let (missed, rx) = self.replicas.register_from(addr, listening_port, offset)?;
+        let mut response = format!("+CONTINUE {}\r\n", replica_config.replid).into_bytes();
+        response.extend(missed);
+        Some((response, rx))
+    }
+
+    /// Sends the dataset to a new replica: the `+FULLRESYNC` reply, then a snapshot in the RDB
+    /// format, then the writes propagated since the snapshot was taken. The snapshot is written
+    /// to the RDB file first, unless `repl-diskless-sync` is set and the replica announced that
+    /// it reads the `$EOF:` format.
+    pub async fn full_resync(
+        &self,
+        addr: SocketAddr,
+        listening_port: u16,
+        capa_eof: bool,
+        client_tx: &mpsc::Sender<Vec<u8>>,
+    ) -> anyhow::Result<mpsc::UnboundedReceiver<Vec<u8>>> {
+        let (fullresync, entries, rx) = {
+            // no write can run between the snapshot and the registration of the replica
+            let _guard = self
+                .command_lock
+                .write()
+                .unwrap_or_else(PoisonError::into_inner);
+            let replica_config = self.replica_config.lock().unwrap();
+            let fullresync = format!(
+                "+FULLRESYNC {} {}\r\n",
+                replica_config.replid,
+                replica_config.repl_offset.load(Ordering::Relaxed)
+            );
+            let offset = replica_config.repl_offset.load(Ordering::Relaxed);
+            (
+                fullresync,
+                self.db.snapshot(),
+                self.replicas.register(addr, listening_port, offset),
+            )
+        };
+        client_tx.send(fullresync.into_bytes()).await?;
+
+        let payload = match capa_eof && self.replicas.diskless_sync() {
+            true => {
+                let rdb = tokio::task::spawn_blocking(move || {
+                    RdbWriter::new(Vec::new()).write_rdb(&entries)
+                })
+                .await??;
+                let mark: String = thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .map(char::from)
+                    .take(EOF_MARK_LEN)
+                    .collect();
+                let mut payload = format!("$EOF:{mark}\r\n").into_bytes();
+                payload.extend(rdb);
+                payload.extend(mark.as_bytes());
+                payload
+            }
+            false => {
+                let db = self.db.clone();
+                let rdb = tokio::task::spawn_blocking(move || {
+                    let path = db.rdb_path();
+                    let persistence = db.persistence();
+                    // the file doubles as a background save when none is running
+                    if persistence.start_bgsave() {
+                        let dirty = persistence.dirty();
+                        let result = writer::write_file(&path, &entries);
+                        persistence.finish_bgsave(dirty, result.is_ok());
+                        result?;
+                        Ok(std::fs::read(&path)?)
+                    } else {
+                        RdbWriter::new(Vec::new()).write_rdb(&entries)
+                    }
+                })
+                .await??;
+                let mut payload = format!("${}\r\n", rdb.len()).into_bytes();
+                payload.extend(rdb);
+                payload
+            }
+        };
+        println!(
+            "Synchronization with replica {addr} succeeded, sent {} bytes",
+            payload.len()
+        );
+        client_tx.send(payload).await?;
+        self.replicas.set_online(addr);
+        Ok(rx)
+    }
+
+    /// Starts a background save when one of the `save` rules is met
+    pub fn save_cron(&self) {
+        if !self.db.persistence().should_save() {
+            return;
+        }
+        let command_lock = self.command_lock.clone();
+        let _guard = command_lock.write().unwrap_or_else(PoisonError::into_inner);
+        if let Err(e) = self.bgsave() {
+            println!("Background saving failed to start: {e}");
+        }
+    }
+
+    /// Writes the dataset to the RDB file, the caller must hold the command lock exclusively
+    fn save(&self) -> anyhow::Result<()> {
+        let persistence = self.db.persistence();
+        anyhow::ensure!(
+            !persistence.bgsave_in_progress(),
+            "Background save already in progress"
+        );
+        let dirty = persistence.dirty();
+        let result = writer::write_file(&self.db.rdb_path(), &self.db.snapshot());
+        persistence.finish_save(dirty, result.is_ok());
+        result
+    }
+
+    /// Copies the dataset and writes it to the RDB file on a blocking thread, the caller must
+    /// hold the command lock exclusively while the copy is taken
+    fn bgsave(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.db.persistence().start_bgsave(),
+            "Background save already in progress"
+        );
+        let dirty = self.db.persistence().dirty();
+        let entries = self.db.snapshot();
+        let path = self.db.rdb_path();
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || {
+            let result = writer::write_file(&path, &entries);
+            match &result {
+                Ok(()) => println!("Background saving terminated with success"),
+                Err(e) => println!("Background saving error: {e}"),
+            }
+            db.persistence().finish_bgsave(dirty, result.is_ok());
+        });
+        Ok(())
+    }
+
+    /// Compacts the AOF into a new base file on a blocking thread, the caller must hold the
+    /// command lock exclusively so that no write lands between the snapshot and the switch to a
+    /// new incremental file
+    fn bgrewriteaof(&self) -> anyhow::Result<()> {
+        let seq = self.aof.start_rewrite()?;
+        let entries = self.db.snapshot();
+        let aof = self.aof.clone();
+        let started = std::time::Instant::now();
+        tokio::task::spawn_blocking(move || {
+            let base = aof.write_base(seq, &entries);
+            aof.finish_rewrite(base, started);
+        });
+        Ok(())
+    }
+
+    /// The `# Persistence` section of `INFO`
+    fn persistence_info(&self) -> String {
+        let persistence = self.db.persistence();
+        let status = |ok: bool| if ok { "ok" } else { "err" };
+        let rewrite = self.aof.rewrite_info();
+        let mut info = format!(
+            "# Persistence\r\nloading:0\r\nrdb_changes_since_last_save:{}\r\n\
+             rdb_bgsave_in_progress:{}\r\nrdb_last_save_time:{}\r\nrdb_last_bgsave_status:{}\r\n\
+             aof_enabled:{}\r\naof_rewrite_in_progress:{}\r\naof_rewrite_scheduled:0\r\n\
+             aof_last_rewrite_time_sec:{}\r\naof_last_bgrewrite_status:{}\r\n",
+            persistence.dirty(),
+            persistence.bgsave_in_progress() as u8,
+            persistence.last_save(),
+            status(persistence.last_bgsave_ok()),
+            self.aof.is_enabled() as u8,
+            rewrite.in_progress as u8,
+            rewrite.last_time,
+            status(rewrite.last_ok),
+        );
+        if self.aof.is_enabled() {
+            info.push_str(&format!(
+                "aof_current_size:{}\r\naof_base_size:{}\r\n",
+                rewrite.current_size, rewrite.base_size
+            ));
+        }
+        info
+    }
+
+    pub fn expire_keys(&self) {
+        self.db.expire_keys();
+    }
+
+    pub fn pubsub(&self) -> Arc<PubSub> {
+        self.pubsub.clone()
+    }
+
+    pub fn watch(&self, key: &BulkString) -> u64 {
+        self.db.watch(key)
+    }
+
+    pub fn unwatch(&self, watched: &[(BulkString, u64)]) {
+        for (key, _) in watched {
+            self.db.unwatch(key);
+        }
+    }
+
+    /// Runs the commands queued by `MULTI` without interleaving commands from other connections.
+    /// Returns `None` without running anything if one of the watched keys was modified.
+    pub fn exec(
+        &mut self,
+        commands: &[RedisData],
+        watched: &[(BulkString, u64)],
+    ) -> Option<String> {
+        let command_lock = self.command_lock.clone();
+        let _guard = command_lock.write().unwrap_or_else(PoisonError::into_inner);
+        self.exec_locked(commands, watched)
+            .map(|responses| array(&responses))
+    }
+
+    /// Runs a transaction from a client like `exec`, then propagates its writes as a single
+    /// `MULTI` ... `EXEC` block
+    pub fn exec_requests(
+        &mut self,
+        queued: Vec<(RedisData, Vec<u8>)>,
+        watched: &[(BulkString, u64)],
+    ) -> Option<String> {
+        let command_lock = self.command_lock.clone();
+        let _guard = command_lock.write().unwrap_or_else(PoisonError::into_inner);
+        self.exec_requests_locked(queued, watched)
+    }
+
+    /// Runs a transaction received from the primary like `exec_requests`, then forwards
+    /// `stream`, the whole transaction as it was received, under the same guard like
+    /// `handle_replicated`
+    pub fn exec_replicated(&mut self, queued: Vec<(RedisData, Vec<u8>)>, stream: &[u8]) -> String {
+        let command_lock = self.command_lock.clone();
+        let _guard = command_lock.write().unwrap_or_else(PoisonError::into_inner);
+        let response = self.exec_requests_locked(queued, &[]).unwrap_or_default();
+        self.replicate(stream);
+        self.aof.synced_on_append(self.offset());
+        response
+    }
+
+    fn exec_requests_locked(
+        &mut self,
+        queued: Vec<(RedisData, Vec<u8>)>,
+        watched: &[(BulkString, u64)],
+    ) -> Option<String> {
+        let (commands, requests): (Vec<_>, Vec<_>) = queued.into_iter().unzip();
+        let responses = self.exec_locked(&commands, watched)?;
+        let writes: Vec<u8> = commands
+            .iter()
+            .zip(&requests)
+            .zip(&responses)
+            .filter(|((redis_data, _), response)| {
+                redis_data.is_write() && !response.starts_with('-')
+            })
+            .flat_map(|((redis_data, request), response)| {
+                self.rewrite(redis_data, request, response)
+            })
+            .collect();
+        if !writes.is_empty() {
+            let mut block = b"*1\r\n$5\r\nMULTI\r\n".to_vec();
+            block.extend(writes);
+            block.extend(b"*1\r\n$4\r\nEXEC\r\n");
+            self.propagate(&block);
+        }
+        Some(array(&responses))
+    }
+
+    fn exec_locked(
+        &mut self,
+        commands: &[RedisData],
+        watched: &[(BulkString, u64)],
+    ) -> Option<Vec<String>> {
+        if watched
+            .iter()
+            .any(|(key, version)| self.db.modified_since(key, *version))
+        {
+            return None;
+        }
+        let responses = commands
+            .iter()
+            .map(|redis_data| match self.execute(redis_data) {
+                Ok(response) => response,
+                Err(e) => format!("-ERR {e}\r\n"),
+            })
+            .collect();
+        Some(responses)
+    }
+
+    fn execute(&mut self, redis_data: &RedisData) -> anyhow::Result<String> {
         let response = match redis_data {
             RedisData::Ping => "+PONG\r\n".to_owned(),
             RedisData::Info(info_arg) => match info_arg {
                 InfoArg::All => {
-                    let mut infos = Vec::new();
-                    infos.push(self.replica_config.lock().unwrap().generate_response());
-                    format!(
-                        "*{}\r\n{}",
-                        infos.len(),
-                        infos
-                            .iter()
-                            .map(|r| BulkString::encode(r).decode())
-                            .collect::<Vec<String>>()
-                            .join("")
-                    )
-                }
-                InfoArg::Replication => {
-                    BulkString::encode(&self.replica_config.lock().unwrap().generate_response())
-                        .decode()
+                    let infos = [
+                        self.replica_config
+                            .lock()
+                            .unwrap()
+                            .generate_response(&self.replicas),
+                        self.persistence_info(),
+                    ];
+                    BulkString::encode(&infos.join("\r\n")).decode()
                 }
+                InfoArg::Replication => BulkString::encode(
+                    &self
+                        .replica_config
+                        .lock()
+                        .unwrap()
+                        .generate_response(&self.replicas),
+                )
+                .decode(),
+                InfoArg::Persistence => BulkString::encode(&self.persistence_info()).decode(),
             },
             RedisData::Set(key, value, config) => {
                 self.db.set(
@@ -208,83 +1213,336 @@ impl State {
                     Err(e) => e.to_string(),
                 }
             }
-            RedisData::Xrange(key, start, end) => match self.db.xrange(key, start, end) {
-                Ok(res) => res,
-                Err(e) => panic!("Failed to retrieve values due to {e}"),
-            },
-            RedisData::Xread(_streams, key_id_pairs, _block_duration) => {
-                self.db.xread(key_id_pairs)
+            RedisData::Del(keys) => format!(":{}\r\n", self.db.del(keys)),
+            RedisData::Restore(key, args) => {
+                let value = dump::deserialize(&args.payload)?;
+                let expiry = match (args.ttl, args.absttl) {
+                    (0, _) => None,
+                    (ttl, true) => Some(UNIX_EPOCH + Duration::from_millis(ttl)),
+                    (ttl, false) => Some(SystemTime::now() + Duration::from_millis(ttl)),
+                };
+                let value = DataValue {
+                    value,
+                    expiry: expiry.map(SetConfig::from_expiration),
+                };
+                match self.db.restore(key.clone(), value, args.replace) {
+                    true => "+OK\r\n".to_owned(),
+                    false => "-BUSYKEY Target key name already exists.\r\n".to_owned(),
+                }
             }
-            RedisData::Keys(_value) => {
-                unimplemented!()
+            // both reply with binary data or wait on another instance
+            RedisData::Dump(_) | RedisData::Migrate(_) => {
+                anyhow::bail!("DUMP and MIGRATE are handled by the connection")
             }
-            RedisData::Psync(repl_id, _repl_offset) => match repl_id.data.as_str() {
-                "?" => {
-                    let resp = format!(
-                        "+FULLRESYNC {} 0\r\n",
-                        self.replica_config.lock().unwrap().replid
-                    );
-                    resp.to_owned()
+            // it starts the replication link, which needs the port of the connections
+            RedisData::Replicaof(_) => anyhow::bail!("REPLICAOF is handled by the connection"),
+            RedisData::Role => self.role(),
+            RedisData::Xrange(key, start, end) => self.db.xrange(key, start, end)?,
+            RedisData::Xread(count, key_id_pairs, _block_duration) => {
+                self.db.xread(*count, key_id_pairs)
+            }
+            RedisData::Xinfo(arg) => self.db.xinfo(arg),
+            // the watched keys are already released once the queued commands run
+            RedisData::Unwatch => "+OK\r\n".to_owned(),
+            RedisData::Multi | RedisData::Exec | RedisData::Discard | RedisData::Watch(_) => {
+                anyhow::bail!("transactions are handled by the connection")
+            }
+            RedisData::Publish(channel, message) => {
+                format!(":{}\r\n", self.pubsub.publish(channel, message))
+            }
+            RedisData::Spublish(channel, message) => {
+                format!(":{}\r\n", self.pubsub.spublish(channel, message))
+            }
+            RedisData::Pubsub(arg) => {
+                let channels_to_resp = |channels: Vec<BulkString>| {
+                    format!(
+                        "*{}\r\n{}",
+                        channels.len(),
+                        channels.iter().map(|c| c.decode()).collect::<String>()
+                    )
+                };
+                let numsub_to_resp = |numsub: Vec<(&BulkString, usize)>| {
+                    format!(
+                        "*{}\r\n{}",
+                        numsub.len() * 2,
+                        numsub
+                            .iter()
+                            .map(|(c, n)| format!("{}:{n}\r\n", c.decode()))
+                            .collect::<String>()
+                    )
+                };
+                match arg {
+                    PubsubArg::Channels(pattern) => {
+                        channels_to_resp(self.pubsub.channels(pattern.as_ref()))
+                    }
+                    PubsubArg::Numsub(channels) => numsub_to_resp(
+                        channels
+                            .iter()
+                            .map(|c| (c, self.pubsub.numsub(c)))
+                            .collect(),
+                    ),
+                    PubsubArg::Numpat => format!(":{}\r\n", self.pubsub.numpat()),
+                    PubsubArg::ShardChannels(pattern) => {
+                        channels_to_resp(self.pubsub.shard_channels(pattern.as_ref()))
+                    }
+                    PubsubArg::ShardNumsub(channels) => numsub_to_resp(
+                        channels
+                            .iter()
+                            .map(|c| (c, self.pubsub.shard_numsub(c)))
+                            .collect(),
+                    ),
                 }
-                _ => anyhow::bail!("not supported"),
-            },
-            RedisData::Wait(_, _) => {
-                let num_replica = self
-                    .replica_config
-                    .lock()
-                    .unwrap()
-                    .num_replicas
-                    .load(Ordering::Relaxed);
-                format!(":{}\r\n", num_replica)
             }
+            RedisData::Subscribe(_)
+            | RedisData::Unsubscribe(_)
+            | RedisData::Psubscribe(_)
+            | RedisData::Punsubscribe(_)
+            | RedisData::Ssubscribe(_)
+            | RedisData::Sunsubscribe(_) => {
+                anyhow::bail!("subscriptions are handled by the connection")
+            }
+            RedisData::Quit => "+OK\r\n".to_owned(),
+            RedisData::Save => {
+                self.save()?;
+                "+OK\r\n".to_owned()
+            }
+            RedisData::Bgsave => {
+                self.bgsave()?;
+                "+Background saving started\r\n".to_owned()
+            }
+            RedisData::Lastsave => format!(":{}\r\n", self.db.persistence().last_save()),
+            RedisData::Bgrewriteaof => {
+                self.bgrewriteaof()?;
+                "+Background append only file rewriting started\r\n".to_owned()
+            }
+            RedisData::Keys(_value) => anyhow::bail!("KEYS is not supported"),
+            // the snapshot is streamed to the replica's connection
+            RedisData::Psync(_, _) => anyhow::bail!("PSYNC is handled by the connection"),
+            RedisData::Wait(_, _) => format!(":{}\r\n", self.replica_count()),
+            // it waits on the offset of the client's last write
+            RedisData::Waitaof(..) => anyhow::bail!("WAITAOF is handled by the connection"),
             RedisData::Get(key) => self.db.get(key),
             RedisData::Type(key) => self.db.ty(key),
             RedisData::Echo(data) => data.decode(),
-            RedisData::Config(cmd, arg) => match cmd.data.to_lowercase().as_str() {
-                "get" => match arg.data.to_lowercase().as_str() {
+            RedisData::Config(cmd, args) => match cmd.data.to_lowercase().as_str() {
+                "get" => match args[0].data.to_lowercase().as_str() {
                     "dir" => self.db.dir()?,
                     "dbfilename" => self.db.dbfilename()?,
+                    name @ ("appendonly"
+                    | "appendfsync"
+                    | "appenddirname"
+                    | "appendfilename"
+                    | "aof-load-truncated"
+                    | "auto-aof-rewrite-percentage"
+                    | "auto-aof-rewrite-min-size") => config_to_resp(name, &self.aof_config(name)),
+                    name @ "save" => config_to_resp(name, &self.db.persistence().rules()),
+                    name @ "repl-diskless-sync" => {
+                        let diskless_sync = self.replicas.diskless_sync();
+                        config_to_resp(name, if diskless_sync { "yes" } else { "no" })
+                    }
+                    name @ "repl-backlog-size" => {
+                        config_to_resp(name, &self.replicas.backlog_size().to_string())
+                    }
+                    name @ "repl-backlog-ttl" => {
+                        config_to_resp(name, &self.replicas.backlog_ttl().to_string())
+                    }
+                    name @ "repl-timeout" => {
+                        config_to_resp(name, &self.repl_timeout().as_secs().to_string())
+                    }
+                    name @ "repl-ping-replica-period" => {
+                        config_to_resp(name, &self.replicas.ping_period().to_string())
+                    }
+                    name @ ("min-replicas-to-write" | "min-slaves-to-write") => {
+                        let min = self.replicas.min_replicas_to_write();
+                        config_to_resp(name, &min.to_string())
+                    }
+                    name @ ("min-replicas-max-lag" | "min-slaves-max-lag") => {
+                        let lag = self.replicas.min_replicas_max_lag();
+                        config_to_resp(name, &lag.to_string())
+                    }
+                    name @ ("replica-read-only" | "slave-read-only") => {
+                        let read_only = self.replica_config.lock().unwrap().read_only;
+                        config_to_resp(name, if read_only { "yes" } else { "no" })
+                    }
+                    name @ "notify-keyspace-events" => {
+                        config_to_resp(name, &self.db.notifier().flags())
+                    }
                     arg => anyhow::bail!("invalid cmd {arg}"),
                 },
+                "set" => {
+                    anyhow::ensure!(
+                        args.len().is_multiple_of(2),
+                        "wrong number of arguments for CONFIG SET"
+                    );
+                    for pair in args.chunks(2) {
+                        let value = pair[1].data.as_str();
+                        match pair[0].data.to_lowercase().as_str() {
+                            "notify-keyspace-events" => self.db.notifier().set_flags(value)?,
+                            "save" => self.db.persistence().set_rules(value)?,
+                            "repl-diskless-sync" => {
+                                self.replicas.set_diskless_sync(parse_yes_no(value)?)
+                            }
+                            "repl-backlog-size" => self
+                                .replicas
+                                .set_backlog_size(parse_memory(value)? as usize),
+                            "repl-backlog-ttl" => {
+                                let ttl = value.parse().map_err(|_| {
+                                    anyhow::anyhow!("argument must be a number, got '{value}'")
+                                })?;
+                                self.replicas.set_backlog_ttl(ttl);
+                            }
+                            "repl-timeout" => {
+                                let timeout = parse_seconds(value)?;
+                                self.set_repl_timeout(Duration::from_secs(timeout));
+                            }
+                            "repl-ping-replica-period" => {
+                                self.replicas.set_ping_period(parse_seconds(value)?)
+                            }
+                            "min-replicas-to-write" | "min-slaves-to-write" => {
+                                let min = value.parse().map_err(|_| {
+                                    anyhow::anyhow!("argument must be a number, got '{value}'")
+                                })?;
+                                self.replicas.set_min_replicas_to_write(min);
+                            }
+                            "min-replicas-max-lag" | "min-slaves-max-lag" => {
+                                let lag = value.parse().map_err(|_| {
+                                    anyhow::anyhow!("argument must be a number, got '{value}'")
+                                })?;
+                                self.replicas.set_min_replicas_max_lag(lag);
+                            }
+                            "replica-read-only" | "slave-read-only" => {
+                                self.set_replica_read_only(parse_yes_no(value)?)
+                            }
+                            "appendfsync" => {
+                                let policy: FsyncPolicy = value.parse()?;
+                                self.aof.set_fsync_policy(policy);
+                            }
+                            "auto-aof-rewrite-percentage" => {
+                                let percentage = value.parse().map_err(|_| {
+                                    anyhow::anyhow!("argument must be a number, got '{value}'")
+                                })?;
+                                self.aof.set_rewrite_percentage(percentage);
+                            }
+                            "auto-aof-rewrite-min-size" => {
+                                self.aof.set_rewrite_min_size(parse_memory(value)?)
+                            }
+                            name => anyhow::bail!(
+                                "Unknown option or number of arguments for CONFIG SET - '{name}'"
+                            ),
+                        }
+                    }
+                    "+OK\r\n".to_owned()
+                }
                 cmd => anyhow::bail!("invalid cmd {cmd}"),
             },
             RedisData::ReplConf(cmd, _arg) => match cmd.data.to_lowercase().as_str() {
                 "listening-port" => "+OK\r\n".to_owned(),
                 "capa" => "+OK\r\n".to_owned(),
                 "ack" => "".to_owned(),
-                "getack" => format!(
-                    "*3\r\n$8\r\nREPLCONF\r\n$3\r\nACK\r\n{}",
-                    BulkString::encode(
-                        &self
-                            .replica_config
-                            .lock()
-                            .unwrap()
-                            .repl_offset
-                            .load(Ordering::Relaxed)
-                            .to_string()
-                    )
-                    .decode()
-                ),
+                "getack" => {
+                    let offset = self.offset().to_string();
+                    let aof_offset = self.aof.fsynced_offset().to_string();
+                    let ack = frame::encode(&["REPLCONF", "ACK", &offset, "FACK", &aof_offset]);
+                    String::from_utf8(ack)?
+                }
                 cmd => anyhow::bail!("invalid cmd {cmd}"),
             },
         };
         Ok(response)
     }
 
-    pub fn replica_request(&self) -> anyhow::Result<BytesMut> {
-        let hex_file = "524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2".to_string();
-        let hex_bytes = hex::decode(hex_file.clone())?;
-        let msg = format!("${}\r\n", hex_bytes.len());
-        let mut response = BytesMut::from(msg.as_bytes());
-        response.extend(hex_bytes);
-        Ok(response)
+    /// The value of an AOF `CONFIG GET` parameter
+    fn aof_config(&self, name: &str) -> String {
+        let yes_no = |value: bool| if value { "yes" } else { "no" }.to_owned();
+        let config = self.aof.config();
+        match name {
+            "appendonly" => yes_no(self.aof.is_enabled()),
+            "appendfsync" => self.aof.fsync_policy().to_string(),
+            "appenddirname" => config.dirname.clone(),
+            "appendfilename" => config.filename.clone(),
+            "auto-aof-rewrite-percentage" => self.aof.rewrite_percentage().to_string(),
+            "auto-aof-rewrite-min-size" => self.aof.rewrite_min_size().to_string(),
+            _ => yes_no(config.load_truncated),
+        }
+    }
+}
+
+/// Sends commands to another instance and reads their replies, each without its final CRLF
+async fn exchange(host: &str, port: u16, requests: &[Vec<u8>]) -> anyhow::Result<Vec<String>> {
+    let stream = TcpStream::connect((host, port)).await?;
+    let (mut reader, mut writer) = stream.into_split();
+    writer.write_all(&requests.concat()).await?;
+    let mut pending = Vec::new();
+    let mut buf = [0; 4096];
+    let mut replies = Vec::new();
+    while replies.len() < requests.len() {
+        match frame::reply_len(&pending)? {
+            Some(len) => {
+                let reply: Vec<u8> = pending.drain(..len).collect();
+                replies.push(String::from_utf8_lossy(&reply[..len - 2]).into_owned());
+            }
+            None => {
+                let n = reader.read(&mut buf).await?;
+                anyhow::ensure!(n > 0, "connection closed");
+                pending.extend(&buf[..n]);
+            }
+        }
+    }
+    Ok(replies)
+}
+
+/// Parses the number of instances `WAIT` and `WAITAOF` wait for
+fn parse_count(value: &BulkString) -> anyhow::Result<usize> {
+    value
+        .data
+        .parse()
+        .map_err(|_| anyhow::anyhow!("value is not an integer or out of range"))
+}
+
+/// Parses the timeout of `WAIT` and `WAITAOF` in milliseconds into a deadline, `None` for 0
+/// which blocks forever
+fn parse_deadline(timeout: &BulkString) -> anyhow::Result<Option<Instant>> {
+    let timeout: i64 = timeout
+        .data
+        .parse()
+        .map_err(|_| anyhow::anyhow!("timeout is not an integer or out of range"))?;
+    anyhow::ensure!(timeout >= 0, "timeout is negative");
+    Ok((timeout > 0).then(|| Instant::now() + Duration::from_millis(timeout as u64)))
+}
+
+/// Parses a positive number of seconds
+fn parse_seconds(value: &str) -> anyhow::Result<u64> {
+    match value.parse() {
+        Ok(seconds) if seconds > 0 => Ok(seconds),
+        _ => anyhow::bail!("argument must be a positive number of seconds, got '{value}'"),
     }
 }
 
+/// Joins encoded replies into an array
+fn array(responses: &[String]) -> String {
+    format!("*{}\r\n{}", responses.len(), responses.join(""))
+}
+
+/// Milliseconds since the unix epoch, 0 for times before it
+fn unix_millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+/// Formats a `CONFIG GET` reply for a single parameter
+fn config_to_resp(name: &str, value: &str) -> String {
+    format!(
+        "*2\r\n{}{}",
+        BulkString::encode(name).decode(),
+        BulkString::encode(value).decode()
+    )
+}
+
 impl Default for State {
     fn default() -> Self {
-        let db = Database::initialize(None);
-        Self::new(false, db)
+        let db = Database::new(None);
+        Self::new(None, db)
     }
 }
 
@@ -313,4 +1571,315 @@ mod tests {
         let result = result.unwrap();
         assert_eq!(result, "$3\r\nbar\r\n")
     }
+
+    #[test]
+    fn test_xinfo_stream() {
+        let mut state = State::default();
+        for id in ["1-1", "1-2"] {
+            let cmd =
+                format!("*5\r\n$4\r\nxadd\r\n$1\r\ns\r\n$3\r\n{id}\r\n$1\r\na\r\n$1\r\nb\r\n");
+            state
+                .handle_response(&RedisData::parse(&cmd).unwrap())
+                .unwrap();
+        }
+        let redis_data =
+            RedisData::parse("*3\r\n$5\r\nxinfo\r\n$6\r\nstream\r\n$1\r\ns\r\n").unwrap();
+        let result = state.handle_response(&redis_data).unwrap();
+        assert!(result.starts_with("*20\r\n$6\r\nlength\r\n:2\r\n"));
+        assert!(result.contains("$17\r\nlast-generated-id\r\n$3\r\n1-2\r\n"));
+        assert!(result
+            .ends_with("$10\r\nlast-entry\r\n*2\r\n$3\r\n1-2\r\n*2\r\n$1\r\na\r\n$1\r\nb\r\n"));
+
+        let redis_data = RedisData::parse(
+            "*6\r\n$5\r\nxinfo\r\n$6\r\nstream\r\n$1\r\ns\r\n$4\r\nFULL\r\n$5\r\nCOUNT\r\n$1\r\n1\r\n",
+        )
+        .unwrap();
+        let result = state.handle_response(&redis_data).unwrap();
+        assert!(result.contains("$7\r\nentries\r\n*1\r\n*2\r\n$3\r\n1-1\r\n"));
+        assert!(result.ends_with("$6\r\ngroups\r\n*0\r\n"));
+    }
+
+    #[test]
+    fn test_stream_commands_on_wrong_type() {
+        let mut state = State::default();
+        let set = RedisData::parse("*3\r\n$3\r\nset\r\n$1\r\nk\r\n$1\r\nv\r\n").unwrap();
+        state.handle_response(&set).unwrap();
+        let xadd =
+            RedisData::parse("*5\r\n$4\r\nxadd\r\n$1\r\nk\r\n$3\r\n1-1\r\n$1\r\na\r\n$1\r\nb\r\n")
+                .unwrap();
+        assert_eq!(
+            state.handle_response(&xadd).unwrap(),
+            crate::db::WRONGTYPE_ERROR
+        );
+        let pairs = [(BulkString::encode("k"), BulkString::encode("$"))];
+        assert!(state.swap_pairs(&pairs).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_xread_blocking_timeout() {
+        let state = State::default();
+        let pairs = [(BulkString::encode("s"), BulkString::encode("0-0"))];
+        assert_eq!(state.xread_blocking(None, &pairs, 10).await, NULL_ARRAY);
+        let mut state = state;
+        let xadd =
+            RedisData::parse("*5\r\n$4\r\nxadd\r\n$1\r\ns\r\n$3\r\n1-1\r\n$1\r\na\r\n$1\r\nb\r\n")
+                .unwrap();
+        state.handle_response(&xadd).unwrap();
+        let response = state.xread_blocking(None, &pairs, 10).await;
+        assert!(response.starts_with("*1\r\n*2\r\n$1\r\ns\r\n"));
+    }
+
+    #[test]
+    fn test_exec() {
+        let mut state = State::default();
+        let commands = [
+            RedisData::parse("*3\r\n$3\r\nset\r\n$3\r\nfoo\r\n$3\r\nbar\r\n").unwrap(),
+            RedisData::parse("*2\r\n$3\r\nget\r\n$3\r\nfoo\r\n").unwrap(),
+        ];
+        let result = state.exec(&commands, &[]);
+        assert_eq!(result.unwrap(), "*2\r\n+OK\r\n$3\r\nbar\r\n");
+    }
+
+    #[test]
+    fn test_exec_with_failing_commands() {
+        let mut state = State::default();
+        let commands = [
+            RedisData::parse("*3\r\n$3\r\nset\r\n$1\r\nk\r\n$1\r\nv\r\n").unwrap(),
+            RedisData::parse("*5\r\n$4\r\nxadd\r\n$1\r\nk\r\n$3\r\n1-1\r\n$1\r\na\r\n$1\r\nb\r\n")
+                .unwrap(),
+            RedisData::parse("*5\r\n$4\r\nxadd\r\n$1\r\ns\r\n$3\r\nx-1\r\n$1\r\na\r\n$1\r\nb\r\n")
+                .unwrap(),
+            RedisData::parse("*2\r\n$4\r\nkeys\r\n$1\r\n*\r\n").unwrap(),
+        ];
+        let result = state.exec(&commands, &[]).unwrap();
+        assert!(result.starts_with("*4\r\n+OK\r\n-WRONGTYPE"));
+        assert!(result.contains("-ERR Invalid stream ID"));
+        assert!(result.ends_with("-ERR KEYS is not supported\r\n"));
+    }
+
+    #[test]
+    fn test_exec_with_modified_watched_key() {
+        let mut state = State::default();
+        let key = BulkString::encode("foo");
+        let version = state.watch(&key);
+        let set = RedisData::parse("*3\r\n$3\r\nset\r\n$3\r\nfoo\r\n$3\r\nbaz\r\n").unwrap();
+        state.handle_response(&set).unwrap();
+        let watched = [(key, version)];
+        assert!(state.exec(&[set], &watched).is_none());
+        state.unwatch(&watched);
+    }
+
+    #[test]
+    fn test_rewrite_for_propagation() {
+        let mut state = State::default();
+        let request = b"*5\r\n$4\r\nxadd\r\n$1\r\ns\r\n$1\r\n*\r\n$1\r\na\r\n$1\r\nb\r\n";
+        let xadd = RedisData::parse_bytes(request).unwrap();
+        let response = state.handle_response(&xadd).unwrap();
+        let id = response.split("\r\n").nth(1).unwrap();
+        assert_eq!(
+            state.rewrite(&xadd, request, &response),
+            frame::encode(&["XADD", "s", id, "a", "b"])
+        );
+
+        let request =
+            b"*5\r\n$3\r\nset\r\n$1\r\nk\r\n$1\r\nv\r\n$4\r\nexat\r\n$10\r\n4000000000\r\n";
+        let set = RedisData::parse_bytes(request).unwrap();
+        assert_eq!(
+            state.rewrite(&set, request, "+OK\r\n"),
+            frame::encode(&["SET", "k", "v", "PXAT", "4000000000000"])
+        );
+    }
+
+    #[test]
+    fn test_replicaof_and_promotion() {
+        let state = State::default();
+        let primary = || MasterConfig {
+            host: "127.0.0.1".to_owned(),
+            port: 6380,
+        };
+        assert_eq!(state.replicaof(None), None);
+        assert_eq!(state.replicaof(Some(primary())), Some(1));
+        // already following that primary
+        assert_eq!(state.replicaof(Some(primary())), None);
+        assert_eq!(
+            state.role(),
+            "*5\r\n$5\r\nslave\r\n$9\r\n127.0.0.1\r\n:6380\r\n$10\r\nconnecting\r\n:0\r\n"
+        );
+        let (replid, _) = state.psync_args();
+        assert_eq!(state.replicaof(None), None);
+        assert_eq!(state.epoch(), 2);
+        assert!(state
+            .replica_config
+            .lock()
+            .unwrap()
+            .can_continue(&replid, 1));
+        assert_eq!(state.role(), "*3\r\n$6\r\nmaster\r\n:0\r\n*0\r\n");
+    }
+
+    #[test]
+    fn test_forward_replicated_writes() {
+        let mut state = State::default();
+        state.replicaof(Some(MasterConfig {
+            host: "127.0.0.1".to_owned(),
+            port: 6380,
+        }));
+        let addr = "127.0.0.1:6001".parse().unwrap();
+        let mut rx = state.replicas().register(addr, 6381, state.offset());
+        let requests = [
+            frame::encode(&["SET", "k", "1"]),
+            frame::encode(&["PING"]),
+            frame::encode(&["SET", "k", "2"]),
+        ];
+        for request in &requests {
+            let redis_data = RedisData::parse_bytes(request).unwrap();
+            state.handle_replicated(&redis_data, request).unwrap();
+        }
+        for request in &requests {
+            assert_eq!(&rx.try_recv().unwrap(), request);
+        }
+        let get = RedisData::parse_bytes(&frame::encode(&["GET", "k"])).unwrap();
+        assert_eq!(state.handle_response(&get).unwrap(), "$1\r\n2\r\n");
+
+        // the write fails to apply, but the offset still follows the primary
+        let offset = state.offset();
+        let restore = frame::encode(&["RESTORE", "k", "0", "garbage", "REPLACE"]);
+        let redis_data = RedisData::parse_bytes(&restore).unwrap();
+        assert!(state.handle_replicated(&redis_data, &restore).is_err());
+        assert!(rx.try_recv().is_err());
+        assert!(state.replicas().is_empty());
+        assert_eq!(state.offset(), offset + restore.len());
+    }
+
+    #[tokio::test]
+    async fn test_full_resync() {
+        let dir = std::env::temp_dir().join(format!("full-resync-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = Database::new(Some(crate::config::DatabaseConfig {
+            dir: dir.to_str().unwrap().to_owned(),
+            dbfilename: "dump.rdb".to_owned(),
+        }));
+        let mut state = State::new(None, db);
+        let set = RedisData::parse_bytes(&frame::encode(&["SET", "k", "v"])).unwrap();
+        state.handle_response(&set).unwrap();
+        let replid = state.replica_config.lock().unwrap().replid.clone();
+        let addr = "127.0.0.1:6001".parse().unwrap();
+        for diskless in [false, true] {
+            state.replicas().set_diskless_sync(diskless);
+            let (tx, mut rx) = mpsc::channel(4);
+            let _writes = state.full_resync(addr, 6381, true, &tx).await.unwrap();
+            assert_eq!(
+                rx.recv().await.unwrap(),
+                format!("+FULLRESYNC {replid} 0\r\n").into_bytes()
+            );
+            let payload = rx.recv().await.unwrap();
+            let rdb = match diskless {
+                // the snapshot between `$EOF:<mark>` and the same mark
+                true => {
+                    let (header, rest) = payload.split_at(b"$EOF:\r\n".len() + EOF_MARK_LEN);
+                    let mark = &header[b"$EOF:".len()..header.len() - 2];
+                    assert!(rest.ends_with(mark));
+                    rest[..rest.len() - EOF_MARK_LEN].to_vec()
+                }
+                // the snapshot after its length, as saved to the RDB file
+                false => {
+                    let end = payload.windows(2).position(|w| w == b"\r\n").unwrap();
+                    let rdb = payload[end + 2..].to_vec();
+                    assert_eq!(payload[..end], *format!("${}", rdb.len()).as_bytes());
+                    assert_eq!(std::fs::read(dir.join("dump.rdb")).unwrap(), rdb);
+                    rdb
+                }
+            };
+            let db = Database::new(None);
+            db.load_rdb(rdb.as_slice()).unwrap();
+            let get = RedisData::parse_bytes(&frame::encode(&["GET", "k"])).unwrap();
+            let mut replica = State::new(None, db);
+            assert_eq!(replica.handle_response(&get).unwrap(), "$1\r\nv\r\n");
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_only_replica() {
+        let mut state = State::default();
+        let request = frame::encode(&["SET", "k", "v"]);
+        let set = RedisData::parse_bytes(&request).unwrap();
+        assert!(set.is_write());
+        assert!(!state.is_read_only());
+        state.replicaof(Some(MasterConfig {
+            host: "127.0.0.1".to_owned(),
+            port: 6380,
+        }));
+        assert!(state.is_read_only());
+        // the writes of the primary still apply
+        state.handle_replicated(&set, &request).unwrap();
+        let get = RedisData::parse_bytes(&frame::encode(&["GET", "k"])).unwrap();
+        assert_eq!(state.handle_response(&get).unwrap(), "$1\r\nv\r\n");
+        state.set_replica_read_only(false);
+        assert!(!state.is_read_only());
+    }
+
+    #[tokio::test]
+    async fn test_waitaof() {
+        let (zero, one, ten) = (
+            BulkString::encode("0"),
+            BulkString::encode("1"),
+            BulkString::encode("10"),
+        );
+        let state = State::default();
+        let error = state.waitaof(&one, &zero, &zero, 0).await.unwrap_err();
+        assert!(error.to_string().contains("appendonly is disabled"));
+
+        let request = b"*3\r\n$3\r\nset\r\n$1\r\nk\r\n$1\r\nv\r\n";
+        let set = RedisData::parse_bytes(request).unwrap();
+        for fsync in [FsyncPolicy::Always, FsyncPolicy::Everysec, FsyncPolicy::No] {
+            let dir =
+                std::env::temp_dir().join(format!("waitaof-test-{}-{fsync:?}", std::process::id()));
+            let config = AofConfig {
+                enabled: true,
+                fsync,
+                ..AofConfig::default()
+            };
+            let mut state = State::default();
+            let aof = Aof::open(dir.to_str().unwrap(), &config, &mut state).unwrap();
+            let mut state = state.with_aof(aof);
+            state.handle_request(&set, request).unwrap();
+            let offset = state.offset();
+            let waitaof = state.waitaof(&one, &zero, &ten, offset).await;
+            match fsync {
+                // the write was synced as it was appended
+                FsyncPolicy::Always => assert_eq!(waitaof.unwrap(), (1, 0)),
+                // until the cron syncs it, which it only does a second after the last time
+                FsyncPolicy::Everysec => {
+                    assert_eq!(waitaof.unwrap(), (0, 0));
+                    let (waitaof, ()) =
+                        tokio::join!(state.waitaof(&one, &zero, &zero, offset), async {
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                            state.aof_cron();
+                        });
+                    assert_eq!(waitaof.unwrap(), (1, 0));
+                }
+                FsyncPolicy::No => {
+                    let error = waitaof.unwrap_err();
+                    assert!(error.to_string().contains("appendfsync is no"));
+                    state.aof_cron();
+                    assert_eq!(state.aof_fsynced_offset(), 0);
+                }
+            }
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_lacks_good_replicas() {
+        let state = State::default();
+        assert!(!state.lacks_good_replicas());
+        state.replicas().set_min_replicas_to_write(1);
+        assert!(state.lacks_good_replicas());
+        // only a primary refuses writes
+        state.replicaof(Some(MasterConfig {
+            host: "127.0.0.1".to_owned(),
+            port: 6380,
+        }));
+        assert!(!state.lacks_good_replicas());
+    }
 }
@@ -0,0 +1,80 @@
+use crate::resp::{bulk_string::BulkString, RedisData};
+
+/// A command queued after `MULTI`, together with the request bytes used for propagation
+pub type QueuedCommand = (RedisData, Vec<u8>);
+
+/// Per-connection `MULTI`/`EXEC` state
+#[derive(Debug, Default)]
+pub struct Transaction {
+    queued: Option<Vec<QueuedCommand>>,
+    aborted: bool,
+    /// keys under `WATCH` with the modification count seen when they were watched
+    watched: Vec<(BulkString, u64)>,
+}
+
+impl Transaction {
+    pub fn is_active(&self) -> bool {
+        self.queued.is_some()
+    }
+
+    pub fn begin(&mut self) -> String {
+        if self.is_active() {
+            return "-ERR MULTI calls can not be nested\r\n".to_owned();
+        }
+        self.queued = Some(Vec::new());
+        self.aborted = false;
+        "+OK\r\n".to_owned()
+    }
+
+    pub fn queue(&mut self, redis_data: RedisData, request: Vec<u8>) -> String {
+        match &mut self.queued {
+            Some(queued) => {
+                queued.push((redis_data, request));
+                "+QUEUED\r\n".to_owned()
+            }
+            None => "-ERR queue without MULTI\r\n".to_owned(),
+        }
+    }
+
+    /// Flags the transaction so that the following `EXEC` fails, used when a command could not
+    /// be queued
+    pub fn abort(&mut self) {
+        if self.is_active() {
+            self.aborted = true;
+        }
+    }
+
+    pub fn discard(&mut self) -> String {
+        match self.queued.take() {
+            Some(_) => "+OK\r\n".to_owned(),
+            None => "-ERR DISCARD without MULTI\r\n".to_owned(),
+        }
+    }
+
+    /// Ends the transaction, returning the queued commands or the error reply for `EXEC`
+    pub fn take(&mut self) -> Result<Vec<QueuedCommand>, String> {
+        let queued = self
+            .queued
+            .take()
+            .ok_or_else(|| "-ERR EXEC without MULTI\r\n".to_owned())?;
+        if std::mem::take(&mut self.aborted) {
+            return Err(
+                "-EXECABORT Transaction discarded because of previous errors.\r\n".to_owned(),
+            );
+        }
+        Ok(queued)
+    }
+
+    pub fn is_watching(&self, key: &BulkString) -> bool {
+        self.watched.iter().any(|(k, _)| k == key)
+    }
+
+    pub fn watch(&mut self, key: BulkString, version: u64) {
+        self.watched.push((key, version));
+    }
+
+    /// Clears the watched keys, returning them so that they can be released on the database
+    pub fn take_watched(&mut self) -> Vec<(BulkString, u64)> {
+        std::mem::take(&mut self.watched)
+    }
+}